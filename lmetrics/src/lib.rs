@@ -8,12 +8,12 @@ mod nanohttp;
 use std::{io::Write, net::TcpStream};
 
 pub use once_cell;
-use prometheus::{core::Collector, IntCounterVec, Opts, Registry, TextEncoder};
+use prometheus::{core::Collector, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
 
 #[cfg(feature = "rocket")]
 pub use {httpmetrics::*, rocket::*};
 
-#[macro_export()]
+#[macro_export]
 macro_rules! register {
     ($($metric:path),+) => {
         {
@@ -25,41 +25,103 @@ macro_rules! register {
         }
     };
 }
-#[macro_export()]
+#[macro_export]
 macro_rules! metrics {
-    {$($vis:vis counter $name:ident ($help:literal, [$($label:ident),*]);)*} => {
+    {$($vis:vis $kind:ident $name:ident ($help:literal, [$($label:ident),*]);)*} => {
         $(
         #[allow(dead_code)]
         #[allow(unused)]
         $vis mod $name {
             pub static METRIC: $crate::once_cell::sync::Lazy<$crate::Metric> = $crate::once_cell::sync::Lazy::new(|| {
-                $crate::Metric::new(stringify!($name), $help, &[$(stringify!($label)),*])
+                $crate::Metric::$kind(stringify!($name), $help, &[$(stringify!($label)),*])
             });
-            pub fn inc($($label: &str,)*){
-                METRIC.inc(&[$($label,)*]);
-            }
+            $crate::metric_fns!($kind, [$($label),*]);
         }
         )*
     };
 }
+#[doc(hidden)]
+#[macro_export]
+macro_rules! metric_fns {
+    (counter, [$($label:ident),*]) => {
+        pub fn inc($($label: &str,)*){
+            METRIC.inc(&[$($label,)*]);
+        }
+    };
+    (gauge, [$($label:ident),*]) => {
+        pub fn inc($($label: &str,)*){
+            METRIC.inc(&[$($label,)*]);
+        }
+        pub fn dec($($label: &str,)*){
+            METRIC.add(&[$($label,)*], -1);
+        }
+        pub fn add($($label: &str,)* value: i64){
+            METRIC.add(&[$($label,)*], value);
+        }
+        pub fn set($($label: &str,)* value: i64){
+            METRIC.set(&[$($label,)*], value);
+        }
+    };
+}
+
+#[derive(Clone)]
+enum MetricVec {
+    Counter(IntCounterVec),
+    Gauge(IntGaugeVec),
+}
 
 #[derive(Clone)]
 pub struct Metric {
-    metric: IntCounterVec,
+    metric: MetricVec,
 }
 impl Metric {
     pub fn new(name: &str, help: &str, labels: &[&str]) -> Self {
+        Self::counter(name, help, labels)
+    }
+    pub fn counter(name: &str, help: &str, labels: &[&str]) -> Self {
+        Self {
+            metric: MetricVec::Counter(
+                IntCounterVec::new(Opts::new(name, help), labels)
+                    .expect("Could not create counter"),
+            ),
+        }
+    }
+    ///Metric that can go up and down
+    pub fn gauge(name: &str, help: &str, labels: &[&str]) -> Self {
         Self {
-            metric: IntCounterVec::new(Opts::new(name, help), labels)
-                .expect("Could not create counter"),
+            metric: MetricVec::Gauge(
+                IntGaugeVec::new(Opts::new(name, help), labels).expect("Could not create gauge"),
+            ),
         }
     }
     pub fn inc(&self, labels: &[&str]) {
-        self.metric.with_label_values(labels).inc();
+        match &self.metric {
+            MetricVec::Counter(counter) => counter.with_label_values(labels).inc(),
+            MetricVec::Gauge(gauge) => gauge.with_label_values(labels).inc(),
+        }
+    }
+    ///Panics for counters when `value` is negative
+    pub fn add(&self, labels: &[&str], value: i64) {
+        match &self.metric {
+            MetricVec::Counter(counter) => counter
+                .with_label_values(labels)
+                .inc_by(value.try_into().expect("Counters can't go down")),
+            MetricVec::Gauge(gauge) => gauge.with_label_values(labels).add(value),
+        }
+    }
+    ///Panics for counters
+    pub fn set(&self, labels: &[&str], value: i64) {
+        match &self.metric {
+            MetricVec::Counter(_) => panic!("Counters can't be set"),
+            MetricVec::Gauge(gauge) => gauge.with_label_values(labels).set(value),
+        }
     }
 
     pub fn into_collector(self) -> Box<dyn Collector> {
-        Box::new(self.metric)
+        match self.metric {
+            MetricVec::Counter(counter) => Box::new(counter),
+            MetricVec::Gauge(gauge) => Box::new(gauge),
+        }
     }
 }
 
@@ -92,11 +154,13 @@ impl<F: Fn() + Send + Sync + Clone> LMetrics<F> {
     pub fn process_http_request(&self, mut stream: TcpStream) -> std::io::Result<()> {
         let request = nanohttp::read_request(&mut stream)?;
         if request.starts_with("GET /metrics") {
-            self.before_handle.as_ref().map(|e| e());
+            if let Some(before_handle) = &self.before_handle {
+                before_handle();
+            }
             let data = self.respond_metrics().unwrap();
-            stream.write(data.as_bytes())?;
+            stream.write_all(data.as_bytes())?;
         } else {
-            stream.write(nanohttp::respond_404().as_bytes())?;
+            stream.write_all(nanohttp::respond_404().as_bytes())?;
         }
         Ok(())
     }
@@ -25,7 +25,7 @@ pub fn respond_404() -> &'static str {
 pub fn respond_200(data: String) -> String {
     format!(
         "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-        data.as_bytes().len(),
+        data.len(),
         data
     )
 }
@@ -12,7 +12,9 @@ pub use rocket_prometheus;
 #[rocket::async_trait]
 impl<F: Fn() + Send + Sync + Clone + 'static> Handler for LMetrics<F> {
     async fn handle<'r>(&self, req: &'r Request<'_>, _: rocket::Data<'r>) -> Outcome<'r> {
-        self.before_handle.as_ref().map(|e| e());
+        if let Some(before_handle) = &self.before_handle {
+            before_handle();
+        }
         let encoder = TextEncoder::new();
         let mut buf = String::new();
         encoder
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::Parser;
+use smppgc_client::{Client, Event, JoinOptions};
+use tokio::time::sleep_until;
+
+///Content prefix of the messages of the bench, followed by the send time
+const PREFIX: &str = "bench ";
+
+///Load test for a local smppgc server. Every simulated user joins, sends messages and counts the
+///messages of everyone else.
+#[derive(Parser)]
+struct Args {
+    ///Base url of the server
+    #[arg(long, default_value = "ws://127.0.0.1:8081")]
+    server: String,
+    ///Room the simulated users join
+    #[arg(long, default_value = "bench")]
+    room: String,
+    ///Simulated users
+    #[arg(short, long, default_value_t = 50)]
+    users: usize,
+    ///Messages every user sends
+    #[arg(short, long, default_value_t = 10)]
+    messages: u32,
+    ///Milliseconds between the messages of a user. Stay above `min_message_time_soft` of the
+    ///server (400 by default) or the users get kicked.
+    #[arg(long, default_value_t = 500)]
+    interval: u64,
+    ///Milliseconds between two joins
+    #[arg(long, default_value_t = 5)]
+    join_interval: u64,
+    ///Every user sends at the same time instead of spread over the interval
+    #[arg(long)]
+    burst: bool,
+    ///Milliseconds to wait for the last messages after sending
+    #[arg(long, default_value_t = 2000)]
+    drain: u64,
+}
+
+///What one simulated user saw
+#[derive(Default)]
+struct UserStats {
+    sent: u64,
+    received: u64,
+    ///Time from sending until arriving at this user
+    latencies: Vec<Duration>,
+    ///Close reason when the server closed the connection
+    closed: Option<String>,
+}
+
+///Formats the percentiles of the samples, sorts them
+fn percentiles(samples: &mut [Duration]) -> String {
+    if samples.is_empty() {
+        return "no samples".to_string();
+    }
+    samples.sort_unstable();
+    let at = |percentile: f64| {
+        let index = ((samples.len() as f64 * percentile).ceil() as usize).clamp(1, samples.len());
+        samples[index - 1].as_secs_f64() * 1000.0
+    };
+    format!(
+        "p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  p99.9 {:.2}ms  max {:.2}ms",
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(0.999),
+        at(1.0)
+    )
+}
+
+async fn run_user(
+    mut client: Client,
+    base: Instant,
+    send_start: Instant,
+    deadline: Instant,
+    interval: Duration,
+    messages: u32,
+) -> UserStats {
+    let mut stats = UserStats::default();
+    let mut next_send = send_start;
+    loop {
+        tokio::select! {
+            _ = sleep_until(next_send.into()), if stats.sent < messages as u64 => {
+                let content = format!("{}{}", PREFIX, base.elapsed().as_micros());
+                if let Err(err) = client.send(&content).await {
+                    stats.closed = Some(err.to_string());
+                    return stats;
+                }
+                stats.sent += 1;
+                next_send += interval;
+            }
+            _ = sleep_until(deadline.into()) => break,
+            event = client.next_event() => match event {
+                Ok(Some(Event::Message(mesg))) => {
+                    let Some(micros) = mesg.content.strip_prefix(PREFIX) else {
+                        continue;
+                    };
+                    stats.received += 1;
+                    // the profanity filter can censor digits of the send time
+                    if let Ok(sent_at) = micros.parse() {
+                        stats.latencies.push(base.elapsed().saturating_sub(Duration::from_micros(sent_at)));
+                    }
+                }
+                Ok(Some(Event::Closed { code, reason })) => {
+                    stats.closed = Some(format!("{} ({})", reason, code));
+                    return stats;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return stats,
+                Err(err) => {
+                    stats.closed = Some(err.to_string());
+                    return stats;
+                }
+            }
+        }
+    }
+    let _ = client.leave().await;
+    stats
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let base = Instant::now();
+    println!(
+        "{} users, {} messages per user every {}ms{} in room {}",
+        args.users,
+        args.messages,
+        args.interval,
+        if args.burst { " (burst)" } else { "" },
+        args.room
+    );
+
+    // names stay leased for a while after leaving, every run needs its own
+    let run = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        % 100_000;
+    let mut joins = Vec::with_capacity(args.users);
+    for i in 0..args.users {
+        let mut options = JoinOptions::new(args.server.clone(), format!("b{}-{}", run, i));
+        options.room = Some(args.room.clone());
+        let start = base + Duration::from_millis(args.join_interval * i as u64);
+        joins.push(tokio::spawn(async move {
+            sleep_until(start.into()).await;
+            let start = Instant::now();
+            Client::join(&options)
+                .await
+                .map(|client| (client, start.elapsed()))
+        }));
+    }
+    let mut clients = Vec::with_capacity(args.users);
+    let mut join_latencies = Vec::with_capacity(args.users);
+    let mut refused: HashMap<String, usize> = HashMap::new();
+    for join in joins {
+        match join.await.expect("join task panicked") {
+            Ok((client, latency)) => {
+                clients.push(client);
+                join_latencies.push(latency);
+            }
+            Err(err) => *refused.entry(err.to_string()).or_default() += 1,
+        }
+    }
+    println!("joined:    {}/{}", clients.len(), args.users);
+    for (reason, count) in &refused {
+        println!("  refused {}x: {}", count, reason);
+    }
+    println!("join latency:    {}", percentiles(&mut join_latencies));
+
+    // the rate limit of the server also counts from the join
+    let interval = Duration::from_millis(args.interval);
+    let send_start = Instant::now() + interval;
+    let deadline = send_start + interval * args.messages + Duration::from_millis(args.drain);
+    let user_count = clients.len().max(1) as u32;
+    let tasks: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(i, client)| {
+            let offset = if args.burst {
+                Duration::ZERO
+            } else {
+                interval * i as u32 / user_count
+            };
+            tokio::spawn(run_user(
+                client,
+                base,
+                send_start + offset,
+                deadline,
+                interval,
+                args.messages,
+            ))
+        })
+        .collect();
+    let mut users = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        users.push(task.await.expect("user task panicked"));
+    }
+
+    let sent: u64 = users.iter().map(|user| user.sent).sum();
+    let mut closed: HashMap<&str, usize> = HashMap::new();
+    let mut expected = 0;
+    let mut received = 0;
+    let mut latencies = Vec::new();
+    for user in &mut users {
+        match &user.closed {
+            Some(reason) => *closed.entry(reason).or_default() += 1,
+            // users that got closed early don't count for lost messages
+            None => {
+                expected += sent;
+                received += user.received;
+            }
+        }
+        latencies.append(&mut user.latencies);
+    }
+    let lost = expected.saturating_sub(received);
+    println!("sent:      {}", sent);
+    println!(
+        "received:  {}/{} (lost {}, {:.2}%)",
+        received,
+        expected,
+        lost,
+        if expected == 0 {
+            0.0
+        } else {
+            lost as f64 * 100.0 / expected as f64
+        }
+    );
+    for (reason, count) in &closed {
+        println!("  closed {}x: {}", count, reason);
+    }
+    println!("fan-out latency: {}", percentiles(&mut latencies));
+}
@@ -0,0 +1,352 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures_util::{SinkExt, StreamExt};
+use smppgc_proto::{
+    v2, Capabilities, ClientPacket, DecodeError, EncodeError, Message, MessageEdit, PrivateMessage,
+    ReactionCount, ReactionDelta, ServerPacket, Severity, Typing, User, LATEST_VERSION,
+};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+use url::Url;
+
+pub use smppgc_proto as proto;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Invalid server url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("Websocket error: {0}")]
+    Ws(Box<tungstenite::Error>),
+    #[error("Invalid packet: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("Can't encode packet: {0}")]
+    Encode(#[from] EncodeError),
+    #[error("The server didn't let us join: {0}")]
+    Refused(String),
+    #[error("Unexpected {0} packet")]
+    Unexpected(&'static str),
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(err: tungstenite::Error) -> Self {
+        ClientError::Ws(Box::new(err))
+    }
+}
+
+///Where and as who to join
+#[derive(Clone, Debug)]
+pub struct JoinOptions {
+    ///Base url of the server, like `ws://127.0.0.1:8081`
+    pub server: String,
+    pub username: String,
+    ///Key of an earlier session to keep the same user id
+    pub key: Option<String>,
+    ///Default room of the server when None
+    pub room: Option<String>,
+}
+impl JoinOptions {
+    pub fn new(server: impl Into<String>, username: impl Into<String>) -> Self {
+        Self {
+            server: server.into(),
+            username: username.into(),
+            key: None,
+            room: None,
+        }
+    }
+
+    ///Url of the v2 socket with the query of these options
+    pub fn url(&self) -> Result<Url, url::ParseError> {
+        let mut url = Url::parse(&self.server)?.join("socket/v2")?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("username", &self.username);
+            if let Some(key) = &self.key {
+                query.append_pair("key", key);
+            }
+            if let Some(room) = &self.room {
+                query.append_pair("room", room);
+            }
+        }
+        Ok(url)
+    }
+}
+
+///What the server sent when we joined
+#[derive(Clone, Debug)]
+pub struct Setup {
+    pub id: u16,
+    ///Pass this key when joining again to keep the same user id
+    pub key: Arc<str>,
+    pub reactions: Vec<ReactionCount>,
+    pub first_history_index: u32,
+    ///Recent messages, oldest first
+    pub history: Vec<Message>,
+}
+
+///Something that happened in the room
+#[derive(Clone, Debug)]
+pub enum Event {
+    Message(Message),
+    UserJoined(User),
+    UserRenamed {
+        old_username: Arc<str>,
+        user: User,
+    },
+    UserLeft(User),
+    ///Answer to [Client::fetch_history]
+    History {
+        first_index: u32,
+        messages: Vec<Message>,
+    },
+    System {
+        severity: Severity,
+        text: Arc<str>,
+    },
+    Private(PrivateMessage),
+    Typing(Typing),
+    Edit(MessageEdit),
+    Reaction(ReactionDelta),
+    ///Last event of the connection
+    Closed {
+        code: u16,
+        reason: String,
+    },
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+enum Received {
+    Packet(ServerPacket),
+    Closed { code: u16, reason: String },
+}
+
+///Waits for the next packet of the server
+async fn receive(ws: &mut Socket) -> Result<Received, ClientError> {
+    loop {
+        let data = match ws.next().await {
+            Some(message) => match message? {
+                tungstenite::Message::Binary(data) => data,
+                tungstenite::Message::Text(_) => return Err(ClientError::Unexpected("text")),
+                tungstenite::Message::Close(frame) => {
+                    let (code, reason) = frame
+                        .map(|frame| (frame.code.into(), frame.reason.into_owned()))
+                        .unwrap_or((CloseCode::Status.into(), String::new()));
+                    return Ok(Received::Closed { code, reason });
+                }
+                _ => continue,
+            },
+            None => {
+                return Ok(Received::Closed {
+                    code: CloseCode::Abnormal.into(),
+                    reason: String::new(),
+                })
+            }
+        };
+        return Ok(Received::Packet(v2::decode_server(&data)?));
+    }
+}
+
+///Connection to a room that speaks v2 like `ws.js`. It doesn't resume sessions or accept resyncs.
+pub struct Client {
+    ws: Socket,
+    setup: Setup,
+    users: HashMap<u16, Arc<str>>,
+    closed: bool,
+}
+impl Client {
+    ///Connects, sends the hello and waits for the setup packet
+    pub async fn join(options: &JoinOptions) -> Result<Self, ClientError> {
+        let (mut ws, _) = tokio_tungstenite::connect_async(options.url()?.as_str()).await?;
+        let capabilities = Capabilities::TYPING
+            | Capabilities::EDITS
+            | Capabilities::REACTIONS
+            | Capabilities::PRIVATE_MESSAGES;
+        ws.send(tungstenite::Message::Binary(v2::encode_hello(
+            LATEST_VERSION,
+            capabilities,
+        )))
+        .await?;
+        let setup = loop {
+            match receive(&mut ws).await? {
+                Received::Packet(ServerPacket::Hello { .. }) => continue,
+                Received::Packet(packet) => break packet,
+                Received::Closed { reason, .. } => return Err(ClientError::Refused(reason)),
+            }
+        };
+        let ServerPacket::Setup {
+            id,
+            key,
+            users,
+            reactions,
+            first_history_index,
+            history,
+        } = setup
+        else {
+            return Err(ClientError::Unexpected("non setup"));
+        };
+        Ok(Self {
+            ws,
+            setup: Setup {
+                id,
+                key,
+                reactions,
+                first_history_index,
+                history,
+            },
+            users: users
+                .into_iter()
+                .map(|user| (user.id, user.username))
+                .collect(),
+            closed: false,
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        self.setup.id
+    }
+    pub fn key(&self) -> &str {
+        &self.setup.key
+    }
+    pub fn setup(&self) -> &Setup {
+        &self.setup
+    }
+    pub fn username(&self, id: u16) -> Option<&str> {
+        self.users.get(&id).map(|username| username.as_ref())
+    }
+    ///Users that are online right now
+    pub fn users(&self) -> impl Iterator<Item = User> + '_ {
+        self.users.iter().map(|(id, username)| User {
+            id: *id,
+            username: username.clone(),
+        })
+    }
+
+    ///Waits for the next event. Returns None after [Event::Closed].
+    pub async fn next_event(&mut self) -> Result<Option<Event>, ClientError> {
+        if self.closed {
+            return Ok(None);
+        }
+        match receive(&mut self.ws).await? {
+            Received::Packet(packet) => Ok(Some(self.on_packet(packet)?)),
+            Received::Closed { code, reason } => {
+                self.closed = true;
+                Ok(Some(Event::Closed { code, reason }))
+            }
+        }
+    }
+
+    fn on_packet(&mut self, packet: ServerPacket) -> Result<Event, ClientError> {
+        Ok(match packet {
+            ServerPacket::Hello { .. } => return Err(ClientError::Unexpected("hello")),
+            ServerPacket::Setup { .. } => return Err(ClientError::Unexpected("setup")),
+            ServerPacket::Session { .. } => return Err(ClientError::Unexpected("session")),
+            ServerPacket::Message(mesg) => Event::Message(mesg),
+            // the server sends a join with the same id when a user changes their name
+            ServerPacket::UserJoined(user) => {
+                match self.users.insert(user.id, user.username.clone()) {
+                    Some(old_username) => Event::UserRenamed { old_username, user },
+                    None => Event::UserJoined(user),
+                }
+            }
+            ServerPacket::UserLeft(id) => Event::UserLeft(User {
+                id,
+                username: self.users.remove(&id).unwrap_or_else(|| "".into()),
+            }),
+            ServerPacket::History {
+                first_index,
+                messages,
+            } => Event::History {
+                first_index,
+                messages,
+            },
+            ServerPacket::System { severity, text } => Event::System { severity, text },
+            ServerPacket::Private(private) => Event::Private(private),
+            ServerPacket::Typing(typing) => Event::Typing(typing),
+            ServerPacket::Edit(edit) => Event::Edit(edit),
+            ServerPacket::Reaction(reaction) => Event::Reaction(reaction),
+        })
+    }
+
+    async fn send_packet(&mut self, packet: &ClientPacket) -> Result<(), ClientError> {
+        let data = v2::encode_client(packet)?;
+        self.ws.send(tungstenite::Message::Binary(data)).await?;
+        Ok(())
+    }
+
+    ///Sends a chat message. The server runs messages starting with a slash as commands.
+    pub async fn send(&mut self, content: &str) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Message {
+            content: content.into(),
+            reply_to: None,
+        })
+        .await
+    }
+    pub async fn reply(&mut self, reply_to: u32, content: &str) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Message {
+            content: content.into(),
+            reply_to: Some(reply_to),
+        })
+        .await
+    }
+    ///Runs `/<name> <args>`
+    pub async fn command(&mut self, name: &str, args: &str) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Command {
+            name: name.to_string(),
+            args: args.to_string(),
+        })
+        .await
+    }
+    pub async fn send_private(&mut self, recipient: u16, content: &str) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::PrivateMessage {
+            recipient,
+            content: content.into(),
+        })
+        .await
+    }
+    pub async fn typing(&mut self, typing: bool) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Typing(typing)).await
+    }
+    pub async fn edit(&mut self, id: u32, content: &str) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Edit {
+            id,
+            content: content.into(),
+        })
+        .await
+    }
+    pub async fn delete(&mut self, id: u32) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Delete(id)).await
+    }
+    ///Adds (or removes) the reaction with this index on message `id`
+    pub async fn react(&mut self, id: u32, reaction: u8, add: bool) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::React { id, reaction, add })
+            .await
+    }
+    ///Requests `count` messages before history index `before`, they arrive as [Event::History]
+    pub async fn fetch_history(&mut self, before: u32, count: u8) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::HistoryRequest { before, count })
+            .await
+    }
+    ///Tells the server we received every message up to and including `id`
+    pub async fn ack(&mut self, id: u32) -> Result<(), ClientError> {
+        self.send_packet(&ClientPacket::Ack(id)).await
+    }
+
+    pub async fn leave(mut self) -> Result<(), ClientError> {
+        self.ws
+            .close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            }))
+            .await?;
+        // wait for the close of the server
+        while let Some(Ok(_)) = self.ws.next().await {}
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use smppgc_client::{proto::Message, Client, ClientError, Event, JoinOptions};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+///Headless smppgc client
+#[derive(Parser)]
+struct Args {
+    ///Base url of the server
+    #[arg(long, default_value = "ws://127.0.0.1:8081")]
+    server: String,
+    ///Room to join, the default room of the server when not set
+    #[arg(long)]
+    room: Option<String>,
+    ///Key of an earlier session to keep the same user id
+    #[arg(long)]
+    key: Option<String>,
+    username: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    ///Print everything that happens in the room
+    Tail,
+    ///Send messages and leave
+    Send {
+        ///Milliseconds between messages, the server kicks clients that type too fast
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+        #[arg(required = true)]
+        messages: Vec<String>,
+    },
+    ///Send the lines of stdin and print everything that happens in the room
+    Chat,
+}
+
+fn time(timestamp: u32) -> String {
+    format!("{:02}:{:02}", timestamp / 60 % 24, timestamp % 60)
+}
+
+fn print_message(mesg: &Message) {
+    let reply = mesg
+        .reply_to
+        .map(|id| format!(" (re #{})", id))
+        .unwrap_or_default();
+    println!(
+        "[{}] #{} {}{}: {}",
+        time(mesg.timestamp),
+        mesg.id,
+        mesg.sender,
+        reply,
+        mesg.content
+    );
+}
+
+///Prints the event, returns false when the connection is closed
+fn print_event(client: &Client, event: &Event) -> bool {
+    match event {
+        Event::Message(mesg) => print_message(mesg),
+        Event::UserJoined(user) => println!("* {} joined", user.username),
+        Event::UserRenamed { old_username, user } => {
+            println!("* {} is now {}", old_username, user.username)
+        }
+        Event::UserLeft(user) => println!("* {} left", user.username),
+        Event::History { messages, .. } => messages.iter().for_each(print_message),
+        Event::System { severity, text } => println!("! {:?}: {}", severity, text),
+        Event::Private(private) => println!(
+            "[{}] {} -> {}: {}",
+            time(private.mesg.timestamp),
+            private.mesg.sender,
+            client.username(private.recipient).unwrap_or("?"),
+            private.mesg.content
+        ),
+        Event::Typing(_) | Event::Reaction(_) => {}
+        Event::Edit(edit) if edit.is_delete() => println!("* #{} deleted", edit.id),
+        Event::Edit(edit) => println!("* #{} edited: {}", edit.id, edit.content),
+        Event::Closed { code, reason } => {
+            println!("* closed ({}): {}", code, reason);
+            return false;
+        }
+    }
+    true
+}
+
+async fn tail(mut client: Client) -> Result<(), ClientError> {
+    while let Some(event) = client.next_event().await? {
+        if !print_event(&client, &event) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn send(mut client: Client, interval: u64, messages: Vec<String>) -> Result<(), ClientError> {
+    for message in &messages {
+        // the rate limit also counts from the join
+        tokio::time::sleep(Duration::from_millis(interval)).await;
+        client.send(message).await?;
+    }
+    client.leave().await
+}
+
+async fn chat(mut client: Client) -> Result<(), ClientError> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else {
+                    return client.leave().await;
+                };
+                match line.strip_prefix('/') {
+                    Some(command) => {
+                        let (name, args) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+                        client.command(name, args).await?;
+                    }
+                    None if line.trim().is_empty() => {}
+                    None => client.send(&line).await?,
+                }
+            }
+            event = client.next_event() => {
+                let Some(event) = event? else {
+                    return Ok(());
+                };
+                if !print_event(&client, &event) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let options = JoinOptions {
+        server: args.server,
+        username: args.username,
+        key: args.key,
+        room: args.room,
+    };
+    let client = match Client::join(&options).await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Failed to join: {}", err);
+            std::process::exit(1);
+        }
+    };
+    eprintln!(
+        "Joined as {} ({}), key {}",
+        options.username,
+        client.id(),
+        client.key()
+    );
+    if !matches!(args.command, Command::Send { .. }) {
+        client.setup().history.iter().for_each(print_message);
+    }
+
+    let result = match args.command {
+        Command::Tail => tail(client).await,
+        Command::Send { interval, messages } => send(client, interval, messages).await,
+        Command::Chat => chat(client).await,
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
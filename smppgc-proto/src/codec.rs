@@ -0,0 +1,126 @@
+use crate::{DecodeError, EncodeError};
+
+///Fails when `len` doesn't fit in the length prefix
+fn check_len(field: &'static str, len: usize, max: usize) -> Result<(), EncodeError> {
+    if len > max {
+        return Err(EncodeError::TooLong { field, len, max });
+    }
+    Ok(())
+}
+
+///Writes a packet front to back. Numbers are big endian.
+pub struct Writer(Vec<u8>);
+impl Writer {
+    pub fn new() -> Self {
+        Self(Vec::with_capacity(16))
+    }
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+    ///String without length, only valid at the end of a packet
+    pub fn rest_str(&mut self, string: &str) {
+        self.0.extend_from_slice(string.as_bytes());
+    }
+    ///String prefixed with its u8 byte length
+    pub fn str_u8(&mut self, field: &'static str, string: &str) -> Result<(), EncodeError> {
+        check_len(field, string.len(), u8::MAX as usize)?;
+        self.u8(string.len() as u8);
+        self.rest_str(string);
+        Ok(())
+    }
+    ///String prefixed with its u16 byte length
+    pub fn str_u16(&mut self, field: &'static str, string: &str) -> Result<(), EncodeError> {
+        check_len(field, string.len(), u16::MAX as usize)?;
+        self.u16(string.len() as u16);
+        self.rest_str(string);
+        Ok(())
+    }
+    ///u16 item count of a list
+    pub fn len_u16(&mut self, field: &'static str, len: usize) -> Result<(), EncodeError> {
+        check_len(field, len, u16::MAX as usize)?;
+        self.u16(len as u16);
+        Ok(())
+    }
+    pub fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+///Reads a packet front to back
+pub struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let (bytes, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or(DecodeError::Truncated)?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+    ///First byte of the packet
+    pub fn opcode(&mut self) -> Result<u8, DecodeError> {
+        self.u8().map_err(|_| DecodeError::Empty)
+    }
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        self.bytes::<1>().map(|bytes| bytes[0])
+    }
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.bytes().map(u16::from_be_bytes)
+    }
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.bytes().map(u32::from_be_bytes)
+    }
+    ///Only 0 and 1 are valid
+    pub fn bool(&mut self, field: &'static str) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidField(field)),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    ///String of `len` bytes
+    pub fn str(&mut self, len: usize) -> Result<&'a str, DecodeError> {
+        if self.0.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)
+    }
+    ///Reads the rest of the packet as a string
+    pub fn rest_str(&mut self) -> Result<&'a str, DecodeError> {
+        self.str(self.0.len())
+    }
+    ///String prefixed with its u8 byte length
+    pub fn str_u8(&mut self) -> Result<&'a str, DecodeError> {
+        let len = self.u8()?;
+        self.str(len as usize)
+    }
+    ///String prefixed with its u16 byte length
+    pub fn str_u16(&mut self) -> Result<&'a str, DecodeError> {
+        let len = self.u16()?;
+        self.str(len as usize)
+    }
+    ///Fails when there are bytes left
+    pub fn finish<T>(self, packet: T) -> Result<T, DecodeError> {
+        match self.0.len() {
+            0 => Ok(packet),
+            left => Err(DecodeError::TrailingBytes(left)),
+        }
+    }
+}
@@ -0,0 +1,348 @@
+mod codec;
+pub mod v1;
+pub mod v2;
+
+use std::{
+    ops::{BitAnd, BitOr},
+    sync::Arc,
+};
+
+use thiserror::Error;
+
+///Newest protocol version of the server. Clients announce the newest version they speak in the
+///handshake and the server answers with the version both sides speak.
+pub const LATEST_VERSION: u16 = 2;
+
+///Wire format of a connection. `/socket/v1` speaks v1 without a handshake, `/socket/v2` starts
+///with a hello packet from the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+impl Version {
+    pub fn label(self) -> &'static str {
+        match self {
+            Version::V1 => "v1",
+            Version::V2 => "v2",
+        }
+    }
+}
+
+///Optional features a client can announce in the handshake. Packets of features the client didn't
+///announce aren't sent to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u32);
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const TYPING: Self = Self(1 << 0);
+    pub const EDITS: Self = Self(1 << 1);
+    pub const REACTIONS: Self = Self(1 << 2);
+    pub const PRIVATE_MESSAGES: Self = Self(1 << 3);
+    ///The client accepts another setup packet after joining, it replaces everything it knew about
+    ///the room
+    pub const RESYNC: Self = Self(1 << 4);
+    ///The client keeps its session token and resumes the session when it reconnects
+    pub const RESUME: Self = Self(1 << 5);
+    ///Every capability of this server
+    pub const ALL: Self = Self(0b111111);
+
+    ///Unknown bits are ignored
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+impl BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+impl BitAnd for Capabilities {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+///Data frame of a websocket connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    ///Assigned by the chat when the message is sent, 0 until then
+    pub id: u32,
+    pub sender: Arc<str>,
+    pub content: Arc<str>,
+    ///Minutes since UNIX_EPOCH
+    pub timestamp: u32,
+    pub sender_id: u16,
+    ///Id of the message this message replies to
+    pub reply_to: Option<u32>,
+}
+impl Message {
+    pub fn is_empty(&self) -> bool {
+        self.content.chars().all(char::is_whitespace)
+    }
+    ///Deleted messages are kept in the history without content
+    pub fn is_deleted(&self) -> bool {
+        self.content.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Severity {
+    Info = 0,
+    Warning = 1,
+    Error = 2,
+}
+impl Severity {
+    pub fn from_u8(severity: u8) -> Option<Self> {
+        match severity {
+            0 => Some(Severity::Info),
+            1 => Some(Severity::Warning),
+            2 => Some(Severity::Error),
+            _ => None,
+        }
+    }
+}
+
+///Client `id` started or stopped typing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Typing {
+    pub id: u16,
+    pub typing: bool,
+}
+
+///Message that is only delivered to `recipient`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrivateMessage {
+    pub mesg: Message,
+    pub recipient: u16,
+}
+
+///New content of message `id`. An empty content means the message was deleted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageEdit {
+    pub id: u32,
+    pub content: Arc<str>,
+}
+impl MessageEdit {
+    pub fn is_delete(&self) -> bool {
+        self.content.is_empty()
+    }
+}
+
+///Client `client_id` added (or removed) `reaction` on message `message_id`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReactionDelta {
+    pub message_id: u32,
+    pub reaction: u8,
+    pub client_id: u16,
+    pub added: bool,
+}
+
+///Count of one reaction on a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReactionCount {
+    pub message_id: u32,
+    pub reaction: u8,
+    pub count: u16,
+    ///The user the counts are for added this reaction
+    pub own: bool,
+}
+
+///A connected user as the clients know it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub id: u16,
+    pub username: Arc<str>,
+}
+
+///Packet sent by the server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerPacket {
+    ///Answer to the hello of the client with the negotiated version and capabilities (v2 only)
+    Hello {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    ///First packet after the handshake
+    Setup {
+        id: u16,
+        key: Arc<str>,
+        users: Vec<User>,
+        reactions: Vec<ReactionCount>,
+        first_history_index: u32,
+        history: Vec<Message>,
+    },
+    Message(Message),
+    UserJoined(User),
+    UserLeft(u16),
+    ///Older messages requested by the client
+    History {
+        first_index: u32,
+        messages: Vec<Message>,
+    },
+    System {
+        severity: Severity,
+        text: Arc<str>,
+    },
+    Private(PrivateMessage),
+    Typing(Typing),
+    ///Edit or deletion of a message
+    Edit(MessageEdit),
+    Reaction(ReactionDelta),
+    ///Token to resume this session after a reconnect. Sent after the setup of a new session and as
+    ///the first packet of a resumed session, followed by what the client missed.
+    Session {
+        token: Arc<str>,
+        resumed: bool,
+    },
+}
+impl ServerPacket {
+    ///Capability the client needs to receive this packet
+    pub fn capability(&self) -> Capabilities {
+        match self {
+            ServerPacket::Typing(_) => Capabilities::TYPING,
+            ServerPacket::Edit(_) => Capabilities::EDITS,
+            ServerPacket::Reaction(_) => Capabilities::REACTIONS,
+            ServerPacket::Private(_) => Capabilities::PRIVATE_MESSAGES,
+            ServerPacket::Session { .. } => Capabilities::RESUME,
+            _ => Capabilities::NONE,
+        }
+    }
+}
+
+///Packet sent by the client. The hello of a v2 client has its own functions in [v2].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientPacket {
+    Message {
+        content: Arc<str>,
+        ///Id of the message this message replies to
+        reply_to: Option<u32>,
+    },
+    ///Request `count` messages before history index `before`
+    HistoryRequest {
+        before: u32,
+        count: u8,
+    },
+    PrivateMessage {
+        recipient: u16,
+        content: Arc<str>,
+    },
+    ///The client started (true) or stopped (false) typing
+    Typing(bool),
+    Edit {
+        id: u32,
+        content: Arc<str>,
+    },
+    Delete(u32),
+    ///Add (or remove) a reaction on message `id`
+    React {
+        id: u32,
+        reaction: u8,
+        add: bool,
+    },
+    ///Slash command without the slash, `/nick Henk` is `nick` with args `Henk`
+    Command {
+        name: String,
+        args: String,
+    },
+    ///The client has received every message up to and including this id
+    Ack(u32),
+}
+
+///Why a packet couldn't be decoded
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("Empty packet")]
+    Empty,
+    #[error("Unknown opcode {0}")]
+    UnknownOpcode(u8),
+    #[error("Hello after the handshake")]
+    UnexpectedHello,
+    #[error("Packet ended early")]
+    Truncated,
+    #[error("{0} bytes left after the packet")]
+    TrailingBytes(usize),
+    #[error("Invalid utf8")]
+    InvalidUtf8,
+    #[error("Invalid value for {0}")]
+    InvalidField(&'static str),
+    #[error("Unexpected {0} frame")]
+    UnexpectedFrame(&'static str),
+}
+
+///Why a packet couldn't be encoded
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EncodeError {
+    #[error("Length of {field} is {len}, the maximum is {max}")]
+    TooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    #[error("Invalid value for {0}")]
+    InvalidField(&'static str),
+    #[error("{0} packets don't exist in this version")]
+    Unsupported(&'static str),
+}
+
+///Version and capabilities of a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: Version,
+    pub capabilities: Capabilities,
+}
+impl Protocol {
+    ///v1 has no handshake. Edits and reactions refer to message ids, which v1 clients don't get.
+    pub const V1: Self = Self {
+        version: Version::V1,
+        capabilities: Capabilities(Capabilities::TYPING.0 | Capabilities::PRIVATE_MESSAGES.0),
+    };
+
+    ///Negotiates the protocol with the hello of a v2 client. Returns None when the client is too old.
+    pub fn negotiate(version: u16, capabilities: Capabilities) -> Option<Self> {
+        if version < 2 {
+            return None;
+        }
+        Some(Self {
+            version: Version::V2,
+            capabilities: capabilities & Capabilities::ALL,
+        })
+    }
+
+    ///Returns None when the client doesn't support the packet
+    pub fn encode_server(&self, packet: &ServerPacket) -> Result<Option<Frame>, EncodeError> {
+        if !self.capabilities.contains(packet.capability()) {
+            return Ok(None);
+        }
+        match self.version {
+            Version::V1 => v1::encode_server(packet).map(Some),
+            Version::V2 => v2::encode_server(packet).map(|data| Some(Frame::Binary(data))),
+        }
+    }
+
+    ///v2 only has binary frames
+    pub fn decode_client(&self, frame: &Frame) -> Result<ClientPacket, DecodeError> {
+        match self.version {
+            Version::V1 => v1::decode_client(frame),
+            Version::V2 => match frame {
+                Frame::Binary(data) => v2::decode_client(data),
+                Frame::Text(_) => Err(DecodeError::UnexpectedFrame("text")),
+            },
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use crate::{
+    codec::{Reader, Writer},
+    ClientPacket, DecodeError, EncodeError, Frame, Message, MessageEdit, PrivateMessage,
+    ReactionDelta, ServerPacket, Severity, Typing, User,
+};
+
+//  v1 has no handshake. Server packets start with the sender id of a chat message or with
+//  USERID_SPECIAL followed by a subid. Text frames from the client are chat messages, binary
+//  frames start with an opcode.
+//
+//  The setup, user join and message packets keep the layout of the first clients, which can't
+//  parse anything else in them. Not every field of a packet exists in v1, decoders leave them
+//  empty:
+//  - messages and hist messages don't have an id or a reply
+//  - messages don't have the sender username
+//  - hist messages don't have the sender id
+//  - the setup packet doesn't have reactions or the history index of the first hist message
+//  - private messages don't have an id or a reply
+
+pub const USERID_SPECIAL: u16 = 0;
+pub const SUBID_SETUP: u8 = 0;
+pub const SUBID_USERJOIN: u8 = 1;
+pub const SUBID_USERLEAVE: u8 = 2;
+pub const SUBID_HISTORY: u8 = 3;
+pub const SUBID_SYSTEM: u8 = 4;
+pub const SUBID_PRIVATE: u8 = 5;
+pub const SUBID_TYPING: u8 = 6;
+pub const SUBID_EDIT: u8 = 7;
+pub const SUBID_DELETE: u8 = 8;
+pub const SUBID_REACTION: u8 = 9;
+
+///Keys are sent without a length, clients read exactly this many bytes
+pub const KEY_LEN: usize = 33;
+
+pub const OPCODE_HISTORY_REQUEST: u8 = 0;
+pub const OPCODE_PRIVATE_MESSAGE: u8 = 1;
+pub const OPCODE_TYPING: u8 = 2;
+pub const OPCODE_EDIT: u8 = 3;
+pub const OPCODE_DELETE: u8 = 4;
+pub const OPCODE_REPLY: u8 = 5;
+pub const OPCODE_REACT: u8 = 6;
+pub const OPCODE_COMMAND: u8 = 7;
+pub const OPCODE_ACK: u8 = 8;
+
+fn put_special(data: &mut Writer, subid: u8) {
+    data.u16(USERID_SPECIAL);
+    data.u8(subid);
+}
+
+fn put_hist_messages(data: &mut Writer, history: &[Message]) -> Result<(), EncodeError> {
+    //  hist messages: (until the end of the packet)
+    //|  u32 | time (minutes since UNIX_EPOCH)
+    //|  u8  | sender username len
+    //| [u8] | sender username
+    //|  u8  | content len
+    //| [u8] | content
+    for message in history {
+        data.u32(message.timestamp);
+        data.str_u8("sender", &message.sender)?;
+        data.str_u8("content", &message.content)?;
+    }
+    Ok(())
+}
+
+fn get_hist_messages(reader: &mut Reader) -> Result<Vec<Message>, DecodeError> {
+    let mut history = Vec::new();
+    while !reader.is_empty() {
+        history.push(Message {
+            id: 0,
+            timestamp: reader.u32()?,
+            reply_to: None,
+            sender: reader.str_u8()?.into(),
+            content: reader.str_u8()?.into(),
+            sender_id: 0,
+        });
+    }
+    Ok(history)
+}
+
+///Encodes a server packet. v1 doesn't have a hello or sessions.
+pub fn encode_server(packet: &ServerPacket) -> Result<Frame, EncodeError> {
+    let mut data = Writer::new();
+    match packet {
+        ServerPacket::Hello { .. } => return Err(EncodeError::Unsupported("hello")),
+        ServerPacket::Session { .. } => return Err(EncodeError::Unsupported("session")),
+        ServerPacket::Setup {
+            id,
+            key,
+            users,
+            history,
+            ..
+        } => {
+            //|    u16   | const USERID_SPECIAL
+            //|    u8    | const SUBID_SETUP
+            //|    u16   | id
+            //| [u8; 33] | key
+            //
+            //  clients:
+            //|    u16   | client count
+            //|    u16   | client id
+            //|    u8    | username len
+            //|    [u8]  | username
+            //
+            //|    [..]  | hist messages
+            if key.len() != KEY_LEN {
+                return Err(EncodeError::InvalidField("key"));
+            }
+            put_special(&mut data, SUBID_SETUP);
+            data.u16(*id);
+            data.rest_str(key);
+            data.len_u16("users", users.len())?;
+            for user in users {
+                data.u16(user.id);
+                data.str_u8("username", &user.username)?;
+            }
+            put_hist_messages(&mut data, history)?;
+        }
+        ServerPacket::Message(mesg) => {
+            //|  u16 | local sender id
+            //|  u32 | time (minutes since UNIX_EPOCH)
+            //| [u8] | content bytes
+            if mesg.sender_id == USERID_SPECIAL {
+                return Err(EncodeError::InvalidField("sender_id"));
+            }
+            data.u16(mesg.sender_id);
+            data.u32(mesg.timestamp);
+            data.rest_str(&mesg.content);
+        }
+        ServerPacket::UserJoined(user) => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_USERJOIN
+            //|  u16 | user id
+            //| [u8] | username
+            put_special(&mut data, SUBID_USERJOIN);
+            data.u16(user.id);
+            data.rest_str(&user.username);
+        }
+        ServerPacket::UserLeft(id) => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_USERLEAVE
+            //|  u16 | user id
+            put_special(&mut data, SUBID_USERLEAVE);
+            data.u16(*id);
+        }
+        ServerPacket::History {
+            first_index,
+            messages,
+        } => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_HISTORY
+            //|  u32 | history index of the first message
+            //| [..] | hist messages (same as setup)
+            put_special(&mut data, SUBID_HISTORY);
+            data.u32(*first_index);
+            put_hist_messages(&mut data, messages)?;
+        }
+        ServerPacket::System { severity, text } => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_SYSTEM
+            //|  u8  | severity (0: info, 1: warning, 2: error)
+            //| [u8] | text
+            put_special(&mut data, SUBID_SYSTEM);
+            data.u8(*severity as u8);
+            data.rest_str(text);
+        }
+        ServerPacket::Private(private) => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_PRIVATE
+            //|  u16 | sender id
+            //|  u16 | recipient id
+            //|  u32 | time (minutes since UNIX_EPOCH)
+            //|  u8  | sender username len
+            //| [u8] | sender username
+            //| [u8] | content
+            let mesg = &private.mesg;
+            put_special(&mut data, SUBID_PRIVATE);
+            data.u16(mesg.sender_id);
+            data.u16(private.recipient);
+            data.u32(mesg.timestamp);
+            data.str_u8("sender", &mesg.sender)?;
+            data.rest_str(&mesg.content);
+        }
+        ServerPacket::Typing(typing) => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_TYPING
+            //|  u16 | client id
+            //|  u8  | 1 when typing, 0 when stopped
+            put_special(&mut data, SUBID_TYPING);
+            data.u16(typing.id);
+            data.bool(typing.typing);
+        }
+        ServerPacket::Edit(edit) => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_EDIT or SUBID_DELETE
+            //|  u32 | message id
+            //| [u8] | new content (only for SUBID_EDIT)
+            put_special(
+                &mut data,
+                if edit.is_delete() {
+                    SUBID_DELETE
+                } else {
+                    SUBID_EDIT
+                },
+            );
+            data.u32(edit.id);
+            data.rest_str(&edit.content);
+        }
+        ServerPacket::Reaction(reaction) => {
+            //|  u16 | const USERID_SPECIAL
+            //|  u8  | const SUBID_REACTION
+            //|  u32 | message id
+            //|  u8  | reaction index
+            //|  u16 | id of the client that reacted
+            //|  u8  | 1 when added, 0 when removed
+            put_special(&mut data, SUBID_REACTION);
+            data.u32(reaction.message_id);
+            data.u8(reaction.reaction);
+            data.u16(reaction.client_id);
+            data.bool(reaction.added);
+        }
+    }
+    Ok(Frame::Binary(data.finish()))
+}
+
+pub fn decode_server(frame: &Frame) -> Result<ServerPacket, DecodeError> {
+    let Frame::Binary(data) = frame else {
+        return Err(DecodeError::UnexpectedFrame("text"));
+    };
+    let mut reader = Reader::new(data);
+    let sender_id = reader.u16().map_err(|_| DecodeError::Empty)?;
+    if sender_id != USERID_SPECIAL {
+        let packet = ServerPacket::Message(Message {
+            id: 0,
+            timestamp: reader.u32()?,
+            reply_to: None,
+            content: reader.rest_str()?.into(),
+            sender: "".into(),
+            sender_id,
+        });
+        return reader.finish(packet);
+    }
+    let packet = match reader.u8()? {
+        SUBID_SETUP => {
+            let id = reader.u16()?;
+            let key = reader.str(KEY_LEN)?.into();
+            let user_count = reader.u16()?;
+            let users = (0..user_count)
+                .map(|_| {
+                    Ok(User {
+                        id: reader.u16()?,
+                        username: reader.str_u8()?.into(),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            ServerPacket::Setup {
+                id,
+                key,
+                users,
+                reactions: Vec::new(),
+                first_history_index: 0,
+                history: get_hist_messages(&mut reader)?,
+            }
+        }
+        SUBID_USERJOIN => ServerPacket::UserJoined(User {
+            id: reader.u16()?,
+            username: reader.rest_str()?.into(),
+        }),
+        SUBID_USERLEAVE => ServerPacket::UserLeft(reader.u16()?),
+        SUBID_HISTORY => ServerPacket::History {
+            first_index: reader.u32()?,
+            messages: get_hist_messages(&mut reader)?,
+        },
+        SUBID_SYSTEM => ServerPacket::System {
+            severity: Severity::from_u8(reader.u8()?)
+                .ok_or(DecodeError::InvalidField("severity"))?,
+            text: reader.rest_str()?.into(),
+        },
+        SUBID_PRIVATE => {
+            let sender_id = reader.u16()?;
+            let recipient = reader.u16()?;
+            ServerPacket::Private(PrivateMessage {
+                mesg: Message {
+                    id: 0,
+                    timestamp: reader.u32()?,
+                    sender: reader.str_u8()?.into(),
+                    content: reader.rest_str()?.into(),
+                    sender_id,
+                    reply_to: None,
+                },
+                recipient,
+            })
+        }
+        SUBID_TYPING => ServerPacket::Typing(Typing {
+            id: reader.u16()?,
+            typing: reader.bool("typing")?,
+        }),
+        SUBID_EDIT => ServerPacket::Edit(MessageEdit {
+            id: reader.u32()?,
+            content: reader.rest_str()?.into(),
+        }),
+        SUBID_DELETE => ServerPacket::Edit(MessageEdit {
+            id: reader.u32()?,
+            content: "".into(),
+        }),
+        SUBID_REACTION => ServerPacket::Reaction(ReactionDelta {
+            message_id: reader.u32()?,
+            reaction: reader.u8()?,
+            client_id: reader.u16()?,
+            added: reader.bool("added")?,
+        }),
+        subid => return Err(DecodeError::UnknownOpcode(subid)),
+    };
+    reader.finish(packet)
+}
+
+///Chat messages without a reply are sent as text
+pub fn encode_client(packet: &ClientPacket) -> Result<Frame, EncodeError> {
+    let mut data = Writer::new();
+    match packet {
+        ClientPacket::Message {
+            content,
+            reply_to: None,
+        } => return Ok(Frame::Text(content.to_string())),
+        ClientPacket::Message {
+            content,
+            reply_to: Some(reply_to),
+        } => {
+            //|  u8  | const OPCODE_REPLY
+            //|  u32 | id of the replied message
+            //| [u8] | content
+            data.u8(OPCODE_REPLY);
+            data.u32(*reply_to);
+            data.rest_str(content);
+        }
+        ClientPacket::HistoryRequest { before, count } => {
+            //|  u8  | const OPCODE_HISTORY_REQUEST
+            //|  u32 | history index of the first message the client has
+            //|  u8  | message count
+            data.u8(OPCODE_HISTORY_REQUEST);
+            data.u32(*before);
+            data.u8(*count);
+        }
+        ClientPacket::PrivateMessage { recipient, content } => {
+            //|  u8  | const OPCODE_PRIVATE_MESSAGE
+            //|  u16 | recipient id
+            //| [u8] | content
+            data.u8(OPCODE_PRIVATE_MESSAGE);
+            data.u16(*recipient);
+            data.rest_str(content);
+        }
+        ClientPacket::Typing(typing) => {
+            //|  u8  | const OPCODE_TYPING
+            //|  u8  | 1 when typing, 0 when stopped
+            data.u8(OPCODE_TYPING);
+            data.bool(*typing);
+        }
+        ClientPacket::Edit { id, content } => {
+            //|  u8  | const OPCODE_EDIT
+            //|  u32 | message id
+            //| [u8] | new content
+            data.u8(OPCODE_EDIT);
+            data.u32(*id);
+            data.rest_str(content);
+        }
+        ClientPacket::Delete(id) => {
+            //|  u8  | const OPCODE_DELETE
+            //|  u32 | message id
+            data.u8(OPCODE_DELETE);
+            data.u32(*id);
+        }
+        ClientPacket::React { id, reaction, add } => {
+            //|  u8  | const OPCODE_REACT
+            //|  u32 | message id
+            //|  u8  | reaction index
+            //|  u8  | 1 to add, 0 to remove
+            data.u8(OPCODE_REACT);
+            data.u32(*id);
+            data.u8(*reaction);
+            data.bool(*add);
+        }
+        ClientPacket::Command { name, args } => {
+            //|  u8  | const OPCODE_COMMAND
+            //|  u8  | command name len
+            //| [u8] | command name without the slash
+            //| [u8] | arguments
+            data.u8(OPCODE_COMMAND);
+            data.str_u8("name", name)?;
+            data.rest_str(args);
+        }
+        ClientPacket::Ack(id) => {
+            //|  u8  | const OPCODE_ACK
+            //|  u32 | id of the newest message the client has
+            data.u8(OPCODE_ACK);
+            data.u32(*id);
+        }
+    }
+    Ok(Frame::Binary(data.finish()))
+}
+
+///Text messages are chat messages, binary messages start with an opcode
+pub fn decode_client(frame: &Frame) -> Result<ClientPacket, DecodeError> {
+    let data = match frame {
+        Frame::Text(content) => {
+            return Ok(ClientPacket::Message {
+                content: content.as_str().into(),
+                reply_to: None,
+            })
+        }
+        Frame::Binary(data) => data,
+    };
+    let mut reader = Reader::new(data);
+    let packet = match reader.opcode()? {
+        OPCODE_HISTORY_REQUEST => ClientPacket::HistoryRequest {
+            before: reader.u32()?,
+            count: reader.u8()?,
+        },
+        OPCODE_PRIVATE_MESSAGE => ClientPacket::PrivateMessage {
+            recipient: reader.u16()?,
+            content: reader.rest_str()?.into(),
+        },
+        OPCODE_TYPING => ClientPacket::Typing(reader.bool("typing")?),
+        OPCODE_EDIT => ClientPacket::Edit {
+            id: reader.u32()?,
+            content: reader.rest_str()?.into(),
+        },
+        OPCODE_DELETE => ClientPacket::Delete(reader.u32()?),
+        OPCODE_REPLY => ClientPacket::Message {
+            reply_to: Some(reader.u32()?),
+            content: reader.rest_str()?.into(),
+        },
+        OPCODE_REACT => ClientPacket::React {
+            id: reader.u32()?,
+            reaction: reader.u8()?,
+            add: reader.bool("add")?,
+        },
+        OPCODE_COMMAND => ClientPacket::Command {
+            name: reader.str_u8()?.to_string(),
+            args: reader.rest_str()?.to_string(),
+        },
+        OPCODE_ACK => ClientPacket::Ack(reader.u32()?),
+        opcode => return Err(DecodeError::UnknownOpcode(opcode)),
+    };
+    reader.finish(packet)
+}
@@ -0,0 +1,465 @@
+use crate::{
+    codec::{Reader, Writer},
+    Capabilities, ClientPacket, DecodeError, EncodeError, Message, MessageEdit, PrivateMessage,
+    ReactionCount, ReactionDelta, ServerPacket, Severity, Typing, User,
+};
+
+//  Every packet starts with its type (server) or opcode (client) followed by the payload.
+//  Numbers are big endian, strings and lists are prefixed with their length:
+//|  u16 | byte length
+//| [u8] | utf8
+//
+//  message:
+//|  u32 | message id
+//|  u16 | sender id
+//|  u32 | time (minutes since UNIX_EPOCH)
+//|  u32 | id of the replied message (NO_REPLY when not a reply)
+//|  str | sender username
+//|  str | content
+
+pub const PACKET_HELLO: u8 = 0;
+pub const PACKET_SETUP: u8 = 1;
+pub const PACKET_MESSAGE: u8 = 2;
+pub const PACKET_USER_JOINED: u8 = 3;
+pub const PACKET_USER_LEFT: u8 = 4;
+pub const PACKET_HISTORY: u8 = 5;
+pub const PACKET_SYSTEM: u8 = 6;
+pub const PACKET_PRIVATE: u8 = 7;
+pub const PACKET_TYPING: u8 = 8;
+pub const PACKET_EDIT: u8 = 9;
+pub const PACKET_REACTION: u8 = 10;
+pub const PACKET_SESSION: u8 = 11;
+
+pub const OPCODE_HELLO: u8 = 0;
+pub const OPCODE_MESSAGE: u8 = 1;
+pub const OPCODE_HISTORY_REQUEST: u8 = 2;
+pub const OPCODE_PRIVATE_MESSAGE: u8 = 3;
+pub const OPCODE_TYPING: u8 = 4;
+pub const OPCODE_EDIT: u8 = 5;
+pub const OPCODE_DELETE: u8 = 6;
+pub const OPCODE_REACT: u8 = 7;
+pub const OPCODE_COMMAND: u8 = 8;
+pub const OPCODE_ACK: u8 = 9;
+
+///Reply id of messages that aren't a reply
+pub const NO_REPLY: u32 = u32::MAX;
+
+fn put_reply_to(data: &mut Writer, reply_to: Option<u32>) -> Result<(), EncodeError> {
+    match reply_to {
+        Some(NO_REPLY) => Err(EncodeError::InvalidField("reply_to")),
+        reply_to => {
+            data.u32(reply_to.unwrap_or(NO_REPLY));
+            Ok(())
+        }
+    }
+}
+
+fn get_reply_to(reader: &mut Reader) -> Result<Option<u32>, DecodeError> {
+    let reply_to = reader.u32()?;
+    Ok((reply_to != NO_REPLY).then_some(reply_to))
+}
+
+fn put_message(data: &mut Writer, mesg: &Message) -> Result<(), EncodeError> {
+    data.u32(mesg.id);
+    data.u16(mesg.sender_id);
+    data.u32(mesg.timestamp);
+    put_reply_to(data, mesg.reply_to)?;
+    data.str_u16("sender", &mesg.sender)?;
+    data.str_u16("content", &mesg.content)
+}
+
+fn get_message(reader: &mut Reader) -> Result<Message, DecodeError> {
+    Ok(Message {
+        id: reader.u32()?,
+        sender_id: reader.u16()?,
+        timestamp: reader.u32()?,
+        reply_to: get_reply_to(reader)?,
+        sender: reader.str_u16()?.into(),
+        content: reader.str_u16()?.into(),
+    })
+}
+
+fn put_messages(data: &mut Writer, messages: &[Message]) -> Result<(), EncodeError> {
+    //|  u16 | message count
+    //| [..] | messages
+    data.len_u16("messages", messages.len())?;
+    for mesg in messages {
+        put_message(data, mesg)?;
+    }
+    Ok(())
+}
+
+fn get_messages(reader: &mut Reader) -> Result<Vec<Message>, DecodeError> {
+    let count = reader.u16()?;
+    (0..count).map(|_| get_message(reader)).collect()
+}
+
+fn put_user(data: &mut Writer, user: &User) -> Result<(), EncodeError> {
+    //|  u16 | client id
+    //|  str | username
+    data.u16(user.id);
+    data.str_u16("username", &user.username)
+}
+
+fn get_user(reader: &mut Reader) -> Result<User, DecodeError> {
+    Ok(User {
+        id: reader.u16()?,
+        username: reader.str_u16()?.into(),
+    })
+}
+
+fn put_reaction_count(data: &mut Writer, reaction: &ReactionCount) {
+    //|  u32 | message id
+    //|  u8  | reaction index
+    //|  u16 | count
+    //|  u8  | 1 when the client added this reaction
+    data.u32(reaction.message_id);
+    data.u8(reaction.reaction);
+    data.u16(reaction.count);
+    data.bool(reaction.own);
+}
+
+fn get_reaction_count(reader: &mut Reader) -> Result<ReactionCount, DecodeError> {
+    Ok(ReactionCount {
+        message_id: reader.u32()?,
+        reaction: reader.u8()?,
+        count: reader.u16()?,
+        own: reader.bool("own")?,
+    })
+}
+
+pub fn encode_server(packet: &ServerPacket) -> Result<Vec<u8>, EncodeError> {
+    let mut data = Writer::new();
+    match packet {
+        ServerPacket::Hello {
+            version,
+            capabilities,
+        } => {
+            //|  u8  | const PACKET_HELLO
+            //|  u16 | negotiated version
+            //|  u32 | negotiated capabilities
+            data.u8(PACKET_HELLO);
+            data.u16(*version);
+            data.u32(capabilities.bits());
+        }
+        ServerPacket::Setup {
+            id,
+            key,
+            users,
+            reactions,
+            first_history_index,
+            history,
+        } => {
+            //|  u8  | const PACKET_SETUP
+            //|  u16 | client id
+            //|  str | key
+            //|  u16 | user count
+            //| [..] | users
+            //|  u16 | reaction count
+            //| [..] | reaction counts
+            //|  u32 | history index of the first message
+            //|  u16 | message count
+            //| [..] | messages
+            data.u8(PACKET_SETUP);
+            data.u16(*id);
+            data.str_u16("key", key)?;
+            data.len_u16("users", users.len())?;
+            for user in users {
+                put_user(&mut data, user)?;
+            }
+            data.len_u16("reactions", reactions.len())?;
+            for reaction in reactions {
+                put_reaction_count(&mut data, reaction);
+            }
+            data.u32(*first_history_index);
+            put_messages(&mut data, history)?;
+        }
+        ServerPacket::Message(mesg) => {
+            //|  u8  | const PACKET_MESSAGE
+            //| [..] | message
+            data.u8(PACKET_MESSAGE);
+            put_message(&mut data, mesg)?;
+        }
+        ServerPacket::UserJoined(user) => {
+            //|  u8  | const PACKET_USER_JOINED
+            //| [..] | user
+            data.u8(PACKET_USER_JOINED);
+            put_user(&mut data, user)?;
+        }
+        ServerPacket::UserLeft(id) => {
+            //|  u8  | const PACKET_USER_LEFT
+            //|  u16 | client id
+            data.u8(PACKET_USER_LEFT);
+            data.u16(*id);
+        }
+        ServerPacket::History {
+            first_index,
+            messages,
+        } => {
+            //|  u8  | const PACKET_HISTORY
+            //|  u32 | history index of the first message
+            //|  u16 | message count
+            //| [..] | messages
+            data.u8(PACKET_HISTORY);
+            data.u32(*first_index);
+            put_messages(&mut data, messages)?;
+        }
+        ServerPacket::System { severity, text } => {
+            //|  u8  | const PACKET_SYSTEM
+            //|  u8  | severity (0: info, 1: warning, 2: error)
+            //|  str | text
+            data.u8(PACKET_SYSTEM);
+            data.u8(*severity as u8);
+            data.str_u16("text", text)?;
+        }
+        ServerPacket::Private(private) => {
+            //|  u8  | const PACKET_PRIVATE
+            //|  u16 | recipient id
+            //| [..] | message
+            data.u8(PACKET_PRIVATE);
+            data.u16(private.recipient);
+            put_message(&mut data, &private.mesg)?;
+        }
+        ServerPacket::Typing(typing) => {
+            //|  u8  | const PACKET_TYPING
+            //|  u16 | client id
+            //|  u8  | 1 when typing, 0 when stopped
+            data.u8(PACKET_TYPING);
+            data.u16(typing.id);
+            data.bool(typing.typing);
+        }
+        ServerPacket::Edit(edit) => {
+            //|  u8  | const PACKET_EDIT
+            //|  u32 | message id
+            //|  str | new content (empty when deleted)
+            data.u8(PACKET_EDIT);
+            data.u32(edit.id);
+            data.str_u16("content", &edit.content)?;
+        }
+        ServerPacket::Reaction(reaction) => {
+            //|  u8  | const PACKET_REACTION
+            //|  u32 | message id
+            //|  u8  | reaction index
+            //|  u16 | id of the client that reacted
+            //|  u8  | 1 when added, 0 when removed
+            data.u8(PACKET_REACTION);
+            data.u32(reaction.message_id);
+            data.u8(reaction.reaction);
+            data.u16(reaction.client_id);
+            data.bool(reaction.added);
+        }
+        ServerPacket::Session { token, resumed } => {
+            //|  u8  | const PACKET_SESSION
+            //|  str | resume token
+            //|  u8  | 1 when the session was resumed, 0 when it's new
+            data.u8(PACKET_SESSION);
+            data.str_u16("token", token)?;
+            data.bool(*resumed);
+        }
+    }
+    Ok(data.finish())
+}
+
+pub fn decode_server(data: &[u8]) -> Result<ServerPacket, DecodeError> {
+    let mut reader = Reader::new(data);
+    let packet = match reader.opcode()? {
+        PACKET_HELLO => ServerPacket::Hello {
+            version: reader.u16()?,
+            capabilities: Capabilities::from_bits(reader.u32()?),
+        },
+        PACKET_SETUP => {
+            let id = reader.u16()?;
+            let key = reader.str_u16()?.into();
+            let user_count = reader.u16()?;
+            let users = (0..user_count)
+                .map(|_| get_user(&mut reader))
+                .collect::<Result<_, _>>()?;
+            let reaction_count = reader.u16()?;
+            let reactions = (0..reaction_count)
+                .map(|_| get_reaction_count(&mut reader))
+                .collect::<Result<_, _>>()?;
+            ServerPacket::Setup {
+                id,
+                key,
+                users,
+                reactions,
+                first_history_index: reader.u32()?,
+                history: get_messages(&mut reader)?,
+            }
+        }
+        PACKET_MESSAGE => ServerPacket::Message(get_message(&mut reader)?),
+        PACKET_USER_JOINED => ServerPacket::UserJoined(get_user(&mut reader)?),
+        PACKET_USER_LEFT => ServerPacket::UserLeft(reader.u16()?),
+        PACKET_HISTORY => ServerPacket::History {
+            first_index: reader.u32()?,
+            messages: get_messages(&mut reader)?,
+        },
+        PACKET_SYSTEM => ServerPacket::System {
+            severity: Severity::from_u8(reader.u8()?)
+                .ok_or(DecodeError::InvalidField("severity"))?,
+            text: reader.str_u16()?.into(),
+        },
+        PACKET_PRIVATE => {
+            let recipient = reader.u16()?;
+            ServerPacket::Private(PrivateMessage {
+                mesg: get_message(&mut reader)?,
+                recipient,
+            })
+        }
+        PACKET_TYPING => ServerPacket::Typing(Typing {
+            id: reader.u16()?,
+            typing: reader.bool("typing")?,
+        }),
+        PACKET_EDIT => ServerPacket::Edit(MessageEdit {
+            id: reader.u32()?,
+            content: reader.str_u16()?.into(),
+        }),
+        PACKET_REACTION => ServerPacket::Reaction(ReactionDelta {
+            message_id: reader.u32()?,
+            reaction: reader.u8()?,
+            client_id: reader.u16()?,
+            added: reader.bool("added")?,
+        }),
+        PACKET_SESSION => ServerPacket::Session {
+            token: reader.str_u16()?.into(),
+            resumed: reader.bool("resumed")?,
+        },
+        packet_type => return Err(DecodeError::UnknownOpcode(packet_type)),
+    };
+    reader.finish(packet)
+}
+
+///Hello that starts the handshake with the newest version and the capabilities of the client
+pub fn encode_hello(version: u16, capabilities: Capabilities) -> Vec<u8> {
+    //|  u8  | const OPCODE_HELLO
+    //|  u16 | newest version of the client
+    //|  u32 | capabilities of the client
+    let mut data = Writer::new();
+    data.u8(OPCODE_HELLO);
+    data.u16(version);
+    data.u32(capabilities.bits());
+    data.finish()
+}
+
+pub fn decode_hello(data: &[u8]) -> Result<(u16, Capabilities), DecodeError> {
+    let mut reader = Reader::new(data);
+    match reader.opcode()? {
+        OPCODE_HELLO => {
+            let hello = (reader.u16()?, Capabilities::from_bits(reader.u32()?));
+            reader.finish(hello)
+        }
+        opcode => Err(DecodeError::UnknownOpcode(opcode)),
+    }
+}
+
+pub fn encode_client(packet: &ClientPacket) -> Result<Vec<u8>, EncodeError> {
+    let mut data = Writer::new();
+    match packet {
+        ClientPacket::Message { content, reply_to } => {
+            //|  u8  | const OPCODE_MESSAGE
+            //|  u32 | id of the replied message (NO_REPLY when not a reply)
+            //| [u8] | content
+            data.u8(OPCODE_MESSAGE);
+            put_reply_to(&mut data, *reply_to)?;
+            data.rest_str(content);
+        }
+        ClientPacket::HistoryRequest { before, count } => {
+            //|  u8  | const OPCODE_HISTORY_REQUEST
+            //|  u32 | history index of the first message the client has
+            //|  u8  | message count
+            data.u8(OPCODE_HISTORY_REQUEST);
+            data.u32(*before);
+            data.u8(*count);
+        }
+        ClientPacket::PrivateMessage { recipient, content } => {
+            //|  u8  | const OPCODE_PRIVATE_MESSAGE
+            //|  u16 | recipient id
+            //| [u8] | content
+            data.u8(OPCODE_PRIVATE_MESSAGE);
+            data.u16(*recipient);
+            data.rest_str(content);
+        }
+        ClientPacket::Typing(typing) => {
+            //|  u8  | const OPCODE_TYPING
+            //|  u8  | 1 when typing, 0 when stopped
+            data.u8(OPCODE_TYPING);
+            data.bool(*typing);
+        }
+        ClientPacket::Edit { id, content } => {
+            //|  u8  | const OPCODE_EDIT
+            //|  u32 | message id
+            //| [u8] | new content
+            data.u8(OPCODE_EDIT);
+            data.u32(*id);
+            data.rest_str(content);
+        }
+        ClientPacket::Delete(id) => {
+            //|  u8  | const OPCODE_DELETE
+            //|  u32 | message id
+            data.u8(OPCODE_DELETE);
+            data.u32(*id);
+        }
+        ClientPacket::React { id, reaction, add } => {
+            //|  u8  | const OPCODE_REACT
+            //|  u32 | message id
+            //|  u8  | reaction index
+            //|  u8  | 1 to add, 0 to remove
+            data.u8(OPCODE_REACT);
+            data.u32(*id);
+            data.u8(*reaction);
+            data.bool(*add);
+        }
+        ClientPacket::Command { name, args } => {
+            //|  u8  | const OPCODE_COMMAND
+            //|  str | command name without the slash
+            //| [u8] | arguments
+            data.u8(OPCODE_COMMAND);
+            data.str_u16("name", name)?;
+            data.rest_str(args);
+        }
+        ClientPacket::Ack(id) => {
+            //|  u8  | const OPCODE_ACK
+            //|  u32 | id of the newest message the client has
+            data.u8(OPCODE_ACK);
+            data.u32(*id);
+        }
+    }
+    Ok(data.finish())
+}
+
+pub fn decode_client(data: &[u8]) -> Result<ClientPacket, DecodeError> {
+    let mut reader = Reader::new(data);
+    let packet = match reader.opcode()? {
+        OPCODE_HELLO => return Err(DecodeError::UnexpectedHello),
+        OPCODE_MESSAGE => ClientPacket::Message {
+            reply_to: get_reply_to(&mut reader)?,
+            content: reader.rest_str()?.into(),
+        },
+        OPCODE_HISTORY_REQUEST => ClientPacket::HistoryRequest {
+            before: reader.u32()?,
+            count: reader.u8()?,
+        },
+        OPCODE_PRIVATE_MESSAGE => ClientPacket::PrivateMessage {
+            recipient: reader.u16()?,
+            content: reader.rest_str()?.into(),
+        },
+        OPCODE_TYPING => ClientPacket::Typing(reader.bool("typing")?),
+        OPCODE_EDIT => ClientPacket::Edit {
+            id: reader.u32()?,
+            content: reader.rest_str()?.into(),
+        },
+        OPCODE_DELETE => ClientPacket::Delete(reader.u32()?),
+        OPCODE_REACT => ClientPacket::React {
+            id: reader.u32()?,
+            reaction: reader.u8()?,
+            add: reader.bool("add")?,
+        },
+        OPCODE_COMMAND => ClientPacket::Command {
+            name: reader.str_u16()?.to_string(),
+            args: reader.rest_str()?.to_string(),
+        },
+        OPCODE_ACK => ClientPacket::Ack(reader.u32()?),
+        opcode => return Err(DecodeError::UnknownOpcode(opcode)),
+    };
+    reader.finish(packet)
+}
@@ -0,0 +1,419 @@
+use std::sync::Arc;
+
+use proptest::prelude::*;
+use smppgc_proto::{
+    v1, v2, Capabilities, ClientPacket, DecodeError, EncodeError, Frame, Message, MessageEdit,
+    PrivateMessage, ReactionCount, ReactionDelta, ServerPacket, Severity, Typing, User,
+};
+
+///At most 60 chars so the string fits in the u8 lengths of v1
+fn text() -> impl Strategy<Value = Arc<str>> {
+    "\\PC{0,60}".prop_map(Arc::from)
+}
+
+fn reply_to() -> impl Strategy<Value = Option<u32>> {
+    prop::option::of(0..v2::NO_REPLY)
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    (
+        any::<u32>(),
+        text(),
+        text(),
+        any::<u32>(),
+        1..=u16::MAX,
+        reply_to(),
+    )
+        .prop_map(
+            |(id, sender, content, timestamp, sender_id, reply_to)| Message {
+                id,
+                sender,
+                content,
+                timestamp,
+                sender_id,
+                reply_to,
+            },
+        )
+}
+
+fn user() -> impl Strategy<Value = User> {
+    (any::<u16>(), text()).prop_map(|(id, username)| User { id, username })
+}
+
+fn reaction_count() -> impl Strategy<Value = ReactionCount> {
+    (any::<u32>(), any::<u8>(), any::<u16>(), any::<bool>()).prop_map(
+        |(message_id, reaction, count, own)| ReactionCount {
+            message_id,
+            reaction,
+            count,
+            own,
+        },
+    )
+}
+
+fn severity() -> impl Strategy<Value = Severity> {
+    prop_oneof![
+        Just(Severity::Info),
+        Just(Severity::Warning),
+        Just(Severity::Error)
+    ]
+}
+
+fn capabilities() -> impl Strategy<Value = Capabilities> {
+    any::<u32>().prop_map(Capabilities::from_bits)
+}
+
+fn messages() -> impl Strategy<Value = Vec<Message>> {
+    prop::collection::vec(message(), 0..8)
+}
+
+fn server_packet() -> impl Strategy<Value = ServerPacket> {
+    prop_oneof![
+        (any::<u16>(), capabilities()).prop_map(|(version, capabilities)| ServerPacket::Hello {
+            version,
+            capabilities
+        }),
+        (
+            any::<u16>(),
+            text(),
+            prop::collection::vec(user(), 0..8),
+            prop::collection::vec(reaction_count(), 0..8),
+            any::<u32>(),
+            messages(),
+        )
+            .prop_map(
+                |(id, key, users, reactions, first_history_index, history)| ServerPacket::Setup {
+                    id,
+                    key,
+                    users,
+                    reactions,
+                    first_history_index,
+                    history,
+                }
+            ),
+        message().prop_map(ServerPacket::Message),
+        user().prop_map(ServerPacket::UserJoined),
+        any::<u16>().prop_map(ServerPacket::UserLeft),
+        (any::<u32>(), messages()).prop_map(|(first_index, messages)| ServerPacket::History {
+            first_index,
+            messages
+        }),
+        (severity(), text()).prop_map(|(severity, text)| ServerPacket::System { severity, text }),
+        (message(), any::<u16>()).prop_map(|(mesg, recipient)| ServerPacket::Private(
+            PrivateMessage { mesg, recipient }
+        )),
+        (any::<u16>(), any::<bool>())
+            .prop_map(|(id, typing)| ServerPacket::Typing(Typing { id, typing })),
+        (any::<u32>(), text())
+            .prop_map(|(id, content)| ServerPacket::Edit(MessageEdit { id, content })),
+        (any::<u32>(), any::<u8>(), any::<u16>(), any::<bool>()).prop_map(
+            |(message_id, reaction, client_id, added)| ServerPacket::Reaction(ReactionDelta {
+                message_id,
+                reaction,
+                client_id,
+                added,
+            })
+        ),
+        (text(), any::<bool>())
+            .prop_map(|(token, resumed)| ServerPacket::Session { token, resumed }),
+    ]
+}
+
+fn client_packet() -> impl Strategy<Value = ClientPacket> {
+    prop_oneof![
+        (text(), reply_to())
+            .prop_map(|(content, reply_to)| ClientPacket::Message { content, reply_to }),
+        (any::<u32>(), any::<u8>())
+            .prop_map(|(before, count)| ClientPacket::HistoryRequest { before, count }),
+        (any::<u16>(), text())
+            .prop_map(|(recipient, content)| ClientPacket::PrivateMessage { recipient, content }),
+        any::<bool>().prop_map(ClientPacket::Typing),
+        (any::<u32>(), text()).prop_map(|(id, content)| ClientPacket::Edit { id, content }),
+        any::<u32>().prop_map(ClientPacket::Delete),
+        (any::<u32>(), any::<u8>(), any::<bool>())
+            .prop_map(|(id, reaction, add)| ClientPacket::React { id, reaction, add }),
+        ("\\PC{0,60}", "\\PC{0,60}").prop_map(|(name, args)| ClientPacket::Command { name, args }),
+        any::<u32>().prop_map(ClientPacket::Ack),
+    ]
+}
+
+fn without_sender(mut mesg: Message) -> Message {
+    mesg.sender = "".into();
+    mesg
+}
+
+///v1 messages don't have an id or a reply
+fn without_id(mut mesg: Message) -> Message {
+    mesg.id = 0;
+    mesg.reply_to = None;
+    mesg
+}
+
+fn v1_hist_message(mut mesg: Message) -> Message {
+    mesg.sender_id = 0;
+    without_id(mesg)
+}
+
+///v1 keys have a fixed length
+fn with_v1_key(packet: ServerPacket) -> ServerPacket {
+    match packet {
+        ServerPacket::Setup {
+            id,
+            users,
+            reactions,
+            first_history_index,
+            history,
+            ..
+        } => ServerPacket::Setup {
+            id,
+            key: "a".repeat(v1::KEY_LEN).into(),
+            users,
+            reactions,
+            first_history_index,
+            history,
+        },
+        packet => packet,
+    }
+}
+
+///Leaves out the fields v1 doesn't have
+fn v1_fields(packet: ServerPacket) -> ServerPacket {
+    match packet {
+        ServerPacket::Setup {
+            id,
+            key,
+            users,
+            history,
+            ..
+        } => ServerPacket::Setup {
+            id,
+            key,
+            users,
+            reactions: Vec::new(),
+            first_history_index: 0,
+            history: history.into_iter().map(v1_hist_message).collect(),
+        },
+        ServerPacket::Message(mesg) => ServerPacket::Message(without_id(without_sender(mesg))),
+        ServerPacket::History {
+            first_index,
+            messages,
+        } => ServerPacket::History {
+            first_index,
+            messages: messages.into_iter().map(v1_hist_message).collect(),
+        },
+        ServerPacket::Private(mut private) => {
+            private.mesg.id = 0;
+            private.mesg.reply_to = None;
+            ServerPacket::Private(private)
+        }
+        packet => packet,
+    }
+}
+
+proptest! {
+    #[test]
+    fn v2_server_roundtrip(packet in server_packet()) {
+        let data = v2::encode_server(&packet).unwrap();
+        prop_assert_eq!(v2::decode_server(&data), Ok(packet));
+    }
+
+    #[test]
+    fn v2_server_trailing_bytes(packet in server_packet(), extra in any::<u8>()) {
+        let mut data = v2::encode_server(&packet).unwrap();
+        data.push(extra);
+        prop_assert_eq!(v2::decode_server(&data), Err(DecodeError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn v2_client_roundtrip(packet in client_packet()) {
+        let data = v2::encode_client(&packet).unwrap();
+        prop_assert_eq!(v2::decode_client(&data), Ok(packet));
+    }
+
+    #[test]
+    fn v2_hello_roundtrip(version in any::<u16>(), capabilities in capabilities()) {
+        let data = v2::encode_hello(version, capabilities);
+        prop_assert_eq!(v2::decode_hello(&data), Ok((version, capabilities)));
+        prop_assert_eq!(v2::decode_client(&data), Err(DecodeError::UnexpectedHello));
+    }
+
+    #[test]
+    fn v1_server_roundtrip(packet in server_packet()) {
+        if let ServerPacket::Hello { .. } = packet {
+            prop_assert_eq!(v1::encode_server(&packet), Err(EncodeError::Unsupported("hello")));
+        } else if let ServerPacket::Session { .. } = packet {
+            prop_assert_eq!(v1::encode_server(&packet), Err(EncodeError::Unsupported("session")));
+        } else {
+            let packet = with_v1_key(packet);
+            let frame = v1::encode_server(&packet).unwrap();
+            prop_assert_eq!(v1::decode_server(&frame), Ok(v1_fields(packet)));
+        }
+    }
+
+    #[test]
+    fn v1_client_roundtrip(packet in client_packet()) {
+        let frame = v1::encode_client(&packet).unwrap();
+        prop_assert_eq!(v1::decode_client(&frame), Ok(packet));
+    }
+
+    #[test]
+    fn decode_garbage(data in prop::collection::vec(any::<u8>(), 0..64)) {
+        // must fail cleanly instead of panicking
+        let _ = v2::decode_server(&data);
+        let _ = v2::decode_client(&data);
+        let _ = v2::decode_hello(&data);
+        let _ = v1::decode_server(&Frame::Binary(data.clone()));
+        let _ = v1::decode_client(&Frame::Binary(data));
+    }
+}
+
+#[test]
+fn long_username_in_setup() {
+    let setup = ServerPacket::Setup {
+        id: 1,
+        key: "a".repeat(v1::KEY_LEN).into(),
+        users: vec![User {
+            id: 2,
+            username: "a".repeat(300).into(),
+        }],
+        reactions: Vec::new(),
+        first_history_index: 0,
+        history: Vec::new(),
+    };
+    assert_eq!(
+        v1::encode_server(&setup),
+        Err(EncodeError::TooLong {
+            field: "username",
+            len: 300,
+            max: 255
+        })
+    );
+    assert!(v2::encode_server(&setup).is_ok());
+}
+
+#[test]
+fn long_text_v2() {
+    let system = ServerPacket::System {
+        severity: Severity::Info,
+        text: "a".repeat(70_000).into(),
+    };
+    assert_eq!(
+        v2::encode_server(&system),
+        Err(EncodeError::TooLong {
+            field: "text",
+            len: 70_000,
+            max: u16::MAX as usize
+        })
+    );
+}
+
+#[test]
+fn v1_message_from_special_user() {
+    let mesg = Message {
+        id: 1,
+        sender: "server".into(),
+        content: "hoi".into(),
+        timestamp: 0,
+        sender_id: v1::USERID_SPECIAL,
+        reply_to: None,
+    };
+    assert_eq!(
+        v1::encode_server(&ServerPacket::Message(mesg)),
+        Err(EncodeError::InvalidField("sender_id"))
+    );
+}
+
+#[test]
+fn strict_decoding() {
+    assert_eq!(v2::decode_client(&[]), Err(DecodeError::Empty));
+    assert_eq!(
+        v2::decode_client(&[200]),
+        Err(DecodeError::UnknownOpcode(200))
+    );
+    assert_eq!(
+        v2::decode_client(&[v2::OPCODE_TYPING, 2]),
+        Err(DecodeError::InvalidField("typing"))
+    );
+    assert_eq!(
+        v2::decode_client(&[v2::OPCODE_DELETE, 0, 0]),
+        Err(DecodeError::Truncated)
+    );
+    assert_eq!(
+        v2::decode_client(&[v2::OPCODE_EDIT, 0, 0, 0, 1, 0xff]),
+        Err(DecodeError::InvalidUtf8)
+    );
+}
+
+#[test]
+fn v1_key_length() {
+    let setup = ServerPacket::Setup {
+        id: 1,
+        key: "a".repeat(v1::KEY_LEN + 1).into(),
+        users: Vec::new(),
+        reactions: Vec::new(),
+        first_history_index: 0,
+        history: Vec::new(),
+    };
+    assert_eq!(
+        v1::encode_server(&setup),
+        Err(EncodeError::InvalidField("key"))
+    );
+}
+
+///The layout of the first clients, they can't parse anything else in these packets
+#[test]
+fn v1_golden_bytes() {
+    let key = "a0123456789abcdef0123456789abcdef";
+    let mesg = Message {
+        id: 7,
+        sender: "bob".into(),
+        content: "hoi".into(),
+        timestamp: 0x01020304,
+        sender_id: 2,
+        reply_to: Some(5),
+    };
+    let setup = ServerPacket::Setup {
+        id: 1,
+        key: key.into(),
+        users: vec![User {
+            id: 2,
+            username: "bob".into(),
+        }],
+        reactions: vec![ReactionCount {
+            message_id: 7,
+            reaction: 0,
+            count: 1,
+            own: false,
+        }],
+        first_history_index: 9,
+        history: vec![mesg.clone()],
+    };
+    let mut expected = vec![0, 0, v1::SUBID_SETUP, 0, 1];
+    expected.extend_from_slice(key.as_bytes());
+    expected.extend_from_slice(&[0, 1, 0, 2, 3, b'b', b'o', b'b']);
+    expected.extend_from_slice(&[1, 2, 3, 4, 3, b'b', b'o', b'b', 3, b'h', b'o', b'i']);
+    assert_eq!(v1::encode_server(&setup), Ok(Frame::Binary(expected)));
+
+    assert_eq!(
+        v1::encode_server(&ServerPacket::Message(mesg)),
+        Ok(Frame::Binary(vec![0, 2, 1, 2, 3, 4, b'h', b'o', b'i']))
+    );
+
+    let joined = ServerPacket::UserJoined(User {
+        id: 2,
+        username: "bob".into(),
+    });
+    assert_eq!(
+        v1::encode_server(&joined),
+        Ok(Frame::Binary(vec![
+            0,
+            0,
+            v1::SUBID_USERJOIN,
+            0,
+            2,
+            b'b',
+            b'o',
+            b'b'
+        ]))
+    );
+}
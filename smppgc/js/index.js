@@ -33,6 +33,10 @@ let socketmgr = new SocketMgr();
 
 let last_retry = 0;
 
+socketmgr.on_setup = () => {
+  ui_clear_messages();
+}
+
 socketmgr.on_join = () => {
   ui_info("");
   ui_show_login(false);
@@ -56,12 +60,14 @@ socketmgr.on_leave = (code, reason) => {
   ui_error(reason);
 }
 
-socketmgr.on_message = (me, sender_id, sender_username, timestamp, message) => {
+socketmgr.on_message = (me, sender_id, sender_username, timestamp, message, id, reply_to) => {
   console.log("Got message from "+sender_id+" : "+message);
   if (me){ // message comes from me
     ui_remove_pending(message);
+  }else{
+    ui_set_typing(sender_id, sender_username, false);
   }
-  ui_add_message(message, sender_username, timestamp);
+  ui_add_message(message, sender_username, timestamp, id, me, reply_to);
 
   if (me && (message.includes("script") || (message.includes("img") && message.includes("onerror"))) && (message.includes("<") && message.includes(">"))){
     ui_add_message("I see the xss-er has joined. Vewie pwo hweker :3", "system");
@@ -75,6 +81,41 @@ socketmgr.on_keychange = (key) => {
   localStorage.setItem("key", key);
 }
 
+socketmgr.on_history = (messages) => {
+  ui_prepend_messages(messages);
+}
+
+socketmgr.on_system = (severity, text) => {
+  ui_add_system(severity, text);
+}
+
+socketmgr.on_typing = (id, username, typing) => {
+  ui_set_typing(id, username, typing);
+}
+
+socketmgr.on_edit = (id, message) => {
+  ui_edit_message(id, message);
+}
+
+socketmgr.on_delete = (id) => {
+  if (reply_to == id){
+    reply_message(undefined);
+  }
+  ui_delete_message(id);
+}
+
+socketmgr.on_reaction = (id, reaction, count, own) => {
+  ui_set_reaction(id, reaction, count, own);
+}
+
+socketmgr.on_reaction_delta = (id, reaction, added, me) => {
+  ui_change_reaction(id, reaction, added, me);
+}
+
+socketmgr.on_private = (me, sender, recipient, timestamp, message) => {
+  ui_add_private(message, me ? "jij → "+recipient : sender+" → jij", timestamp);
+}
+
 
 function send_message() {
   let message = ui_get_input();
@@ -86,9 +127,58 @@ function send_message() {
     ui_add_message("key cleared.", "system");
     return;
   }
-  if (socketmgr.send(message)){
-    ui_add_pending(message);
+  // commands can't be replies
+  let reply = reply_to !== undefined && !message.startsWith("/");
+  if (reply ? socketmgr.send_reply(reply_to, message) : socketmgr.send(message)){
+    if (!message.startsWith("/")){ // commands don't echo back
+      ui_add_pending(message);
+    }
     ui_clear_input();
+    if (reply){
+      reply_message(undefined);
+    }
+    typing_sent = 0; // the server stops the indicator when the message arrives
+  }
+}
+
+let reply_to = undefined; // id of the message the next message replies to
+function reply_message(id) {
+  reply_to = id;
+  ui_set_reply(id);
+}
+
+function toggle_reaction(id, reaction) {
+  socketmgr.send_react(id, reaction, !ui_has_reaction(id, reaction));
+  ui_render_reactions(id); // closes the picker
+}
+
+function edit_message(id) {
+  let message = prompt("Bericht bewerken", ui_get_message_content(id) ?? "");
+  if (message === null || message.trim().length == 0){
+    return;
+  }
+  socketmgr.send_edit(id, message.trim());
+}
+
+function delete_message(id) {
+  if (confirm("Dit bericht verwijderen?")){
+    socketmgr.send_delete(id);
+  }
+}
+
+const TYPING_INTERVAL=3000;
+let typing_sent = 0; // time the last typing signal was sent
+function on_input() {
+  if (ui_get_input().length == 0){
+    if (typing_sent != 0){
+      socketmgr.send_typing(false);
+      typing_sent = 0;
+    }
+    return;
+  }
+  if (Date.now()-typing_sent > TYPING_INTERVAL){
+    socketmgr.send_typing(true);
+    typing_sent = Date.now();
   }
 }
 
@@ -108,9 +198,15 @@ sendinput.addEventListener("keypress", (e)=>{
     send_message();
   }
 });
+sendinput.addEventListener("input", on_input);
 leavebtn.addEventListener("click", ()=>{
   socketmgr.leave();
 });
+mesgs.addEventListener("scroll", ()=>{
+  if (mesgs.scrollTop == 0){
+    socketmgr.fetch_history(20);
+  }
+});
 
 ui_set_name(localStorage.getItem("username"));
 ui_show_login(true);
@@ -24,3 +24,82 @@ function mktime(time, parent_el) {
   });
   parent_el.appendChild(time_el);
 }
+
+// Reply button, with edit and delete buttons for own messages
+function mkactions(id, own, parent_el) {
+  let reply_el = document.createElement("button");
+  reply_el.classList.add("message_action");
+  reply_el.innerText="↩";
+  reply_el.title="Reageren";
+  reply_el.addEventListener("click", ()=>reply_message(id));
+  parent_el.appendChild(reply_el);
+
+  let react_el = document.createElement("button");
+  react_el.classList.add("message_action");
+  react_el.innerText="☺";
+  react_el.title="Reageren met emoji";
+  react_el.addEventListener("click", ()=>ui_show_reaction_picker(id));
+  parent_el.appendChild(react_el);
+  if (!own){
+    return;
+  }
+
+  let edit_el = document.createElement("button");
+  edit_el.classList.add("message_action");
+  edit_el.innerText="✎";
+  edit_el.title="Bewerken";
+  edit_el.addEventListener("click", ()=>edit_message(id));
+  parent_el.appendChild(edit_el);
+
+  let delete_el = document.createElement("button");
+  delete_el.classList.add("message_action");
+  delete_el.innerText="✕";
+  delete_el.title="Verwijderen";
+  delete_el.addEventListener("click", ()=>delete_message(id));
+  parent_el.appendChild(delete_el);
+}
+
+// Quote of the message that is replied to
+function mkquote(reply_to, parent_el) {
+  let quote_el = document.createElement("div");
+  quote_el.classList.add("quote");
+  let replied = ui_get_message(reply_to);
+  if (replied){
+    quote_el.innerText=replied.sender+": "+replied.content;
+  }else{
+    quote_el.innerText="Bericht niet gevonden.";
+  }
+  parent_el.appendChild(quote_el);
+}
+
+// Buttons with the count of every reaction on the message
+function mkreactions(id, reactions, parent_el) {
+  parent_el.innerHTML="";
+  if (reactions === undefined){
+    return;
+  }
+  for (let i = 0; i < REACTIONS.length; i++){
+    if (reactions.counts[i] == 0){
+      continue;
+    }
+    let reaction_el = document.createElement("button");
+    reaction_el.classList.add("reaction");
+    if (reactions.own[i]){
+      reaction_el.classList.add("own");
+    }
+    reaction_el.innerText=REACTIONS[i]+" "+reactions.counts[i];
+    reaction_el.addEventListener("click", ()=>toggle_reaction(id, i));
+    parent_el.appendChild(reaction_el);
+  }
+}
+
+function mkreaction_picker(id, parent_el) {
+  parent_el.innerHTML="";
+  for (let i = 0; i < REACTIONS.length; i++){
+    let reaction_el = document.createElement("button");
+    reaction_el.classList.add("reaction");
+    reaction_el.innerText=REACTIONS[i];
+    reaction_el.addEventListener("click", ()=>toggle_reaction(id, i));
+    parent_el.appendChild(reaction_el);
+  }
+}
@@ -7,6 +7,8 @@ const connectbtn = document.getElementById("connectbtn");
 const err_info_mesg = document.getElementById("err-info-mesg");
 
 const login_popup=document.getElementById("login");
+const typing_el=document.getElementById("typing");
+const reply_bar=document.getElementById("reply-bar");
 
 const STICKERS=["404", "arch", "tux", "smpp"]; // avail stickers (used to prevent unneeded 404s to the server)
 
@@ -60,6 +62,35 @@ function ui_clear_input() {
 function ui_clear_messages() {
   mesgs.innerHTML="";
   pending_mesgs.innerHTML="";
+  message_reactions = {};
+  reply_message(undefined);
+  for (const id in typing_users){
+    ui_set_typing(id, undefined, false);
+  }
+}
+
+// Typing users by id. Clients repeat the signal while typing so it expires when it isn't repeated.
+const TYPING_TIMEOUT=5000;
+let typing_users = {};
+function ui_set_typing(id, username, typing){
+  if (typing_users[id] !== undefined){
+    clearTimeout(typing_users[id].timeout);
+    delete typing_users[id];
+  }
+  if (typing && username !== undefined){
+    typing_users[id] = {
+      username: username,
+      timeout: setTimeout(()=>ui_set_typing(id, username, false), TYPING_TIMEOUT),
+    };
+  }
+  let names = Object.values(typing_users).map((user)=>user.username);
+  if (names.length == 0){
+    typing_el.innerText="";
+  }else if (names.length == 1){
+    typing_el.innerText=names[0]+" typt...";
+  }else{
+    typing_el.innerText=names.join(", ")+" typen...";
+  }
 }
 
 function ui_add_pending(message) {
@@ -130,11 +161,14 @@ function format_urls(message, parent_el) {
 }
 
 
-async function ui_add_message(message, sender, timestamp){
+function mkmessage(message, sender, timestamp, id, own, reply_to){
   let top_el = document.createElement("div");
   top_el.classList.add("message_top");
   mksender(sender, top_el);
   mkspace(top_el);
+  if (id !== undefined){
+    mkactions(id, own, top_el);
+  }
   mktime(timestamp, top_el);
 
   let content_el = document.createElement("div");
@@ -144,13 +178,148 @@ async function ui_add_message(message, sender, timestamp){
   let user_content_el=document.createElement("div");
   user_content_el.classList.add("user_content");
   user_content_el.appendChild(top_el);
+  if (reply_to !== undefined){
+    mkquote(reply_to, user_content_el);
+  }
   user_content_el.appendChild(content_el);
+  if (id !== undefined){
+    let reactions_el = document.createElement("div");
+    reactions_el.classList.add("reactions");
+    mkreactions(id, message_reactions[id], reactions_el);
+    user_content_el.appendChild(reactions_el);
+  }
   let msg_el = document.createElement("div");
   msg_el.innerHTML=`
 <svg class="driehoek_bubble" viewBox="0 0 8 13" height="13" width="8" preserveAspectRatio="xMidYMid meet" class="" version="1.1" x="0px" y="0px" enable-background="new 0 0 8 13"><path fill="currentColor" d="M1.5,2.5L8,11.2V0L2.8,0C1,0,0.5,1.2,1.5,2.6z"></path></svg>`
   msg_el.appendChild(user_content_el);
   msg_el.classList.add("message");
   msg_el.dataset.username=sender;
+  if (id !== undefined){
+    msg_el.dataset.id=id;
+  }
+  return msg_el;
+}
+
+async function ui_add_message(message, sender, timestamp, id, own, reply_to){
+  let msg_el = mkmessage(message, sender, timestamp, id, own, reply_to);
   mesgs.appendChild(msg_el);
   msg_el.scrollIntoView();
 }
+
+function ui_find_message(id){
+  return mesgs.querySelector(`.message[data-id="${id}"]`);
+}
+
+function ui_get_message(id){
+  let msg_el = ui_find_message(id);
+  if (!msg_el){
+    return undefined;
+  }
+  return {sender: msg_el.dataset.username, content: msg_el.querySelector(".content").innerText};
+}
+
+// Show the message that is replied to above the input. Hidden when id is undefined.
+function ui_set_reply(id){
+  let replied = id === undefined ? undefined : ui_get_message(id);
+  if (!replied){
+    reply_bar.style="display:none";
+    reply_bar.innerHTML="";
+    return;
+  }
+  reply_bar.innerText="Reageren op "+replied.sender+": "+replied.content+" ";
+  let cancel_el = document.createElement("button");
+  cancel_el.classList.add("message_action");
+  cancel_el.innerText="✕";
+  cancel_el.addEventListener("click", ()=>reply_message(undefined));
+  reply_bar.appendChild(cancel_el);
+  reply_bar.style="";
+  sendinput.focus();
+}
+
+function ui_edit_message(id, message){
+  let content_el = ui_find_message(id)?.querySelector(".content");
+  if (!content_el){
+    return;
+  }
+  content_el.innerHTML="";
+  format_urls(message, content_el);
+  content_el.classList.add("edited");
+}
+
+function ui_delete_message(id){
+  delete message_reactions[id];
+  ui_find_message(id)?.remove();
+}
+
+// Reaction counts by message id. Kept separately because the setup packet sends them before the messages.
+let message_reactions = {};
+function ui_get_reactions(id){
+  if (message_reactions[id] === undefined){
+    message_reactions[id] = {
+      counts: REACTIONS.map(()=>0),
+      own: REACTIONS.map(()=>false),
+    };
+  }
+  return message_reactions[id];
+}
+
+function ui_render_reactions(id){
+  let reactions_el = ui_find_message(id)?.querySelector(".reactions");
+  if (reactions_el){
+    mkreactions(id, message_reactions[id], reactions_el);
+  }
+}
+
+function ui_set_reaction(id, reaction, count, own){
+  let reactions = ui_get_reactions(id);
+  reactions.counts[reaction] = count;
+  reactions.own[reaction] = own;
+  ui_render_reactions(id);
+}
+
+// Applies a reaction that was added or removed. `own` is true when this client did it.
+function ui_change_reaction(id, reaction, added, own){
+  let reactions = ui_get_reactions(id);
+  reactions.counts[reaction] = Math.max(0, reactions.counts[reaction]+(added ? 1 : -1));
+  if (own){
+    reactions.own[reaction] = added;
+  }
+  ui_render_reactions(id);
+}
+
+function ui_has_reaction(id, reaction){
+  return message_reactions[id]?.own[reaction] ?? false;
+}
+
+function ui_show_reaction_picker(id){
+  let reactions_el = ui_find_message(id)?.querySelector(".reactions");
+  if (reactions_el){
+    mkreaction_picker(id, reactions_el);
+  }
+}
+
+// Server message, styled by severity (info, warning or error)
+function ui_add_system(severity, text){
+  let msg_el = document.createElement("div");
+  msg_el.classList.add("system", "system-"+severity);
+  msg_el.innerText=text;
+  mesgs.appendChild(msg_el);
+  msg_el.scrollIntoView();
+}
+
+function ui_add_private(message, label, timestamp){
+  let msg_el = mkmessage(message, label, timestamp);
+  msg_el.classList.add("private");
+  mesgs.appendChild(msg_el);
+  msg_el.scrollIntoView();
+}
+
+// Add older messages to the top without moving the messages in view
+function ui_prepend_messages(messages){
+  let old_height = mesgs.scrollHeight;
+  let first = mesgs.firstChild;
+  for (const mesg of messages){
+    mesgs.insertBefore(mkmessage(mesg.message, mesg.username, mesg.timestamp, mesg.id, false, mesg.reply_to), first);
+  }
+  mesgs.scrollTop += mesgs.scrollHeight-old_height;
+}
@@ -1,7 +1,42 @@
 const CLOSED=3;
-const SUBID_SETUP=0;
-const SUBID_USERJOIN=1;
-const KEY_LENGTH=33;
+const PROTOCOL_VERSION=2;
+// Capabilities announced in the hello packet
+const CAP_TYPING=1<<0;
+const CAP_EDITS=1<<1;
+const CAP_REACTIONS=1<<2;
+const CAP_PRIVATE_MESSAGES=1<<3;
+const CAP_RESYNC=1<<4; // the server can send the setup packet again when we fall behind
+const CAP_RESUME=1<<5; // we can continue our session after a reconnect
+const CAPABILITIES=CAP_TYPING|CAP_EDITS|CAP_REACTIONS|CAP_PRIVATE_MESSAGES|CAP_RESYNC|CAP_RESUME;
+
+const PACKET_HELLO=0;
+const PACKET_SETUP=1;
+const PACKET_MESSAGE=2;
+const PACKET_USER_JOINED=3;
+const PACKET_USER_LEFT=4;
+const PACKET_HISTORY=5;
+const PACKET_SYSTEM=6;
+const PACKET_PRIVATE=7;
+const PACKET_TYPING=8;
+const PACKET_EDIT=9;
+const PACKET_REACTION=10;
+const PACKET_SESSION=11;
+
+const NO_REPLY=0xFFFFFFFF;
+
+const SEVERITY_NAMES=["info", "warning", "error"];
+const OPCODE_HELLO=0;
+const OPCODE_MESSAGE=1;
+const OPCODE_HISTORY_REQUEST=2;
+const OPCODE_PRIVATE_MESSAGE=3;
+const OPCODE_TYPING=4;
+const OPCODE_EDIT=5;
+const OPCODE_DELETE=6;
+const OPCODE_REACT=7;
+const OPCODE_COMMAND=8;
+
+// Same order as the server, packets refer to reactions by index
+const REACTIONS=["👍", "❤️", "😂", "😮", "😢", "🔥"];
 
 class Reader{
   #dv;
@@ -19,6 +54,12 @@ class Reader{
     return this.tdecoder.decode(dv);
   };
 
+  // String prefixed with its u16 length
+  getStr(){
+    let len = this.getUint16();
+    return this.getString(0, len);
+  }
+
   getUint8(offset=0){
     let out = this.dv.getUint8(this.index+offset, false);
     this.index+=1;
@@ -35,64 +76,187 @@ class Reader{
     return out;
   }
 
+  getReplyTo(offset=0){
+    let reply_to = this.getUint32(offset);
+    return reply_to == NO_REPLY ? undefined : reply_to;
+  }
+
   getDate(offset=0){
     return new Date((this.getUint32(offset)*1000*60))
   }
 
+  getMessage(){
+    let id = this.getUint32();
+    let sender_id = this.getUint16();
+    let timestamp = this.getDate();
+    let reply_to = this.getReplyTo();
+    let username = this.getStr();
+    let message = this.getStr();
+    return {id:id, sender_id:sender_id, username:username, timestamp:timestamp, message:message, reply_to:reply_to};
+  }
+
+  getMessages(){
+    let messages = [];
+    let count = this.getUint16();
+    for (let i = 0; i < count; i++){
+      messages.push(this.getMessage());
+    }
+    return messages;
+  }
+
   end(){
     return this.index >= this.dv.byteLength;
   }
 }
 
+// Packet with an opcode, a fixed size header and an optional string at the end
+function mkpacket(opcode, header_size, content){
+  let content_bytes = new TextEncoder().encode(content ?? "");
+  let data = new Uint8Array(1+header_size+content_bytes.length);
+  data[0] = opcode;
+  data.set(content_bytes, 1+header_size);
+  return [data, new DataView(data.buffer)];
+}
+
 class SocketMgr{
+  on_setup;
   on_message;
   on_leave;
   on_join;
   on_keychange;
+  on_history;
+  on_system;
+  on_private;
+  on_typing;
+  on_edit;
+  on_delete;
+  on_reaction;
+  on_reaction_delta;
 
   #local_id;
+  #username;
   #users;
+  #first_history_index;
+  #history_pending;
+  #capabilities;
+  #session_token;
+  #last_seen_id;
 
   constructor(){
     this.users={};
+    this.first_history_index=0;
+    this.history_pending=false;
+    this.capabilities=0;
   }
 
-  #on_special_message(sub_id, reader){
-    switch(sub_id){
-      case SUBID_SETUP:
+  #on_packet(packet_type, reader){
+    switch(packet_type){
+      case PACKET_HELLO:
+        let version = reader.getUint16();
+        this.capabilities = reader.getUint32();
+        console.log("Protocol v"+version+" capabilities "+this.capabilities);
+        break;
+      case PACKET_SETUP:
+        // replaces everything, also when it is sent again after falling behind
+        this.users={};
+        this.on_setup();
         this.local_id = reader.getUint16();
-        this.local_key = reader.getString(0, KEY_LENGTH);
+        this.local_key = reader.getStr();
         this.on_keychange(this.local_key);
 
         let client_count = reader.getUint16();
         for (let i = 0; i < client_count; i++){
           let id = reader.getUint16();
-          let name_length = reader.getUint8();
-          let username = reader.getString(0, name_length);
+          let username = reader.getStr();
           this.users[id]=username;
           console.log("(hist_user) "+username+" ("+id+")")
         }
 
-        while(!reader.end()){
-          let timestamp = reader.getDate();
-          let username_length=reader.getUint8();
-          let username = reader.getString(0, username_length);
-          let mesg_length=reader.getUint8();
-          let message = reader.getString(0, mesg_length);
-          this.on_message(this.local_id, -1, username, timestamp, message);
+        let reaction_count = reader.getUint16();
+        for (let i = 0; i < reaction_count; i++){
+          let message_id = reader.getUint32();
+          let reaction = reader.getUint8();
+          let count = reader.getUint16();
+          let own = reader.getUint8() == 1;
+          this.on_reaction(message_id, reaction, count, own);
+        }
+
+        this.first_history_index = reader.getUint32();
+        for (const mesg of reader.getMessages()){
+          this.last_seen_id = mesg.id;
+          this.on_message(false, -1, mesg.username, mesg.timestamp, mesg.message, mesg.id, mesg.reply_to);
         }
 
         console.log("Setup packet "+this.local_id+" "+this.local_key);
         this.on_join();
         break;
-      case SUBID_USERJOIN:
-        let id = reader.getUint16(0);
-        let username = reader.getString(0)
+      case PACKET_MESSAGE:
+        let mesg = reader.getMessage();
+        this.last_seen_id = mesg.id;
+        let me = this.local_id == mesg.sender_id;
+        this.on_message(me, mesg.sender_id, mesg.username, mesg.timestamp, mesg.message, mesg.id, mesg.reply_to);
+        break;
+      case PACKET_USER_JOINED:
+        let id = reader.getUint16();
+        let username = reader.getStr();
         console.log("user join: "+username+" ("+id+")");
         this.users[id] = username;
+        if (id == this.local_id){ // renamed
+          this.username = username;
+        }
+        break;
+      case PACKET_USER_LEFT:
+        let left_id = reader.getUint16();
+        console.log("user leave: "+this.users[left_id]+" ("+left_id+")");
+        this.on_typing(left_id, this.users[left_id], false);
+        delete this.users[left_id];
+        break;
+      case PACKET_HISTORY:
+        this.first_history_index = reader.getUint32();
+        this.history_pending = false;
+        this.on_history(reader.getMessages());
+        break;
+      case PACKET_SYSTEM:
+        let severity = SEVERITY_NAMES[reader.getUint8()] ?? "info";
+        this.on_system(severity, reader.getStr());
+        break;
+      case PACKET_PRIVATE:
+        let recipient_id = reader.getUint16();
+        let private_mesg = reader.getMessage();
+        let recipient = this.users[recipient_id] ?? "?";
+        this.on_private(private_mesg.sender_id == this.local_id, private_mesg.username, recipient, private_mesg.timestamp, private_mesg.message);
+        break;
+      case PACKET_TYPING:
+        let typing_id = reader.getUint16();
+        let typing = reader.getUint8() == 1;
+        this.on_typing(typing_id, this.users[typing_id], typing);
+        break;
+      case PACKET_EDIT:
+        let edit_id = reader.getUint32();
+        let content = reader.getStr();
+        if (content.length == 0){
+          this.on_delete(edit_id);
+        }else{
+          this.on_edit(edit_id, content);
+        }
+        break;
+      case PACKET_REACTION:
+        let reaction_mesg_id = reader.getUint32();
+        let reaction = reader.getUint8();
+        let reactor_id = reader.getUint16();
+        let added = reader.getUint8() == 1;
+        this.on_reaction_delta(reaction_mesg_id, reaction, added, reactor_id == this.local_id);
+        break;
+      case PACKET_SESSION:
+        this.session_token = reader.getStr();
+        // a resumed session keeps everything, the server only sends what we missed
+        if (reader.getUint8() == 1){
+          console.log("Session resumed");
+          this.on_join();
+        }
         break;
       default:
-        console.error("PROTOCOL_ERROR: Invalid subid ("+sub_id+") packet recieved");
+        console.error("PROTOCOL_ERROR: Invalid packet type ("+packet_type+") recieved");
         break;
     }
 
@@ -102,16 +266,40 @@ class SocketMgr{
     if (this.ws !== undefined){
       await this.ws.close();
     }
+    this.username = username;
     let encoded_username = encodeURIComponent(username);
     let query=`username=${encoded_username}`;
     if (key !== undefined && key !== null && key !== ""){
       query+="&key="+key;
     }
+    if (ROOM !== ""){
+      query+="&room="+ROOM;
+    }
+    if (this.session_token !== undefined){
+      query+="&resume="+this.session_token;
+      if (this.last_seen_id !== undefined){
+        query+="&last_seen="+this.last_seen_id;
+      }
+    }
     this.ws = new WebSocket(WEBSOCKET_URL+"?"+query);
     this.ws.binaryType = "arraybuffer";
 
+    this.ws.onopen = () => {
+      let [data, dv] = mkpacket(OPCODE_HELLO, 6);
+      dv.setUint16(1, PROTOCOL_VERSION, false);
+      dv.setUint32(3, CAPABILITIES, false);
+      this.ws.send(data.buffer);
+    }
+
     this.ws.onclose = async (e) => {
-      this.users={};
+      this.history_pending=false;
+      // only a dropped connection can be resumed, keep what we know until then
+      if (e.code != 1006){
+        this.session_token=undefined;
+        this.last_seen_id=undefined;
+        this.users={};
+        this.first_history_index=0;
+      }
       let reason = e.reason;
       if (!e.reason || e.reason.startsWith("INT:")){
         if (e.reason) {
@@ -129,32 +317,89 @@ class SocketMgr{
       let data = e.data;
       if (data instanceof ArrayBuffer){
         let reader = new Reader(new DataView(data))
-        const sender_id = reader.getUint16();
-        if (sender_id == 0){ // user 0 is special message
-          let sub_id = reader.getUint8();
-          this.#on_special_message(sub_id, reader);
-        }else{
-          const timestamp = reader.getDate();
-          let message = reader.getString(0);
-          let sender_username = this.users[sender_id];
-          let me = this.local_id == sender_id;
-          if (me){
-            sender_username = username;
-          }
-          this.on_message(me, sender_id, sender_username, timestamp, message);
-        }
+        this.#on_packet(reader.getUint8(), reader);
       }
     };
   }
 
-  async send(message){
+  async #send(data){
     if (this.ws.bufferedAmount > 2){
       return false;
     }
-    await this.ws.send(message);
+    await this.ws.send(data);
     return true;
   }
 
+  // Slash commands are sent as command packets
+  send(message){
+    let command = message.trim().match(/^\/(\S+)(?:\s([\s\S]*))?$/);
+    if (command){
+      return this.send_command(command[1], command[2] ?? "");
+    }
+    return this.send_reply(NO_REPLY, message);
+  }
+
+  send_command(name, args){
+    let name_bytes = new TextEncoder().encode(name);
+    let [data, dv] = mkpacket(OPCODE_COMMAND, 2+name_bytes.length, args);
+    dv.setUint16(1, name_bytes.length, false);
+    data.set(name_bytes, 3);
+    return this.#send(data.buffer);
+  }
+
+  send_reply(reply_to, message){
+    let [data, dv] = mkpacket(OPCODE_MESSAGE, 4, message);
+    dv.setUint32(1, reply_to, false);
+    return this.#send(data.buffer);
+  }
+
+  send_private(recipient_id, message){
+    let [data, dv] = mkpacket(OPCODE_PRIVATE_MESSAGE, 2, message);
+    dv.setUint16(1, recipient_id, false);
+    return this.#send(data.buffer);
+  }
+
+  send_react(id, reaction, add){
+    let [data, dv] = mkpacket(OPCODE_REACT, 6);
+    dv.setUint32(1, id, false);
+    dv.setUint8(5, reaction);
+    dv.setUint8(6, add ? 1 : 0);
+    return this.#send(data.buffer);
+  }
+
+  send_edit(id, message){
+    let [data, dv] = mkpacket(OPCODE_EDIT, 4, message);
+    dv.setUint32(1, id, false);
+    return this.#send(data.buffer);
+  }
+
+  send_delete(id){
+    let [data, dv] = mkpacket(OPCODE_DELETE, 4);
+    dv.setUint32(1, id, false);
+    return this.#send(data.buffer);
+  }
+
+  send_typing(typing){
+    if (this.ws.readyState != WebSocket.OPEN){
+      return;
+    }
+    let [data, dv] = mkpacket(OPCODE_TYPING, 1);
+    dv.setUint8(1, typing ? 1 : 0);
+    this.ws.send(data.buffer);
+  }
+
+  // Request older messages than the ones we already have
+  fetch_history(count){
+    if (this.history_pending || this.first_history_index == 0 || this.ws.readyState != WebSocket.OPEN){
+      return;
+    }
+    this.history_pending = true;
+    let [data, dv] = mkpacket(OPCODE_HISTORY_REQUEST, 5);
+    dv.setUint32(1, this.first_history_index, false);
+    dv.setUint8(5, count);
+    this.ws.send(data.buffer);
+  }
+
   async leave(){
     await this.ws.close(1000, "Dag dag ik ga je missen. xxx");
   }
@@ -0,0 +1,53 @@
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    request::{self, FromRequest},
+    serde::Deserialize,
+    Request,
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminConfig {
+    ///Bearer token for the admin endpoints. The admin endpoints are disabled when unset.
+    pub admin_token: Option<String>,
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+///Request guard for requests with a valid `Authorization: Bearer <admin_token>` header
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(admin_token) = req
+            .rocket()
+            .state::<AdminConfig>()
+            .and_then(|config| config.admin_token.as_ref())
+        else {
+            return request::Outcome::Error((Status::NotFound, ()));
+        };
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => {
+                request::Outcome::Success(Admin)
+            }
+            _ => request::Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+pub fn stage() -> AdHoc {
+    AdHoc::config::<AdminConfig>()
+}
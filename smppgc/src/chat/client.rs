@@ -1,8 +1,9 @@
 use std::{
     borrow::Cow,
     hash::Hash,
+    net::IpAddr,
     sync::{atomic::AtomicU16, Arc},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use futures_util::{SinkExt, StreamExt};
@@ -14,84 +15,110 @@ use rocket_ws::{
 use log::*;
 use rocket_ws::result::Result;
 use thiserror::Error;
+use tokio::sync::mpsc;
 use tokio_tungstenite::tungstenite;
 
-use super::{joined_total, packet, Chat};
+use super::{
+    joined_total,
+    packet::{self, v2, ClientPacket, DecodeError, Protocol, ServerPacket, User, LATEST_VERSION},
+    presence::{Eviction, Presence, PresenceGuard},
+    session::DetachedSession,
+    Chat, ChatEvents, MessageEdit, NewClientError, PrivateMessage, ReactionDelta, Severity, Typing,
+};
 use crate::names::{ClaimedName, UserId};
 
-#[derive(Clone, Debug)]
-pub struct Message {
-    pub sender: Arc<str>,
-    pub content: Arc<str>,
-    pub timestamp: u32,
-    pub sender_id: u16,
-}
-impl Message {
-    pub fn is_valid(&self) -> bool {
-        if self.content.as_bytes().len() > 100 {
-            return false;
-        }
-        if self.is_empty() {
-            return false;
-        }
-        true
-    }
-    pub fn is_empty(&self) -> bool {
-        for char in self.content.chars() {
-            if !char.is_whitespace() {
-                return false;
-            }
-        }
-        true
-    }
-}
+pub use smppgc_proto::Message;
 
 pub struct ClientFactory {
     id_counter: AtomicU16,
 }
+impl Default for ClientFactory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl ClientFactory {
     pub fn new() -> Self {
         Self {
             id_counter: 1.into(),
         }
     }
-    pub fn reserve_id(&self) -> u16 {
-        let value = self
-            .id_counter
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        if value == 0 {
-            self.id_counter
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
-        } else {
-            value
-        }
+    ///Returns the next id that isn't used by a client in the room, detached sessions included.
+    ///None when every id is in use.
+    pub fn reserve_id(&self, presence: &Presence) -> Option<u16> {
+        (0..u16::MAX)
+            .map(|_| {
+                self.id_counter
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            })
+            .find(|&id| id != 0 && !presence.contains(id))
     }
+    #[allow(clippy::too_many_arguments)]
     pub async fn new_client(
         &self,
-        mut ws: DuplexStream,
+        ws: DuplexStream,
+        protocol: Protocol,
         key: UserId,
+        token: &str,
+        ip: Option<IpAddr>,
         username: ClaimedName,
         chat_state: &Chat,
-    ) -> Result<Client> {
-        let id = self.reserve_id();
-        joined_total::inc();
-        ws.send(packet::new_setup(
-            key,
-            id,
-            chat_state.clients().await,
-            chat_state.history().await,
-        ))
-        .await?;
+    ) -> std::result::Result<(Client, mpsc::Receiver<PrivateMessage>, ChatEvents), NewClientError>
+    {
+        let Some(id) = self.reserve_id(&chat_state.presence) else {
+            return Err(Chat::reject_full(ws).await);
+        };
         let info = ClientInfo {
             username: username.into(),
             id,
+            user_id: key.clone(),
+            ip,
         };
-        let left_sender = chat_state.left_sender();
-        Ok(Client {
+        // a concurrent join can take the id after it was reserved
+        let Some((setup, presence, mailbox, events)) = chat_state.join(info.clone(), token).await
+        else {
+            return Err(Chat::reject_full(ws).await);
+        };
+        joined_total::inc();
+        let resume_token = chat_state.resume_token(&protocol);
+        let mut client = Client {
             ws,
+            protocol,
             info,
-            left_sender,
-        })
+            user_id: key,
+            token: token.into(),
+            presence,
+            last_seen_id: None,
+            resume_token,
+            closed: false,
+        };
+        client.send_setup(setup).await?;
+        client.send_session(false).await?;
+        Ok((client, mailbox, events))
+    }
+
+    ///Continues a detached session on a new connection with the same id. Nothing is announced to
+    ///the room, the client is still in the user list.
+    pub fn resume_client(
+        ws: DuplexStream,
+        protocol: Protocol,
+        session: DetachedSession,
+        chat_state: &Chat,
+    ) -> (Client, mpsc::Receiver<PrivateMessage>, ChatEvents) {
+        session.presence.set_detached(false);
+        session.presence.heartbeat();
+        let client = Client {
+            ws,
+            protocol,
+            resume_token: chat_state.resume_token(&protocol),
+            info: session.info,
+            user_id: session.user_id,
+            token: session.token,
+            presence: session.presence,
+            last_seen_id: session.last_seen_id,
+            closed: false,
+        };
+        (client, session.mailbox, session.events)
     }
 }
 
@@ -112,57 +139,190 @@ impl From<tungstenite::Error> for PacketError {
         }
     }
 }
+impl From<DecodeError> for PacketError {
+    fn from(_: DecodeError) -> Self {
+        PacketError::Invalid
+    }
+}
+///How long a v2 client has to send its hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+///Waits for the hello of a v2 client and answers with the negotiated protocol. Closes the
+///connection and returns None when the handshake fails.
+pub async fn handshake(ws: &mut DuplexStream) -> Result<Option<Protocol>> {
+    let hello = match tokio::time::timeout(HANDSHAKE_TIMEOUT, ws.next()).await {
+        Ok(Some(message)) => match message? {
+            tungstenite::Message::Binary(data) => v2::decode_hello(&data).ok(),
+            _ => None,
+        },
+        Ok(None) => return Ok(None),
+        Err(_) => None,
+    };
+    let protocol =
+        hello.and_then(|(version, capabilities)| Protocol::negotiate(version, capabilities));
+    let Some(protocol) = protocol else {
+        error!("Closing connection because: Handshake failed");
+        ws.close(Some(CloseFrame {
+            code: CloseCode::Protocol,
+            reason: Cow::Borrowed("INT: Handshake failed."),
+        }))
+        .await?;
+        return Ok(None);
+    };
+    let hello = ServerPacket::Hello {
+        version: LATEST_VERSION,
+        capabilities: protocol.capabilities,
+    };
+    if let Some(hello) = packet::encode(&protocol, &hello) {
+        ws.send(hello).await?;
+    }
+    Ok(Some(protocol))
+}
+
 pub struct Client {
     ws: DuplexStream,
+    protocol: Protocol,
     info: ClientInfo,
-    left_sender: rocket::tokio::sync::broadcast::Sender<ClientInfo>,
+    user_id: UserId,
+    ///Key of the setup packet
+    token: Arc<str>,
+    presence: PresenceGuard,
+    last_seen_id: Option<u32>,
+    ///None when the session can't be resumed
+    resume_token: Option<Arc<str>>,
+    ///Set when either side closed the connection
+    closed: bool,
 }
 impl Client {
-    pub async fn forward_client(&mut self, client: &ClientInfo) -> Result<()> {
-        self.ws.send(packet::new_client_joined(client)).await?;
+    ///Sends the packet when the protocol of the client supports it
+    async fn send_packet(&mut self, packet: ServerPacket) -> Result<()> {
+        if let Some(message) = packet::encode(&self.protocol, &packet) {
+            self.ws.send(message).await?;
+        }
         Ok(())
     }
+    ///Tells the client the token to resume this session with
+    pub async fn send_session(&mut self, resumed: bool) -> Result<()> {
+        let Some(token) = self.resume_token.clone() else {
+            return Ok(());
+        };
+        self.send_packet(ServerPacket::Session { token, resumed })
+            .await
+    }
+    ///Setup packet that replaces everything the client knows about the room. The client can't
+    ///continue without it, the connection is closed when it can't be encoded.
+    pub async fn send_setup(&mut self, setup: ServerPacket) -> Result<()> {
+        match packet::try_encode(&self.protocol, &setup) {
+            Ok(Some(message)) => self.ws.send(message).await,
+            Ok(None) => Ok(()),
+            Err(err) => {
+                error!(
+                    "Closing connection of client {}, setup packet can't be encoded: {}",
+                    self.info.id(),
+                    err
+                );
+                self.close(CloseCode::Error, "De chat kan niet geladen worden.")
+                    .await?;
+                Err(tungstenite::Error::ConnectionClosed)
+            }
+        }
+    }
+    pub async fn forward_client(&mut self, client: &ClientInfo) -> Result<()> {
+        self.send_packet(ServerPacket::UserJoined(client.into()))
+            .await
+    }
+    pub async fn forward_client_left(&mut self, client: &ClientInfo) -> Result<()> {
+        self.send_packet(ServerPacket::UserLeft(client.id())).await
+    }
     pub async fn forward_all_clients(
         &mut self,
         clients: impl Iterator<Item = &ClientInfo>,
     ) -> Result<()> {
         for client in clients {
-            self.ws.feed(packet::new_client_joined(client)).await?;
+            if let Some(message) =
+                packet::encode(&self.protocol, &ServerPacket::UserJoined(client.into()))
+            {
+                self.ws.feed(message).await?;
+            }
         }
         self.ws.flush().await?;
         Ok(())
     }
     pub async fn forward(&mut self, mesg: &Message) -> Result<()> {
-        self.ws.send(packet::new_message(mesg)).await?;
-        Ok(())
+        self.send_packet(ServerPacket::Message(mesg.clone())).await
     }
     pub async fn forward_all(&mut self, messages: impl Iterator<Item = &Message>) -> Result<()> {
         for message in messages {
-            self.ws.feed(packet::new_message(message)).await?;
+            if let Some(message) =
+                packet::encode(&self.protocol, &ServerPacket::Message(message.clone()))
+            {
+                self.ws.feed(message).await?;
+            }
         }
         self.ws.flush().await?;
         Ok(())
     }
-    pub async fn try_recv(&mut self) -> Result<Option<Message>> {
-        let Some(message) = self.ws.next().await else {
-            return Err(rocket_ws::result::Error::ConnectionClosed);
-        };
-        let message = message?;
-        if message.is_close() {
-            return Ok(None);
-        }
-        if !message.is_text() {
-            error!("Closing connection because: Received non text message");
-            self.ws
-                .close(Some(CloseFrame {
-                    code: CloseCode::Unsupported,
-                    reason: Cow::Borrowed("INT: No non text messages."),
-                }))
-                .await?;
-            return Ok(None);
+    pub async fn forward_history(&mut self, first_index: u32, history: Vec<Message>) -> Result<()> {
+        self.send_packet(ServerPacket::History {
+            first_index,
+            messages: history,
+        })
+        .await
+    }
+    ///Waits for the next packet of the client, control frames are skipped. Closes the connection
+    ///when the packet can't be decoded.
+    pub async fn try_recv(&mut self) -> std::result::Result<ClientPacket, PacketError> {
+        loop {
+            let Some(message) = self.ws.next().await else {
+                return Err(PacketError::Disconected);
+            };
+            let message = message?;
+            self.presence.heartbeat();
+            if message.is_close() {
+                self.closed = true;
+            }
+            // the next read flushes the reply to a close and ends the stream
+            if message.is_close() || message.is_ping() || message.is_pong() {
+                continue;
+            }
+            return match packet::decode(&self.protocol, message) {
+                Ok(packet) => Ok(packet),
+                Err(err) => {
+                    error!(
+                        "Closing connection because: Received invalid packet: {}",
+                        err
+                    );
+                    self.close(CloseCode::Unsupported, "INT: Invalid packet.")
+                        .await?;
+                    Err(err.into())
+                }
+            };
         }
-        let content = String::from_utf8_lossy(&message.into_data()).to_string();
+    }
 
+    ///Browsers answer pings by themselves, the pong counts as a heartbeat
+    pub async fn ping(&mut self) -> Result<()> {
+        self.ws.send(tungstenite::Message::Ping(Vec::new())).await
+    }
+    pub fn eviction(&self) -> Eviction {
+        self.presence.eviction()
+    }
+    ///When the client last sent a frame, pongs included
+    pub fn last_heartbeat(&self) -> Instant {
+        self.presence.last_heartbeat()
+    }
+
+    ///Remembers the newest message the client has received
+    pub fn ack(&mut self, id: u32) {
+        self.last_seen_id = Some(self.last_seen_id.map_or(id, |last| last.max(id)));
+    }
+    ///Id of the newest message the client acknowledged
+    pub fn last_seen_id(&self) -> Option<u32> {
+        self.last_seen_id
+    }
+
+    ///Creates a message sent by this client
+    pub fn new_message(&self, content: Arc<str>) -> Message {
         let timestamp = (SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap_or_else(|_| {
@@ -172,46 +332,126 @@ impl Client {
             .as_secs()
             / 60) as u32;
 
-        Ok(Some(Message {
+        Message {
+            id: 0,
             timestamp,
             sender_id: self.info.id(),
             sender: self.info.username.clone(),
-            content: content.into(),
-        }))
+            content,
+            reply_to: None,
+        }
+    }
+
+    pub async fn send_system(&mut self, severity: Severity, text: &str) -> Result<()> {
+        self.send_packet(ServerPacket::System {
+            severity,
+            text: text.into(),
+        })
+        .await
+    }
+    pub async fn forward_edit(&mut self, edit: &MessageEdit) -> Result<()> {
+        self.send_packet(ServerPacket::Edit(edit.clone())).await
+    }
+    pub async fn forward_reaction(&mut self, reaction: ReactionDelta) -> Result<()> {
+        self.send_packet(ServerPacket::Reaction(reaction)).await
+    }
+    pub async fn forward_typing(&mut self, typing: Typing) -> Result<()> {
+        self.send_packet(ServerPacket::Typing(typing)).await
+    }
+    pub async fn forward_private(&mut self, mesg: &PrivateMessage) -> Result<()> {
+        self.send_packet(ServerPacket::Private(mesg.clone())).await
     }
 
     pub fn client_info(&self) -> ClientInfo {
         self.info.clone()
     }
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+    pub fn set_username(&mut self, username: ClaimedName) {
+        self.info.username = username.into();
+    }
 
-    pub async fn ratelimit_kick(&mut self) -> Result<()> {
+    ///Closes the connection on purpose, the session can't be resumed after this
+    async fn close(&mut self, code: CloseCode, reason: &'static str) -> Result<()> {
+        self.closed = true;
         self.ws
             .close(Some(CloseFrame {
-                code: rocket_ws::frame::CloseCode::Error,
-                reason: Cow::Borrowed("Te veel berichten. Typ de volgende keer wat langzamer."),
+                code,
+                reason: Cow::Borrowed(reason),
             }))
-            .await?;
-        Ok(())
+            .await
     }
-}
-impl Drop for Client {
-    fn drop(&mut self) {
-        match self.left_sender.send(self.client_info()) {
-            Ok(_) => {}
-            Err(err) => {
-                error!(
-                    "Failed to send leave event (This will cause ghosts to appear): {}",
-                    err
-                )
-            }
-        };
+
+    pub async fn ratelimit_kick(&mut self) -> Result<()> {
+        self.close(
+            CloseCode::Error,
+            "Te veel berichten. Typ de volgende keer wat langzamer.",
+        )
+        .await
+    }
+
+    ///Closes the connection of a client whose queue overflowed
+    pub async fn slow_kick(&mut self) -> Result<()> {
+        self.close(
+            CloseCode::Again,
+            "Je verbinding is te traag. Verbind opnieuw.",
+        )
+        .await
+    }
+
+    ///Closes the connection of a client that stopped answering pings, if it is still there
+    pub async fn timeout_kick(&mut self) -> Result<()> {
+        self.close(CloseCode::Away, "Je verbinding reageerde niet meer.")
+            .await
+    }
+
+    pub async fn idle_kick(&mut self) -> Result<()> {
+        self.close(CloseCode::Away, "Je was te lang inactief.")
+            .await
     }
-}
 
-#[derive(Clone, Debug, Hash)]
+    pub async fn kick(&mut self, reason: &'static str) -> Result<()> {
+        self.close(CloseCode::Policy, reason).await
+    }
+
+    ///Keeps the session when the connection dropped without a close from either side, so the
+    ///client can resume it. Returns the resume token and the session.
+    pub fn into_session(
+        self,
+        events: ChatEvents,
+        mailbox: mpsc::Receiver<PrivateMessage>,
+    ) -> Option<(Arc<str>, DetachedSession)> {
+        if self.closed {
+            return None;
+        }
+        let token = self.resume_token?;
+        Some((
+            token,
+            DetachedSession {
+                info: self.info,
+                user_id: self.user_id,
+                token: self.token,
+                presence: self.presence,
+                events,
+                mailbox,
+                last_seen_id: self.last_seen_id,
+            },
+        ))
+    }
+}
+#[derive(Clone, Debug)]
 pub struct ClientInfo {
     username: Arc<str>,
     id: u16,
+    user_id: UserId,
+    ip: Option<IpAddr>,
 }
 impl Eq for ClientInfo {}
 impl ClientInfo {
@@ -221,12 +461,39 @@ impl ClientInfo {
     pub fn username(&self) -> &str {
         &self.username
     }
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+#[cfg(test)]
+impl ClientInfo {
+    pub fn for_test(id: u16, username: &str) -> Self {
+        Self {
+            username: username.into(),
+            id,
+            user_id: UserId::new(),
+            ip: None,
+        }
+    }
 }
 impl PartialEq for ClientInfo {
     fn eq(&self, other: &Self) -> bool {
         other.id == self.id
     }
-    fn ne(&self, other: &Self) -> bool {
-        other.id != self.id
+}
+impl Hash for ClientInfo {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+impl From<&ClientInfo> for User {
+    fn from(client: &ClientInfo) -> Self {
+        Self {
+            id: client.id(),
+            username: client.username().into(),
+        }
     }
 }
@@ -0,0 +1,944 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use log::*;
+use tokio::sync::mpsc;
+
+use super::client::Message;
+use crate::{names::UserId, utils::dropvec::DropVec, ChatConfig};
+
+///Persistent storage for the message history of a room
+pub trait HistoryStore: Send + Sync {
+    fn append(&self, entry: &Entry) -> io::Result<()>;
+    ///Stores an edit of message `id`. Deleted messages have an empty content.
+    fn append_edit(&self, id: u32, content: &str) -> io::Result<()>;
+    ///Loads all stored messages (oldest first) that are not older than `retention`
+    fn load(&self, retention: Option<Duration>) -> io::Result<Vec<Entry>>;
+    ///Reads the messages in `range` and returns the index of the first one. Indices start at the
+    ///oldest message returned by `load`, messages dropped by `compact` are left out.
+    fn read(&self, range: Range<usize>) -> io::Result<(usize, Vec<Message>)>;
+    ///Drops the oldest messages that are older than `retention` and folds the edits into the
+    ///messages. The indices of the remaining messages don't change.
+    fn compact(&self, retention: Option<Duration>) -> io::Result<()>;
+}
+
+///How often the history store is compacted while the server runs
+const COMPACT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+///The recent messages of a room kept in memory, backed by an optional [HistoryStore] for older ones.
+///
+///Every message has an index that counts up from the oldest stored message. Clients use it as
+///a cursor to fetch older messages. Deleted messages keep their index, they are stored with an
+///empty content and left out when the history is returned.
+pub struct History {
+    recent: DropVec<Entry>,
+    len: u32,
+    next_id: u32,
+    store: Option<Arc<dyn HistoryStore>>,
+    ///Writes to `store`, done in order by the writer task so they don't block the history lock
+    writes: Option<mpsc::UnboundedSender<StoreWrite>>,
+}
+///A message together with the user that sent it
+#[derive(Clone)]
+pub struct Entry {
+    pub mesg: Message,
+    ///None for messages stored by an older version that didn't store authors
+    pub author: Option<UserId>,
+}
+impl History {
+    pub fn new(config: &ChatConfig, store: Option<Arc<dyn HistoryStore>>) -> Self {
+        let mut recent = DropVec::new(config.max_stored_messages);
+        let mut len = 0;
+        let mut next_id = 0;
+        let retention =
+            (config.history_retention != 0).then(|| Duration::from_secs(config.history_retention));
+        if let Some(store) = &store {
+            match store.load(retention) {
+                Ok(entries) => {
+                    len = entries.len() as u32;
+                    next_id = entries.last().map_or(0, |entry| entry.mesg.id + 1);
+                    recent.extend(entries);
+                }
+                Err(err) => error!("Failed to load history: {}", err),
+            }
+        }
+        Self {
+            recent,
+            len,
+            next_id,
+            writes: store
+                .clone()
+                .map(|store| Self::spawn_writer(store, retention)),
+            store,
+        }
+    }
+
+    ///Spawns the task that writes to the store and compacts it every [COMPACT_INTERVAL]. It stops
+    ///after the history is dropped and every queued write is done.
+    fn spawn_writer(
+        store: Arc<dyn HistoryStore>,
+        retention: Option<Duration>,
+    ) -> mpsc::UnboundedSender<StoreWrite> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<StoreWrite>();
+        tokio::task::spawn(async move {
+            let mut writes = Vec::new();
+            // load just compacted the store
+            let start = tokio::time::Instant::now() + COMPACT_INTERVAL;
+            let mut compact = tokio::time::interval_at(start, COMPACT_INTERVAL);
+            loop {
+                let store = store.clone();
+                let result = tokio::select! {
+                    count = receiver.recv_many(&mut writes, 64) => {
+                        if count == 0 {
+                            break;
+                        }
+                        let writes = std::mem::take(&mut writes);
+                        tokio::task::spawn_blocking(move || {
+                            for write in writes {
+                                write.apply(&*store);
+                            }
+                        })
+                        .await
+                    }
+                    _ = compact.tick() => {
+                        tokio::task::spawn_blocking(move || {
+                            if let Err(err) = store.compact(retention) {
+                                error!("Failed to compact history: {}", err);
+                            }
+                        })
+                        .await
+                    }
+                };
+                if let Err(err) = result {
+                    error!("History writer failed: {}", err);
+                }
+            }
+        });
+        sender
+    }
+
+    fn write(&self, write: StoreWrite) {
+        if let Some(writes) = &self.writes {
+            if writes.send(write).is_err() {
+                error!("History writer stopped, message not stored");
+            }
+        }
+    }
+
+    fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.recent.iter().map(|entry| &entry.mesg)
+    }
+
+    ///Assigns the next message id to the message and stores it
+    pub fn push(&mut self, mut mesg: Message, author: UserId) -> Message {
+        mesg.id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let entry = Entry {
+            mesg: mesg.clone(),
+            author: Some(author),
+        };
+        self.write(StoreWrite::Message(entry.clone()));
+        self.recent.push(entry);
+        self.len += 1;
+        mesg
+    }
+
+    ///Returns the message if it is still in the recent history and not deleted
+    pub fn get(&self, id: u32) -> Option<&Message> {
+        self.messages()
+            .find(|mesg| mesg.id == id && !mesg.is_deleted())
+    }
+
+    ///User that sent a recent message. None when it was stored without its author.
+    pub fn author(&self, id: u32) -> Option<&UserId> {
+        self.recent
+            .iter()
+            .find(|entry| entry.mesg.id == id)?
+            .author
+            .as_ref()
+    }
+
+    ///Replaces the content of a recent message. An empty content deletes the message.
+    pub fn edit(&mut self, id: u32, content: Arc<str>) -> bool {
+        let Some(entry) = self.recent.iter_mut().find(|entry| entry.mesg.id == id) else {
+            return false;
+        };
+        entry.mesg.content = content.clone();
+        self.write(StoreWrite::Edit(id, content));
+        true
+    }
+
+    ///Id of the oldest message kept in memory
+    pub fn oldest_recent_id(&self) -> Option<u32> {
+        self.messages().next().map(|mesg| mesg.id)
+    }
+
+    ///Index of the oldest message returned by [History::recent]
+    pub fn first_recent_index(&self) -> u32 {
+        self.len - self.recent.len() as u32
+    }
+
+    ///Returns the messages sent after message `id`, None when message `id` isn't in the recent
+    ///history anymore so messages after it could be missing
+    pub fn after(&self, id: u32) -> Option<Vec<Message>> {
+        if id.wrapping_add(1) == self.next_id {
+            return Some(Vec::new());
+        }
+        let mut messages = self.messages().skip_while(|mesg| mesg.id != id);
+        messages.next()?;
+        Some(
+            messages
+                .filter(|mesg| !mesg.is_deleted())
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn recent(&self) -> Vec<Message> {
+        self.messages()
+            .filter(|mesg| !mesg.is_deleted())
+            .cloned()
+            .collect()
+    }
+
+    ///Returns up to `count` messages before `index`. Messages older than the recent history have to
+    ///be read from the store with [Scrollback::read] after the history lock is released.
+    pub fn before(&self, index: u32, count: u32) -> Scrollback {
+        let end = index.min(self.len);
+        let start = end.saturating_sub(count);
+        let first_recent = self.first_recent_index();
+
+        let recent_start = start.max(first_recent);
+        let messages = self
+            .messages()
+            .skip((recent_start - first_recent) as usize)
+            .take(end.saturating_sub(recent_start) as usize)
+            .filter(|mesg| !mesg.is_deleted())
+            .cloned()
+            .collect();
+        let recent = (recent_start, messages);
+        match &self.store {
+            Some(store) if start < first_recent => Scrollback::Stored {
+                store: store.clone(),
+                range: start as usize..end as usize,
+                recent,
+            },
+            _ => Scrollback::Recent(recent),
+        }
+    }
+}
+
+enum StoreWrite {
+    Message(Entry),
+    Edit(u32, Arc<str>),
+}
+impl StoreWrite {
+    fn apply(self, store: &dyn HistoryStore) {
+        match self {
+            Self::Message(entry) => {
+                if let Err(err) = store.append(&entry) {
+                    error!("Failed to store message: {}", err);
+                }
+            }
+            Self::Edit(id, content) => {
+                if let Err(err) = store.append_edit(id, &content) {
+                    error!("Failed to store message edit: {}", err);
+                }
+            }
+        }
+    }
+}
+
+///Messages before a history index, see [History::before]
+pub enum Scrollback {
+    Recent((u32, Vec<Message>)),
+    ///Reaches past the recent history
+    Stored {
+        store: Arc<dyn HistoryStore>,
+        range: Range<usize>,
+        ///Returned when reading the store fails
+        recent: (u32, Vec<Message>),
+    },
+}
+impl Scrollback {
+    ///Returns the messages together with the index of the first returned message. Reads the
+    ///store on a blocking thread.
+    pub async fn read(self) -> (u32, Vec<Message>) {
+        let (store, range, recent) = match self {
+            Self::Recent(recent) => return recent,
+            Self::Stored {
+                store,
+                range,
+                recent,
+            } => (store, range, recent),
+        };
+        match tokio::task::spawn_blocking(move || store.read(range)).await {
+            Ok(Ok((start, mut messages))) => {
+                messages.retain(|mesg| !mesg.is_deleted());
+                (start as u32, messages)
+            }
+            Ok(Err(err)) => {
+                error!("Failed to read history: {}", err);
+                recent
+            }
+            Err(err) => {
+                error!("Failed to read history: {}", err);
+                recent
+            }
+        }
+    }
+}
+
+///Append only history file.
+///
+///Every record is prefixed with its length and a crc32 of its payload so a partially written
+///record at the end of the file (crash, full disk) is detected and cut off on the next load.
+///Edits are appended as separate records and folded into the messages when the file is compacted.
+///
+///When a message can't be appended the store stops appending and reading, otherwise the indices
+///of the messages after it would be off by one.
+pub struct FileHistoryStore {
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+#[derive(Default)]
+struct FileState {
+    file: Option<File>,
+    ///Index of the first message in the file, messages before it were dropped by compaction
+    first_index: usize,
+    ///Offset of every message record in the file
+    offsets: Vec<u64>,
+    ///Id of every message record in the file
+    ids: Vec<u32>,
+    len: u64,
+    ///Edits appended after the last compaction, by message id
+    edits: HashMap<u32, Arc<str>>,
+    ///Set when loading or appending a message failed
+    failed: bool,
+}
+
+enum Record {
+    Message(Entry),
+    Edit { id: u32, content: Arc<str> },
+}
+
+///Set in the length of records that start with a record kind and message id. Records without it
+///are messages from before message ids existed.
+const RECORD_V2: u32 = 1 << 31;
+const RECORD_MESSAGE: u8 = 0;
+const RECORD_EDIT: u8 = 1;
+const RECORD_AUTHORED_MESSAGE: u8 = 2;
+
+impl FileHistoryStore {
+    pub fn new(path: PathBuf) -> Arc<Self> {
+        Arc::new(Self {
+            path,
+            state: Mutex::new(FileState::default()),
+        })
+    }
+
+    fn encode_record(payload: Vec<u8>) -> Vec<u8> {
+        //|  u32 | payload len | RECORD_V2
+        //|  u32 | crc32 of payload
+        //| [u8] | payload
+
+        let mut data = Vec::with_capacity(payload.len() + 8);
+        data.extend_from_slice(&(payload.len() as u32 | RECORD_V2).to_be_bytes());
+        data.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        data.extend_from_slice(&payload);
+        data
+    }
+
+    fn encode(entry: &Entry) -> Vec<u8> {
+        //  message payload:
+        //|  u8  | const RECORD_AUTHORED_MESSAGE, RECORD_MESSAGE when the author isn't known
+        //|  u32 | message id
+        //| [u8] | author, 17 bytes of UserId::to_bytes_le (left out for RECORD_MESSAGE)
+        //|  u32 | time (minutes since UNIX_EPOCH)
+        //|  u16 | sender id
+        //|  u16 | sender username len
+        //| [u8] | sender username
+        //|  u16 | content len
+        //| [u8] | content
+        //|  u32 | id of the replied message (left out when not a reply)
+
+        let mesg = &entry.mesg;
+        let sender_bytes = mesg.sender.as_bytes();
+        let content_bytes = mesg.content.as_bytes();
+        let mut payload = Vec::with_capacity(
+            1 + 4 + 17 + 4 + 2 + 4 + 4 + sender_bytes.len() + content_bytes.len(),
+        );
+        match &entry.author {
+            Some(author) => {
+                payload.push(RECORD_AUTHORED_MESSAGE);
+                payload.extend_from_slice(&mesg.id.to_be_bytes());
+                payload.extend_from_slice(&author.to_bytes_le());
+            }
+            None => {
+                payload.push(RECORD_MESSAGE);
+                payload.extend_from_slice(&mesg.id.to_be_bytes());
+            }
+        }
+        payload.extend_from_slice(&mesg.timestamp.to_be_bytes());
+        payload.extend_from_slice(&mesg.sender_id.to_be_bytes());
+        payload.extend_from_slice(&(sender_bytes.len() as u16).to_be_bytes());
+        payload.extend_from_slice(sender_bytes);
+        payload.extend_from_slice(&(content_bytes.len() as u16).to_be_bytes());
+        payload.extend_from_slice(content_bytes);
+        if let Some(reply_to) = mesg.reply_to {
+            payload.extend_from_slice(&reply_to.to_be_bytes());
+        }
+        Self::encode_record(payload)
+    }
+
+    fn encode_edit(id: u32, content: &str) -> Vec<u8> {
+        //  edit payload:
+        //|  u8  | const RECORD_EDIT
+        //|  u32 | message id
+        //| [u8] | new content (empty when deleted)
+
+        let mut payload = Vec::with_capacity(1 + 4 + content.len());
+        payload.push(RECORD_EDIT);
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(content.as_bytes());
+        Self::encode_record(payload)
+    }
+
+    fn decode_message(id: u32, payload: &[u8]) -> Option<Message> {
+        let timestamp = u32::from_be_bytes(payload.get(0..4)?.try_into().ok()?);
+        let sender_id = u16::from_be_bytes(payload.get(4..6)?.try_into().ok()?);
+        let sender_len = u16::from_be_bytes(payload.get(6..8)?.try_into().ok()?) as usize;
+        let sender = std::str::from_utf8(payload.get(8..8 + sender_len)?).ok()?;
+        let rest = &payload[8 + sender_len..];
+        let content_len = u16::from_be_bytes(rest.get(0..2)?.try_into().ok()?) as usize;
+        let content = std::str::from_utf8(rest.get(2..2 + content_len)?).ok()?;
+        let reply_to = match &rest[2 + content_len..] {
+            [] => None,
+            reply_to => Some(u32::from_be_bytes(reply_to.try_into().ok()?)),
+        };
+        Some(Message {
+            id,
+            sender: sender.into(),
+            content: content.into(),
+            timestamp,
+            sender_id,
+            reply_to,
+        })
+    }
+
+    fn decode_payload(v2: bool, next_id: u32, payload: &[u8]) -> Option<Record> {
+        let message = |mesg, author| Record::Message(Entry { mesg, author });
+        if !v2 {
+            return Self::decode_message(next_id, payload).map(|mesg| message(mesg, None));
+        }
+        let (kind, rest) = payload.split_first()?;
+        let id = u32::from_be_bytes(rest.get(0..4)?.try_into().ok()?);
+        let rest = &rest[4..];
+        match *kind {
+            RECORD_MESSAGE => Self::decode_message(id, rest).map(|mesg| message(mesg, None)),
+            RECORD_AUTHORED_MESSAGE => {
+                let author = UserId::from_bytes_le(rest.get(0..17)?.try_into().ok()?)?;
+                Self::decode_message(id, &rest[17..]).map(|mesg| message(mesg, Some(author)))
+            }
+            RECORD_EDIT => Some(Record::Edit {
+                id,
+                content: std::str::from_utf8(rest).ok()?.into(),
+            }),
+            _ => None,
+        }
+    }
+
+    ///Decodes all valid records and the offsets of the message records. Stops at the first
+    ///truncated or corrupt record. Records from before message ids existed are numbered from
+    ///`first_id`.
+    fn decode_all(data: &[u8], first_id: u32) -> (Vec<Record>, Vec<u64>, usize) {
+        let mut records = Vec::new();
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        let mut next_id = first_id;
+        while let Some(header) = data.get(offset..offset + 8) {
+            let len = u32::from_be_bytes(header[0..4].try_into().unwrap());
+            let crc = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let v2 = len & RECORD_V2 != 0;
+            let len = (len & !RECORD_V2) as usize;
+            let Some(payload) = data.get(offset + 8..offset + 8 + len) else {
+                break;
+            };
+            if crc32fast::hash(payload) != crc {
+                break;
+            }
+            let Some(record) = Self::decode_payload(v2, next_id, payload) else {
+                break;
+            };
+            if let Record::Message(entry) = &record {
+                next_id = entry.mesg.id.wrapping_add(1);
+                offsets.push(offset as u64);
+            }
+            records.push(record);
+            offset += 8 + len;
+        }
+        (records, offsets, offset)
+    }
+
+    ///Returns the messages with the edits applied
+    fn apply_edits(records: Vec<Record>, edits: &HashMap<u32, Arc<str>>) -> (Vec<Entry>, bool) {
+        let mut entries = Vec::with_capacity(records.len());
+        let mut index_by_id = HashMap::new();
+        let mut has_edits = false;
+        for record in records {
+            match record {
+                Record::Message(entry) => {
+                    index_by_id.insert(entry.mesg.id, entries.len());
+                    entries.push(entry);
+                }
+                Record::Edit { id, content } => {
+                    has_edits = true;
+                    if let Some(index) = index_by_id.get(&id) {
+                        entries[*index].mesg.content = content;
+                    }
+                }
+            }
+        }
+        for entry in &mut entries {
+            if let Some(content) = edits.get(&entry.mesg.id) {
+                entry.mesg.content = content.clone();
+            }
+        }
+        (entries, has_edits)
+    }
+
+    ///Timestamp of the oldest message that is not older than `retention`
+    fn min_timestamp(retention: Duration) -> u32 {
+        SystemTime::now()
+            .checked_sub(retention)
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|time| (time.as_secs() / 60) as u32)
+            .unwrap_or(0)
+    }
+
+    ///Rewrites the history file with only the given messages
+    fn rewrite(path: &Path, first_index: usize, entries: &[Entry]) -> io::Result<FileState> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut offsets = Vec::with_capacity(entries.len());
+        let mut ids = Vec::with_capacity(entries.len());
+        let mut len = 0;
+        for entry in entries {
+            let data = Self::encode(entry);
+            writer.write_all(&data)?;
+            offsets.push(len);
+            ids.push(entry.mesg.id);
+            len += data.len() as u64;
+        }
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(FileState {
+            file: Some(OpenOptions::new().append(true).open(path)?),
+            first_index,
+            offsets,
+            ids,
+            len,
+            edits: HashMap::new(),
+            failed: false,
+        })
+    }
+
+    ///Appends a record, `message_id` is the id of the message when it is a message record
+    fn append_data(&self, data: &[u8], message_id: Option<u32>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.failed {
+            return Ok(());
+        }
+        let result = Self::write_record(&mut state, &self.path, data, message_id);
+        if result.is_err() && message_id.is_some() {
+            error!(
+                "History file {} is missing a message, not storing any more",
+                self.path.display()
+            );
+            state.failed = true;
+        }
+        result
+    }
+
+    fn write_record(
+        state: &mut FileState,
+        path: &Path,
+        data: &[u8],
+        message_id: Option<u32>,
+    ) -> io::Result<()> {
+        let len = state.len;
+        let file = match state.file.as_mut() {
+            Some(file) => file,
+            None => state
+                .file
+                .insert(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        if let Err(err) = file.write_all(data) {
+            // Cut off the partially written record so the offsets stay valid
+            file.set_len(len)?;
+            return Err(err);
+        }
+        if let Some(id) = message_id {
+            state.offsets.push(len);
+            state.ids.push(id);
+        }
+        state.len += data.len() as u64;
+        Ok(())
+    }
+
+    fn load_file(&self, retention: Option<Duration>) -> io::Result<Vec<Entry>> {
+        let mut data = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut data)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let (records, offsets, valid_len) = Self::decode_all(&data, 0);
+        if valid_len != data.len() {
+            warn!(
+                "History file {} has {} bytes of trailing garbage. Cutting it off",
+                self.path.display(),
+                data.len() - valid_len
+            );
+        }
+        let (mut entries, has_edits) = Self::apply_edits(records, &HashMap::new());
+        let total = entries.len();
+        if let Some(retention) = retention {
+            let min_timestamp = Self::min_timestamp(retention);
+            entries.retain(|entry| entry.mesg.timestamp >= min_timestamp);
+        }
+        if valid_len != data.len() || total != entries.len() || has_edits {
+            *self.state.lock().unwrap() = Self::rewrite(&self.path, 0, &entries)?;
+        } else {
+            let mut state = self.state.lock().unwrap();
+            state.offsets = offsets;
+            state.ids = entries.iter().map(|entry| entry.mesg.id).collect();
+            state.len = valid_len as u64;
+        }
+
+        Ok(entries)
+    }
+}
+impl HistoryStore for FileHistoryStore {
+    fn append(&self, entry: &Entry) -> io::Result<()> {
+        self.append_data(&Self::encode(entry), Some(entry.mesg.id))
+    }
+
+    fn append_edit(&self, id: u32, content: &str) -> io::Result<()> {
+        self.append_data(&Self::encode_edit(id, content), None)?;
+        self.state.lock().unwrap().edits.insert(id, content.into());
+        Ok(())
+    }
+
+    fn load(&self, retention: Option<Duration>) -> io::Result<Vec<Entry>> {
+        let result = self.load_file(retention);
+        if result.is_err() {
+            // appends would no longer line up with the indices of the loaded messages
+            self.state.lock().unwrap().failed = true;
+        }
+        result
+    }
+
+    fn read(&self, range: Range<usize>) -> io::Result<(usize, Vec<Message>)> {
+        let (mut file, start, end, first_index, first_id, edits) = {
+            let state = self.state.lock().unwrap();
+            if state.failed {
+                return Err(io::Error::other("history file is missing messages"));
+            }
+            let start_index = range.start.saturating_sub(state.first_index);
+            let end_index = range.end.saturating_sub(state.first_index);
+            let Some(start) = state.offsets.get(start_index) else {
+                return Ok((range.start, Vec::new()));
+            };
+            let end = state.offsets.get(end_index).copied().unwrap_or(state.len);
+            let ids = &state.ids[start_index..end_index.clamp(start_index, state.ids.len())];
+            let Some((&first_id, &last_id)) = ids.first().zip(ids.last()) else {
+                return Ok((range.start, Vec::new()));
+            };
+            let edits: HashMap<u32, Arc<str>> = state
+                .edits
+                .iter()
+                .filter(|(id, _)| (first_id..=last_id).contains(*id))
+                .map(|(id, content)| (*id, content.clone()))
+                .collect();
+            // opened while locked so a compaction can't replace the file in between
+            let file = File::open(&self.path)?;
+            let first_index = state.first_index + start_index;
+            (file, *start, end, first_index, first_id, edits)
+        };
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = vec![0; end.saturating_sub(start) as usize];
+        file.read_exact(&mut data)?;
+        let (records, _, _) = Self::decode_all(&data, first_id);
+        // Edit records in this range are also in `edits`
+        let (entries, _) = Self::apply_edits(records, &edits);
+        Ok((
+            first_index,
+            entries.into_iter().map(|entry| entry.mesg).collect(),
+        ))
+    }
+
+    fn compact(&self, retention: Option<Duration>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.failed || state.len == 0 {
+            return Ok(());
+        }
+        let mut data = vec![0; state.len as usize];
+        File::open(&self.path)?.read_exact(&mut data)?;
+        let first_id = state.ids.first().copied().unwrap_or(0);
+        let (records, _, _) = Self::decode_all(&data, first_id);
+        let (mut entries, has_edits) = Self::apply_edits(records, &HashMap::new());
+        if entries.len() != state.ids.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "history file changed while the server is running",
+            ));
+        }
+        // Only the oldest messages are dropped so the indices of the others stay the same
+        let expired = retention.map_or(0, |retention| {
+            let min_timestamp = Self::min_timestamp(retention);
+            entries
+                .iter()
+                .take_while(|entry| entry.mesg.timestamp < min_timestamp)
+                .count()
+        });
+        if expired == 0 && !has_edits {
+            return Ok(());
+        }
+        entries.drain(..expired);
+        let first_index = state.first_index + expired;
+        *state = Self::rewrite(&self.path, first_index, &entries)?;
+        debug!(
+            "Compacted history file {}, dropped {} messages",
+            self.path.display(),
+            expired
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Path in the temp dir that is removed when the test is done
+    struct TempPath(PathBuf);
+    impl TempPath {
+        fn new() -> Self {
+            let name = format!("smppgc-history-{}.hist", uuid::Uuid::new_v4().simple());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn now() -> u32 {
+        FileHistoryStore::min_timestamp(Duration::ZERO)
+    }
+
+    fn entry(id: u32, timestamp: u32, content: &str, author: Option<UserId>) -> Entry {
+        Entry {
+            mesg: Message {
+                id,
+                sender: "alice".into(),
+                content: content.into(),
+                timestamp,
+                sender_id: 1,
+                reply_to: (id > 0).then(|| id - 1),
+            },
+            author,
+        }
+    }
+
+    ///Record without RECORD_V2, as written before message ids existed
+    fn legacy_record(mesg: &Message) -> Vec<u8> {
+        let entry = Entry {
+            mesg: Message {
+                reply_to: None,
+                ..mesg.clone()
+            },
+            author: None,
+        };
+        // the v2 payload starts with the record kind and message id
+        let payload = &FileHistoryStore::encode(&entry)[8 + 1 + 4..];
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn contents(messages: &[Message]) -> Vec<(u32, &str)> {
+        messages
+            .iter()
+            .map(|mesg| (mesg.id, mesg.content.as_ref()))
+            .collect()
+    }
+
+    fn entry_contents(entries: &[Entry]) -> Vec<(u32, &str)> {
+        entries
+            .iter()
+            .map(|entry| (entry.mesg.id, entry.mesg.content.as_ref()))
+            .collect()
+    }
+
+    #[test]
+    fn load_applies_edits_and_keeps_authors() {
+        let path = TempPath::new();
+        let author = UserId::new();
+        let store = FileHistoryStore::new(path.0.clone());
+        store.load(None).unwrap();
+        for id in 0..3 {
+            store
+                .append(&entry(id, now(), "hoi", Some(author.clone())))
+                .unwrap();
+        }
+        store.append_edit(1, "bewerkt").unwrap();
+        store.append_edit(2, "").unwrap();
+
+        let entries = FileHistoryStore::new(path.0.clone()).load(None).unwrap();
+        assert_eq!(
+            entry_contents(&entries),
+            [(0, "hoi"), (1, "bewerkt"), (2, "")]
+        );
+        assert!(entries
+            .iter()
+            .all(|entry| entry.author.as_ref() == Some(&author)));
+        assert_eq!(entries[2].mesg.reply_to, Some(1));
+        // the edits were folded in when loading
+        let data = std::fs::read(&path.0).unwrap();
+        let (records, _, _) = FileHistoryStore::decode_all(&data, 0);
+        assert!(records
+            .iter()
+            .all(|record| matches!(record, Record::Message(_))));
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let path = TempPath::new();
+        let store = FileHistoryStore::new(path.0.clone());
+        store.load(None).unwrap();
+        for id in 0..3 {
+            store.append(&entry(id, now(), "hoi", None)).unwrap();
+        }
+        let valid_len = std::fs::metadata(&path.0).unwrap().len();
+        let torn = FileHistoryStore::encode(&entry(3, now(), "half geschreven", None));
+        let mut file = OpenOptions::new().append(true).open(&path.0).unwrap();
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(file);
+
+        let store = FileHistoryStore::new(path.0.clone());
+        let entries = store.load(None).unwrap();
+        assert_eq!(
+            entry_contents(&entries),
+            [(0, "hoi"), (1, "hoi"), (2, "hoi")]
+        );
+        assert_eq!(std::fs::metadata(&path.0).unwrap().len(), valid_len);
+        // appends continue after the last valid record
+        store.append(&entry(3, now(), "heel", None)).unwrap();
+        let (first_index, messages) = store.read(2..4).unwrap();
+        assert_eq!(first_index, 2);
+        assert_eq!(contents(&messages), [(2, "hoi"), (3, "heel")]);
+    }
+
+    #[test]
+    fn corrupt_record_is_cut_off() {
+        let path = TempPath::new();
+        let store = FileHistoryStore::new(path.0.clone());
+        store.load(None).unwrap();
+        for id in 0..3 {
+            store.append(&entry(id, now(), "hoi", None)).unwrap();
+        }
+        let mut data = std::fs::read(&path.0).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path.0, data).unwrap();
+
+        let entries = FileHistoryStore::new(path.0.clone()).load(None).unwrap();
+        assert_eq!(entry_contents(&entries), [(0, "hoi"), (1, "hoi")]);
+    }
+
+    #[test]
+    fn legacy_records_are_numbered_in_order() {
+        let path = TempPath::new();
+        let mut data = Vec::new();
+        for content in ["een", "twee", "drie"] {
+            data.extend(legacy_record(&entry(0, now(), content, None).mesg));
+        }
+        std::fs::write(&path.0, data).unwrap();
+
+        let store = FileHistoryStore::new(path.0.clone());
+        let entries = store.load(None).unwrap();
+        assert_eq!(
+            entry_contents(&entries),
+            [(0, "een"), (1, "twee"), (2, "drie")]
+        );
+        store
+            .append(&entry(3, now(), "vier", Some(UserId::new())))
+            .unwrap();
+        store.append_edit(2, "drie!").unwrap();
+
+        let (first_index, messages) = store.read(1..4).unwrap();
+        assert_eq!(first_index, 1);
+        assert_eq!(
+            contents(&messages),
+            [(1, "twee"), (2, "drie!"), (3, "vier")]
+        );
+    }
+
+    #[test]
+    fn compaction_keeps_indices() {
+        let path = TempPath::new();
+        let store = FileHistoryStore::new(path.0.clone());
+        store.load(None).unwrap();
+        let old = now() - 2 * 24 * 60;
+        for id in 0..4 {
+            let timestamp = if id < 2 { old } else { now() };
+            store.append(&entry(id, timestamp, "hoi", None)).unwrap();
+        }
+        store.append_edit(3, "bewerkt").unwrap();
+        let retention = Some(Duration::from_secs(24 * 60 * 60));
+        store.compact(retention).unwrap();
+
+        let (first_index, messages) = store.read(0..4).unwrap();
+        assert_eq!(first_index, 2);
+        assert_eq!(contents(&messages), [(2, "hoi"), (3, "bewerkt")]);
+        assert_eq!(store.read(0..2).unwrap().1.len(), 0);
+        store.append(&entry(4, now(), "nieuw", None)).unwrap();
+        let (first_index, messages) = store.read(3..5).unwrap();
+        assert_eq!(first_index, 3);
+        assert_eq!(contents(&messages), [(3, "bewerkt"), (4, "nieuw")]);
+
+        let entries = FileHistoryStore::new(path.0.clone()).load(None).unwrap();
+        assert_eq!(
+            entry_contents(&entries),
+            [(2, "hoi"), (3, "bewerkt"), (4, "nieuw")]
+        );
+    }
+
+    #[test]
+    fn failed_append_stops_the_store() {
+        let dir = TempPath::new();
+        // the parent directory doesn't exist, so the file can't be created
+        let store = FileHistoryStore::new(dir.0.join("room.hist"));
+        assert!(store.load(None).unwrap().is_empty());
+        assert!(store.append(&entry(0, now(), "hoi", None)).is_err());
+        assert!(store.read(0..1).is_err());
+        // later appends don't go in at the wrong index
+        std::fs::create_dir(&dir.0).unwrap();
+        store.append(&entry(1, now(), "hoi", None)).unwrap();
+        assert!(!dir.0.join("room.hist").exists());
+        std::fs::remove_dir(&dir.0).unwrap();
+    }
+}
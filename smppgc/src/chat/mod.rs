@@ -1,27 +1,40 @@
-use std::{borrow::Cow, collections::HashSet, sync::Arc};
+use std::{borrow::Cow, net::IpAddr, sync::Arc, time::Duration};
 
-use log::*;
 use rocket_ws::{
     frame::{CloseCode, CloseFrame},
     stream::DuplexStream,
 };
-use tokio::sync::{
-    broadcast::{self, error::RecvError},
-    Mutex,
-};
+use tokio::sync::{mpsc, Mutex};
 
 pub mod client;
-mod packet;
+pub mod history;
+pub mod packet;
+pub mod presence;
+pub mod queue;
+pub mod reactions;
+pub mod rooms;
+pub mod session;
 
 use crate::{
+    moderation::{BanTarget, Moderation},
     names::{ClaimedName, UserId},
-    utils::dropvec::DropVec,
     ChatConfig,
 };
 use client::{Client, ClientFactory, ClientInfo, Message};
+use history::{History, HistoryStore};
 use lmetrics::metrics;
+use packet::{Capabilities, Protocol, ServerPacket, User};
+use presence::{Presence, PresenceGuard};
+use queue::{Fanout, QueueReceiver};
+use reactions::{ReactionCount, Reactions, REACTIONS};
+use session::{DetachedSession, Sessions};
 use thiserror::Error;
 
+pub use smppgc_proto::{MessageEdit, PrivateMessage, ReactionDelta, Severity, Typing};
+
+///How many private messages can be waiting for a client
+const MAILBOX_SIZE: usize = 16;
+
 metrics! {
     pub counter joined_total("Total joined users",[]);
     pub counter left_total("Total left users", []);
@@ -32,144 +45,477 @@ metrics! {
 pub enum NewClientError {
     #[error("Max concurrent user count reached")]
     MaxConcurrentUserCount,
+    #[error("User is banned")]
+    Banned,
     #[error("Setup packet fail: {0}")]
     SetupPacketError(#[from] rocket_ws::result::Error),
 }
 
-pub struct Chat {
-    messages_sender: broadcast::Sender<Message>,
-    join_sender: broadcast::Sender<ClientInfo>,
-    left_sender: broadcast::Sender<ClientInfo>,
+///Message from the server to every client in the room
+#[derive(Clone, Debug)]
+pub struct SystemMessage {
+    pub severity: Severity,
+    pub text: Arc<str>,
+}
+
+#[derive(Debug, Error)]
+pub enum PrivateMessageError {
+    #[error("Die gebruiker is niet online.")]
+    NotOnline,
+    #[error("Log in om privéberichten te sturen.")]
+    AnonSender,
+    #[error("{0} kan geen privéberichten ontvangen.")]
+    AnonRecipient(Arc<str>),
+    #[error("{0} krijgt te veel privéberichten. Probeer het later opnieuw.")]
+    MailboxFull(Arc<str>),
+}
+
+#[derive(Debug, Error)]
+pub enum EditError {
+    #[error("Dat bericht bestaat niet (meer).")]
+    NotFound,
+    #[error("Je kan alleen je eigen berichten aanpassen.")]
+    NotAuthor,
+}
+
+#[derive(Debug, Error)]
+pub enum ReactionError {
+    #[error("Dat bericht bestaat niet (meer).")]
+    NotFound,
+    #[error("Onbekende reactie.")]
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub enum KickTarget {
+    Client(u16),
+    Ban(BanTarget),
+}
+
+///Disconnects every client in the room that matches `target`
+#[derive(Clone, Debug)]
+pub struct Kick {
+    pub target: KickTarget,
+    pub reason: &'static str,
+}
+impl Kick {
+    pub fn matches(&self, client: &ClientInfo) -> bool {
+        match &self.target {
+            KickTarget::Client(id) => client.id() == *id,
+            KickTarget::Ban(BanTarget::User(user_id)) => client.user_id() == user_id,
+            KickTarget::Ban(BanTarget::Ip(ip)) => client.ip() == Some(*ip),
+        }
+    }
+}
+
+///Something that happened in the room, every client gets it in its queue
+#[derive(Clone, Debug)]
+pub enum ChatEvent {
+    Message(Message),
+    Edit(MessageEdit),
+    Reaction(ReactionDelta),
+    Joined(ClientInfo),
+    Left(ClientInfo),
+    System(SystemMessage),
+    Kick(Kick),
+    Typing(Typing),
+}
+
+pub type ChatEvents = QueueReceiver<ChatEvent>;
 
-    clients: Arc<Mutex<HashSet<ClientInfo>>>,
-    history: Arc<Mutex<DropVec<Message>>>,
+pub struct Chat {
+    events: Arc<Fanout<ChatEvent>>,
+    presence: Arc<Presence>,
+    sessions: Arc<Sessions>,
+    history: Arc<Mutex<History>>,
+    ///Reactions on the messages in `history`. Lock the history first when locking both.
+    reactions: Mutex<Reactions>,
     client_factory: ClientFactory,
+    moderation: Arc<Moderation>,
 
     config: ChatConfig,
 }
 impl Chat {
-    pub fn new(config: ChatConfig) -> Self {
-        let (messages_sender, messages_receiver) = broadcast::channel(20);
-        let (join_sender, _) = broadcast::channel(20);
-        let (left_sender, left_receiver) = broadcast::channel(20);
-
-        let clients = Arc::new(Mutex::new(HashSet::new()));
-        let history = Arc::new(Mutex::new(DropVec::new(config.max_stored_messages)));
-
-        Self::spawn_histrec(
-            left_receiver,
-            messages_receiver,
-            clients.clone(),
-            history.clone(),
+    pub fn new(
+        config: ChatConfig,
+        store: Option<Arc<dyn HistoryStore>>,
+        moderation: Arc<Moderation>,
+    ) -> Self {
+        let events = Arc::new(Fanout::new(config.client_queue_size));
+        // the socket closes the connection first, this is for sockets that are stuck
+        let presence = Presence::new(
+            events.clone(),
+            Duration::from_secs(config.ping_interval + config.pong_timeout * 2),
         );
+        let history = Arc::new(Mutex::new(History::new(&config, store)));
 
         Self {
-            messages_sender,
-            join_sender,
-            left_sender,
-            clients,
+            events,
+            presence,
+            sessions: Sessions::new(Duration::from_secs(config.resume_grace)),
             history,
+            reactions: Mutex::new(Reactions::default()),
             client_factory: ClientFactory::new(),
+            moderation,
             config,
         }
     }
 
-    fn spawn_histrec(
-        mut left_receiver: broadcast::Receiver<ClientInfo>,
-        mut messages_receiver: broadcast::Receiver<Message>,
-        clients: Arc<Mutex<HashSet<ClientInfo>>>,
-        history: Arc<Mutex<DropVec<Message>>>,
-    ) {
-        tokio::task::spawn(async move {
-            loop {
-                tokio::select! {
-                    left_client = left_receiver.recv() => {
-                        match left_client{
-                            Ok(left_client)=>{
-                                left_total::inc();
-                                trace!("User {} left", left_client.id());
-                                clients.lock().await.remove(&left_client);
-                            },
-                            Err(RecvError::Closed)=>{
-                                return;
-                            },
-                            Err(RecvError::Lagged(count))=>{
-                                error!("main client_left receiver lagged behind {} times. Ghosts will appear", count);
-                            }
-                        }
-                    },
-                    mesg = messages_receiver.recv() => {
-                        match mesg{
-                            Ok(mesg) => {
-                                history.lock().await.push(mesg);
-                                messages_total::inc();
-                            },
-                            Err(RecvError::Closed) => {
-                                return;
-                            },
-                            Err(RecvError::Lagged(count))=>{
-                                error!("Lost {} messages", count);
-                            }
-                        }
-                    }
-
-                }
-            }
-        });
-    }
-
+    ///Sets up a new client. Only the snapshot of the clients and history is taken under a lock, the
+    ///setup packet is sent without blocking other joins. Everything that happens after the snapshot
+    ///is queued in the returned events.
+    ///
+    ///Returns the client, the mailbox with the private messages sent to it and its events.
     pub async fn new_client(
-        &mut self,
+        &self,
         mut ws: DuplexStream,
+        protocol: Protocol,
         user_id: UserId,
+        token: &str,
+        ip: Option<IpAddr>,
         leased_name: ClaimedName,
-    ) -> Result<Client, NewClientError> {
-        if self.config.max_users != 0
-            && self.config.max_users <= self.clients.lock().await.len() as u16
-        {
-            ws.close(Some(CloseFrame {
+    ) -> Result<(Client, mpsc::Receiver<PrivateMessage>, ChatEvents), NewClientError> {
+        if self.reject_banned(&mut ws, &user_id, ip).await? {
+            return Err(NewClientError::Banned);
+        }
+        // Concurrent joins can briefly exceed max_users by a few clients.
+        if self.config.max_users != 0 && self.config.max_users as usize <= self.presence.count() {
+            return Err(Self::reject_full(ws).await);
+        }
+        self.client_factory
+            .new_client(ws, protocol, user_id, token, ip, leased_name, self)
+            .await
+    }
+
+    ///Closes the connection when the user or ip is banned. Returns true when it was closed.
+    pub async fn reject_banned(
+        &self,
+        ws: &mut DuplexStream,
+        user_id: &UserId,
+        ip: Option<IpAddr>,
+    ) -> rocket_ws::result::Result<bool> {
+        let Some(remaining) = self.moderation.banned_for(user_id, ip) else {
+            return Ok(false);
+        };
+        let reason = if remaining == Duration::MAX {
+            Cow::Borrowed("Je bent verbannen.")
+        } else {
+            Cow::Owned(format!(
+                "Je bent nog {} minuten verbannen.",
+                remaining.as_secs().div_ceil(60)
+            ))
+        };
+        ws.close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason,
+        }))
+        .await?;
+        Ok(true)
+    }
+
+    ///Closes the connection of a client that doesn't fit in the room
+    async fn reject_full(mut ws: DuplexStream) -> NewClientError {
+        let closed = ws
+            .close(Some(CloseFrame {
                 code: CloseCode::Again,
                 reason: Cow::Borrowed("Chat zit vol."),
             }))
-            .await?;
-            return Err(NewClientError::MaxConcurrentUserCount);
+            .await;
+        match closed {
+            Ok(()) => NewClientError::MaxConcurrentUserCount,
+            Err(err) => err.into(),
         }
-        let client = self
-            .client_factory
-            .new_client(ws, user_id, leased_name, &self)
-            .await
-            .map_err(|e| NewClientError::SetupPacketError(e))?;
-        let _ = self.join_sender.send(client.client_info()); // throws error when no receivers
-        self.clients.lock().await.insert(client.client_info());
+    }
 
-        Ok(client)
+    ///Adds the client to the user list until the guard is dropped. Returns the guard and the mailbox
+    ///with the private messages sent to the client, None when another client has its id.
+    fn register(
+        &self,
+        info: ClientInfo,
+    ) -> Option<(PresenceGuard, mpsc::Receiver<PrivateMessage>)> {
+        let (mailbox, mailbox_receiver) = mpsc::channel(MAILBOX_SIZE);
+        Some((self.presence.register(info, mailbox)?, mailbox_receiver))
+    }
+
+    ///Setup packet with a snapshot of the room. Messages and edits are sent with the history
+    ///locked, take the snapshot under the same lock as the subscription or clearing of the queue
+    ///so no message is both in the snapshot and after it in the queue of the client.
+    async fn setup_packet(
+        &self,
+        history: &History,
+        id: u16,
+        token: &str,
+        user_id: &UserId,
+    ) -> ServerPacket {
+        ServerPacket::Setup {
+            id,
+            key: token.into(),
+            users: self.clients().iter().map(User::from).collect(),
+            reactions: self.reactions(user_id).await,
+            first_history_index: history.first_recent_index(),
+            history: history.recent(),
+        }
+    }
+
+    ///Adds a joining client to the room and takes the snapshot for its setup packet. The client is
+    ///registered before it subscribes to the events, so it finds itself in the user list of the
+    ///snapshot instead of getting its own join event. None when another client has its id.
+    async fn join(
+        &self,
+        info: ClientInfo,
+        token: &str,
+    ) -> Option<(
+        ServerPacket,
+        PresenceGuard,
+        mpsc::Receiver<PrivateMessage>,
+        ChatEvents,
+    )> {
+        let history = self.history.lock().await;
+        let (presence, mailbox) = self.register(info.clone())?;
+        let events = self.events.subscribe();
+        let setup = self
+            .setup_packet(&history, info.id(), token, info.user_id())
+            .await;
+        Some((setup, presence, mailbox, events))
+    }
+
+    ///Throws away the queued events of a client that fell behind and sends it a fresh setup packet.
+    ///Returns the discarded events.
+    pub async fn resync(
+        &self,
+        client: &mut Client,
+        events: &mut ChatEvents,
+    ) -> rocket_ws::result::Result<Vec<ChatEvent>> {
+        let history = self.history.lock().await;
+        let discarded = events.clear();
+        let setup = self
+            .setup_packet(
+                &history,
+                client.client_info().id(),
+                client.token(),
+                client.user_id(),
+            )
+            .await;
+        drop(history);
+        client.send_setup(setup).await?;
+        Ok(discarded)
+    }
+
+    ///New resume token when the client can resume its session
+    fn resume_token(&self, protocol: &Protocol) -> Option<Arc<str>> {
+        (self.sessions.enabled() && protocol.capabilities.contains(Capabilities::RESUME))
+            .then(Sessions::new_token)
+    }
+
+    ///Keeps the session of a client whose connection dropped, see [Client::into_session]
+    pub fn detach(
+        &self,
+        client: Client,
+        events: ChatEvents,
+        mailbox: mpsc::Receiver<PrivateMessage>,
+    ) {
+        if let Some((token, session)) = client.into_session(events, mailbox) {
+            self.sessions.detach(token, session);
+        }
+    }
+
+    ///Returns the detached session of the user with this resume token
+    pub fn take_session(&self, token: &str, user_id: &UserId) -> Option<DetachedSession> {
+        self.sessions.take(token, user_id)
+    }
+
+    ///Continues a detached session on a new connection and sends the client what it missed since
+    ///message `last_seen`: only the missed messages when they are all in the recent history, a
+    ///fresh setup packet otherwise. Returns the other events that happened in the meantime.
+    pub async fn resume(
+        &self,
+        ws: DuplexStream,
+        protocol: Protocol,
+        session: DetachedSession,
+        last_seen: Option<u32>,
+    ) -> rocket_ws::result::Result<(
+        Client,
+        mpsc::Receiver<PrivateMessage>,
+        ChatEvents,
+        Vec<ChatEvent>,
+    )> {
+        let last_seen = last_seen.or(session.last_seen_id);
+        let (mut client, mailbox, mut events) =
+            ClientFactory::resume_client(ws, protocol, session, self);
+        client.send_session(true).await?;
+
+        let history = self.history.lock().await;
+        let overflowed = events.is_overflowed();
+        let discarded = events.clear();
+        let missed = last_seen
+            .filter(|_| !overflowed)
+            .and_then(|id| history.after(id));
+        let Some(missed) = missed else {
+            let setup = self
+                .setup_packet(
+                    &history,
+                    client.client_info().id(),
+                    client.token(),
+                    client.user_id(),
+                )
+                .await;
+            drop(history);
+            client.send_setup(setup).await?;
+            // the setup packet replaces the rest, kicks and system messages still apply
+            let discarded = discarded
+                .into_iter()
+                .filter(|event| matches!(event, ChatEvent::Kick(_) | ChatEvent::System(_)))
+                .collect();
+            return Ok((client, mailbox, events, discarded));
+        };
+        drop(history);
+        client.forward_all(missed.iter()).await?;
+        // the missed messages are already sent, typing signals are outdated
+        let discarded = discarded
+            .into_iter()
+            .filter(|event| !matches!(event, ChatEvent::Message(_) | ChatEvent::Typing(_)))
+            .collect();
+        Ok((client, mailbox, events, discarded))
     }
 
     pub fn config(&self) -> &ChatConfig {
         &self.config
     }
 
-    pub async fn history<'a>(&'a self) -> Vec<Message> {
-        self.history.lock().await.iter().cloned().collect()
+    ///Returns the recent history and the history index of the first message
+    pub async fn history(&self) -> (u32, Vec<Message>) {
+        let history = self.history.lock().await;
+        (history.first_recent_index(), history.recent())
+    }
+    ///Returns true when the message is in the recent history and not deleted
+    pub async fn message_exists(&self, id: u32) -> bool {
+        self.history.lock().await.get(id).is_some()
     }
-    pub async fn clients<'a>(&'a self) -> Vec<ClientInfo> {
-        self.clients.lock().await.iter().cloned().collect()
+    pub async fn history_before(&self, index: u32, count: u32) -> (u32, Vec<Message>) {
+        let count = count.min(self.config.max_stored_messages as u32);
+        let scrollback = self.history.lock().await.before(index, count);
+        scrollback.read().await
+    }
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.presence.clients()
+    }
+
+    ///Updates the username of a connected client and announces it with a join event
+    pub fn rename_client(&self, client: ClientInfo) {
+        self.presence.rename(client);
     }
 
-    pub fn subscribe_events(
+    ///Delivers the message to the mailbox of `recipient`. Returns the delivered message.
+    pub fn send_private(
         &self,
-    ) -> (
-        broadcast::Receiver<Message>,
-        broadcast::Sender<Message>,
-        broadcast::Receiver<ClientInfo>,
-    ) {
-        (
-            self.messages_sender.subscribe(),
-            self.messages_sender.clone(),
-            self.join_sender.subscribe(),
-        )
-    }
-    fn left_sender(&self) -> broadcast::Sender<ClientInfo> {
-        self.left_sender.clone()
+        mesg: Message,
+        recipient: u16,
+    ) -> Result<PrivateMessage, PrivateMessageError> {
+        if !self.config.anon_private_messages {
+            let sender_anon = self
+                .presence
+                .get(mesg.sender_id)
+                .is_none_or(|(info, _)| info.user_id().is_anon());
+            if sender_anon {
+                return Err(PrivateMessageError::AnonSender);
+            }
+        }
+        let (info, mailbox) = self
+            .presence
+            .get(recipient)
+            .ok_or(PrivateMessageError::NotOnline)?;
+        let username: Arc<str> = info.username().into();
+        if !self.config.anon_private_messages && info.user_id().is_anon() {
+            return Err(PrivateMessageError::AnonRecipient(username));
+        }
+        let private = PrivateMessage { mesg, recipient };
+        mailbox.try_send(private.clone()).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => PrivateMessageError::MailboxFull(username),
+            mpsc::error::TrySendError::Closed(_) => PrivateMessageError::NotOnline,
+        })?;
+        Ok(private)
+    }
+
+    ///Assigns the message its id, stores it in the history and broadcasts it. The history stays
+    ///locked while broadcasting so clients receive the messages in id order.
+    pub async fn send_message(&self, mesg: Message, author: &UserId) {
+        let mut history = self.history.lock().await;
+        let mesg = history.push(mesg, author.clone());
+        self.events.send(ChatEvent::Message(mesg));
+        messages_total::inc();
+        if let Some(oldest_id) = history.oldest_recent_id() {
+            self.reactions.lock().await.prune(oldest_id);
+        }
+    }
+
+    ///Replaces the content of a recent message, an empty content deletes it. Only the author and
+    ///moderators can edit a message.
+    pub async fn edit_message(
+        &self,
+        editor: &ClientInfo,
+        moderator: bool,
+        id: u32,
+        content: Arc<str>,
+    ) -> Result<(), EditError> {
+        let mut history = self.history.lock().await;
+        history.get(id).ok_or(EditError::NotFound)?;
+        if !moderator && history.author(id) != Some(editor.user_id()) {
+            return Err(EditError::NotAuthor);
+        }
+        history.edit(id, content.clone());
+        if content.is_empty() {
+            self.reactions.lock().await.remove_message(id);
+        }
+        self.events
+            .send(ChatEvent::Edit(MessageEdit { id, content }));
+        Ok(())
+    }
+
+    ///Adds or removes a reaction of the client on a recent message
+    pub async fn react(
+        &self,
+        client: &ClientInfo,
+        message_id: u32,
+        reaction: u8,
+        add: bool,
+    ) -> Result<(), ReactionError> {
+        if reaction as usize >= REACTIONS.len() {
+            return Err(ReactionError::Unknown);
+        }
+        let history = self.history.lock().await;
+        history.get(message_id).ok_or(ReactionError::NotFound)?;
+        let mut reactions = self.reactions.lock().await;
+        let changed = if add {
+            reactions.add(message_id, reaction, client.user_id().clone())
+        } else {
+            reactions.remove(message_id, reaction, client.user_id())
+        };
+        if changed {
+            self.events.send(ChatEvent::Reaction(ReactionDelta {
+                message_id,
+                reaction,
+                client_id: client.id(),
+                added: add,
+            }));
+        }
+        Ok(())
+    }
+
+    ///Returns the reaction counts of the recent messages
+    pub async fn reactions(&self, user_id: &UserId) -> Vec<ReactionCount> {
+        self.reactions.lock().await.counts(user_id)
+    }
+
+    pub fn send_system(&self, mesg: SystemMessage) {
+        self.events.send(ChatEvent::System(mesg));
+    }
+
+    ///Typing signals are only broadcast, they are not stored in the history
+    pub fn send_typing(&self, typing: Typing) {
+        self.events.send(ChatEvent::Typing(typing));
+    }
+
+    pub fn kick(&self, kick: Kick) {
+        self.events.send(ChatEvent::Kick(kick));
     }
 }
@@ -0,0 +1,322 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use lmetrics::metrics;
+use log::*;
+use tokio::sync::{mpsc, Notify};
+
+use super::{client::ClientInfo, left_total, queue::Fanout, ChatEvent, PrivateMessage};
+
+///How often the registry is checked for ghosts and dead connections
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(15);
+
+metrics! {
+    pub gauge users_online("Users connected to a room", []);
+    pub counter presence_evicted_total("Clients removed from the user list without leaving by reason", [reason]);
+}
+
+///Shared by the registry and the guard of a connection
+struct Connection {
+    last_heartbeat: Mutex<Instant>,
+    evicted: Notify,
+    ///Set while the session waits for a reconnect. It expires on its own and doesn't answer pings.
+    detached: AtomicBool,
+}
+
+struct PresenceSlot {
+    info: ClientInfo,
+    mailbox: mpsc::Sender<PrivateMessage>,
+    connection: Arc<Connection>,
+}
+
+///Authoritative list of the clients in a room. Clients are added when they join and removed when
+///their [PresenceGuard] is dropped, both synchronously. A reconciler removes ghosts that are left
+///behind anyway and evicts connections that stopped answering pings.
+pub struct Presence {
+    clients: DashMap<u16, PresenceSlot>,
+    events: Arc<Fanout<ChatEvent>>,
+    ///Connections that didn't send anything (not even a pong) for this long are evicted
+    heartbeat_timeout: Duration,
+}
+impl Presence {
+    pub fn new(events: Arc<Fanout<ChatEvent>>, heartbeat_timeout: Duration) -> Arc<Self> {
+        let presence = Arc::new(Self {
+            clients: DashMap::new(),
+            events,
+            heartbeat_timeout,
+        });
+        Self::spawn_reconciler(Arc::downgrade(&presence));
+        presence
+    }
+
+    fn spawn_reconciler(presence: Weak<Presence>) {
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(presence) = presence.upgrade() else {
+                    return;
+                };
+                presence.reconcile();
+            }
+        });
+    }
+
+    ///Removes the clients whose connection is gone and evicts the ones that timed out
+    pub(super) fn reconcile(&self) {
+        let mut removed = Vec::new();
+        self.clients.retain(|_, slot| {
+            // the guard holds the other reference, the mailbox receiver lives as long as the socket
+            let reason = if Arc::strong_count(&slot.connection) == 1 || slot.mailbox.is_closed() {
+                "ghost"
+            } else if !slot.connection.detached.load(Ordering::Relaxed)
+                && slot.connection.last_heartbeat.lock().unwrap().elapsed() > self.heartbeat_timeout
+            {
+                slot.connection.evicted.notify_one();
+                "heartbeat"
+            } else {
+                return true;
+            };
+            warn!("Evicting client {}: {}", slot.info.id(), reason);
+            presence_evicted_total::inc(reason);
+            removed.push(slot.info.clone());
+            false
+        });
+        for info in removed {
+            self.left(info);
+        }
+    }
+
+    ///Adds the client and announces it. The client stays in the list until the guard is dropped.
+    ///Returns None when another client has the id.
+    pub fn register(
+        self: &Arc<Self>,
+        info: ClientInfo,
+        mailbox: mpsc::Sender<PrivateMessage>,
+    ) -> Option<PresenceGuard> {
+        let connection = Arc::new(Connection {
+            last_heartbeat: Mutex::new(Instant::now()),
+            evicted: Notify::new(),
+            detached: AtomicBool::new(false),
+        });
+        match self.clients.entry(info.id()) {
+            Entry::Occupied(_) => return None,
+            Entry::Vacant(entry) => {
+                entry.insert(PresenceSlot {
+                    info: info.clone(),
+                    mailbox,
+                    connection: connection.clone(),
+                });
+            }
+        }
+        users_online::inc();
+        self.events.send(ChatEvent::Joined(info.clone()));
+        Some(PresenceGuard {
+            presence: self.clone(),
+            id: info.id(),
+            connection,
+        })
+    }
+
+    ///Removes the slot of this connection, the id can already be reused by another client
+    fn unregister(&self, id: u16, connection: &Arc<Connection>) {
+        let removed = self
+            .clients
+            .remove_if(&id, |_, slot| Arc::ptr_eq(&slot.connection, connection));
+        if let Some((_, slot)) = removed {
+            self.left(slot.info);
+        }
+    }
+
+    fn left(&self, info: ClientInfo) {
+        left_total::inc();
+        users_online::dec();
+        trace!("User {} left", info.id());
+        self.events.send(ChatEvent::Left(info));
+    }
+
+    pub fn count(&self) -> usize {
+        self.clients.len()
+    }
+    pub fn contains(&self, id: u16) -> bool {
+        self.clients.contains_key(&id)
+    }
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients
+            .iter()
+            .map(|client| client.info.clone())
+            .collect()
+    }
+    ///Returns the client and its mailbox for private messages
+    pub fn get(&self, id: u16) -> Option<(ClientInfo, mpsc::Sender<PrivateMessage>)> {
+        self.clients
+            .get(&id)
+            .map(|slot| (slot.info.clone(), slot.mailbox.clone()))
+    }
+    ///Updates the username of a connected client and announces it with a join event
+    pub fn rename(&self, info: ClientInfo) {
+        if let Some(mut slot) = self.clients.get_mut(&info.id()) {
+            slot.info = info.clone();
+            drop(slot);
+            self.events.send(ChatEvent::Joined(info));
+        }
+    }
+}
+
+///Keeps a client in the user list of its room
+pub struct PresenceGuard {
+    presence: Arc<Presence>,
+    id: u16,
+    connection: Arc<Connection>,
+}
+impl PresenceGuard {
+    ///Call whenever the client sends something
+    pub fn heartbeat(&self) {
+        *self.connection.last_heartbeat.lock().unwrap() = Instant::now();
+    }
+    pub fn last_heartbeat(&self) -> Instant {
+        *self.connection.last_heartbeat.lock().unwrap()
+    }
+    ///Stops or resumes the heartbeat eviction while the session is detached
+    pub fn set_detached(&self, detached: bool) {
+        self.connection.detached.store(detached, Ordering::Relaxed);
+    }
+    pub fn eviction(&self) -> Eviction {
+        Eviction(self.connection.clone())
+    }
+    ///False once the client was evicted
+    pub fn is_registered(&self) -> bool {
+        self.presence
+            .clients
+            .get(&self.id)
+            .is_some_and(|slot| Arc::ptr_eq(&slot.connection, &self.connection))
+    }
+}
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        self.presence.unregister(self.id, &self.connection);
+    }
+}
+
+///Completes when the client was evicted because it stopped answering pings
+pub struct Eviction(Arc<Connection>);
+impl Eviction {
+    pub async fn wait(&self) {
+        self.0.evicted.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{
+        client::ClientFactory,
+        queue::{Delivery, QueueReceiver},
+    };
+
+    fn presence(heartbeat_timeout: Duration) -> (Arc<Presence>, QueueReceiver<ChatEvent>) {
+        let events = Arc::new(Fanout::new(16));
+        let receiver = events.subscribe();
+        (Presence::new(events, heartbeat_timeout), receiver)
+    }
+
+    fn register(
+        presence: &Arc<Presence>,
+        id: u16,
+    ) -> Option<(PresenceGuard, mpsc::Receiver<PrivateMessage>)> {
+        let (mailbox, receiver) = mpsc::channel(1);
+        let guard = presence.register(ClientInfo::for_test(id, "alice"), mailbox)?;
+        Some((guard, receiver))
+    }
+
+    ///Ids of the joins (positive) and leaves (negative) that are queued
+    fn presence_events(receiver: &mut QueueReceiver<ChatEvent>) -> Vec<i32> {
+        receiver
+            .clear()
+            .into_iter()
+            .filter_map(|event| match event {
+                ChatEvent::Joined(info) => Some(info.id() as i32),
+                ChatEvent::Left(info) => Some(-(info.id() as i32)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn guard_keeps_client_in_the_list() {
+        let (presence, mut events) = presence(Duration::from_secs(60));
+        let (guard, _mailbox) = register(&presence, 1).unwrap();
+        assert!(register(&presence, 1).is_none());
+        assert!(presence.contains(1) && guard.is_registered());
+        assert_eq!(presence.count(), 1);
+
+        drop(guard);
+        assert!(!presence.contains(1));
+        assert_eq!(presence_events(&mut events), [1, -1]);
+    }
+
+    #[tokio::test]
+    async fn ghosts_are_removed() {
+        let (presence, mut events) = presence(Duration::from_secs(60));
+        let (_guard, mailbox) = register(&presence, 1).unwrap();
+        let (_kept, _kept_mailbox) = register(&presence, 2).unwrap();
+        // the socket is gone but the guard was leaked
+        drop(mailbox);
+        presence.reconcile();
+        assert!(!presence.contains(1) && presence.contains(2));
+        assert_eq!(presence_events(&mut events), [1, 2, -1]);
+    }
+
+    #[tokio::test]
+    async fn silent_connections_are_evicted() {
+        let (presence, mut events) = presence(Duration::from_millis(10));
+        let (guard, _mailbox) = register(&presence, 1).unwrap();
+        let (detached, _detached_mailbox) = register(&presence, 2).unwrap();
+        detached.set_detached(true);
+        let eviction = guard.eviction();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        presence.reconcile();
+
+        tokio::time::timeout(Duration::from_secs(1), eviction.wait())
+            .await
+            .expect("eviction wasn't signaled");
+        assert!(!guard.is_registered());
+        assert!(detached.is_registered());
+        // the id can be reused and dropping the old guard doesn't remove the new client
+        let (_new, _new_mailbox) = register(&presence, 1).unwrap();
+        drop(guard);
+        assert!(presence.contains(1));
+        assert_eq!(presence_events(&mut events), [1, 2, -1, 1]);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_prevents_eviction() {
+        let (presence, mut events) = presence(Duration::from_millis(50));
+        let (guard, _mailbox) = register(&presence, 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        guard.heartbeat();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        presence.reconcile();
+        assert!(guard.is_registered());
+        assert!(matches!(
+            events.recv().await,
+            Some(Delivery::Event(ChatEvent::Joined(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reserved_ids_skip_clients_in_the_room() {
+        let (presence, _events) = presence(Duration::from_secs(60));
+        let factory = ClientFactory::new();
+        let (_first, _first_mailbox) = register(&presence, 1).unwrap();
+        let (_second, _second_mailbox) = register(&presence, 2).unwrap();
+        assert_eq!(factory.reserve_id(&presence), Some(3));
+        assert_eq!(factory.reserve_id(&presence), Some(4));
+    }
+}
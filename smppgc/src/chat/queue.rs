@@ -0,0 +1,237 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use lmetrics::metrics;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Notify,
+};
+
+metrics! {
+    pub gauge client_queue_depth("Events waiting in the queues of the clients", []);
+    pub counter client_queue_overflows_total("Clients whose queue overflowed by what was done with them", [policy]);
+}
+
+///Set by the sender when the queue is full
+#[derive(Default)]
+struct Overflow {
+    overflowed: AtomicBool,
+    notify: Notify,
+}
+
+///Bounded queue of a single client. Unlike a broadcast channel a slow client can't silently miss
+///events: when its queue is full the queue is marked as overflowed and the client has to be
+///disconnected or resynced.
+pub fn channel<T>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    let overflow = Arc::new(Overflow::default());
+    (
+        QueueSender {
+            sender,
+            overflow: overflow.clone(),
+        },
+        QueueReceiver { receiver, overflow },
+    )
+}
+
+pub struct QueueSender<T> {
+    sender: mpsc::Sender<T>,
+    overflow: Arc<Overflow>,
+}
+impl<T> QueueSender<T> {
+    ///Never waits. Marks the queue as overflowed and drops the event when the queue is full.
+    ///Returns false when the receiver is gone.
+    pub fn send(&self, event: T) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => {
+                client_queue_depth::inc();
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if !self.overflow.overflowed.swap(true, Ordering::AcqRel) {
+                    self.overflow.notify.notify_one();
+                }
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+pub enum Delivery<T> {
+    Event(T),
+    ///Events were dropped. Returned until [QueueReceiver::clear] is called.
+    Overflowed,
+}
+
+pub struct QueueReceiver<T> {
+    receiver: mpsc::Receiver<T>,
+    overflow: Arc<Overflow>,
+}
+impl<T> QueueReceiver<T> {
+    ///Waits for the next event, an overflow is returned before the events that are still queued.
+    ///Returns None when every sender is gone.
+    pub async fn recv(&mut self) -> Option<Delivery<T>> {
+        loop {
+            if self.overflow.overflowed.load(Ordering::Acquire) {
+                return Some(Delivery::Overflowed);
+            }
+            tokio::select! {
+                biased;
+                // the permit can be left over from an overflow that is already cleared
+                _ = self.overflow.notify.notified() => continue,
+                event = self.receiver.recv() => {
+                    let event = event?;
+                    client_queue_depth::dec();
+                    return Some(Delivery::Event(event));
+                }
+            }
+        }
+    }
+
+    ///True when events were dropped since the last [QueueReceiver::clear]
+    pub fn is_overflowed(&self) -> bool {
+        self.overflow.overflowed.load(Ordering::Acquire)
+    }
+
+    ///Discards the queued events and resets the overflow. Returns the discarded events.
+    pub fn clear(&mut self) -> Vec<T> {
+        let mut discarded = Vec::with_capacity(self.receiver.len());
+        while let Ok(event) = self.receiver.try_recv() {
+            discarded.push(event);
+        }
+        client_queue_depth::add(-(discarded.len() as i64));
+        self.overflow.overflowed.store(false, Ordering::Release);
+        discarded
+    }
+}
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        // closing first so no event is queued after counting
+        self.receiver.close();
+        let mut remaining = 0;
+        while self.receiver.try_recv().is_ok() {
+            remaining += 1;
+        }
+        client_queue_depth::add(-remaining);
+    }
+}
+
+///Sends every event to the queue of every subscriber
+pub struct Fanout<T> {
+    queues: Mutex<Vec<QueueSender<T>>>,
+    capacity: usize,
+}
+impl<T: Clone> Fanout<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queues: Mutex::new(Vec::new()),
+            capacity,
+        }
+    }
+
+    pub fn subscribe(&self) -> QueueReceiver<T> {
+        let (sender, receiver) = channel(self.capacity);
+        self.queues.lock().unwrap().push(sender);
+        receiver
+    }
+
+    ///Queues the event for every subscriber and forgets the subscribers that are gone
+    pub fn send(&self, event: T) {
+        self.queues
+            .lock()
+            .unwrap()
+            .retain(|queue| queue.send(event.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn recv(receiver: &mut QueueReceiver<u32>) -> Option<Delivery<u32>> {
+        tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .expect("recv didn't return")
+    }
+
+    #[tokio::test]
+    async fn events_arrive_in_order() {
+        let (sender, mut receiver) = channel(4);
+        for event in 0..4 {
+            assert!(sender.send(event));
+        }
+        for event in 0..4 {
+            assert!(matches!(recv(&mut receiver).await, Some(Delivery::Event(e)) if e == event));
+        }
+        drop(sender);
+        assert!(recv(&mut receiver).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn overflow_is_returned_until_cleared() {
+        let (sender, mut receiver) = channel(2);
+        for event in 0..5 {
+            assert!(sender.send(event));
+        }
+        assert!(receiver.is_overflowed());
+        // the overflow comes before the events that are still queued
+        assert!(matches!(
+            recv(&mut receiver).await,
+            Some(Delivery::Overflowed)
+        ));
+        assert!(matches!(
+            recv(&mut receiver).await,
+            Some(Delivery::Overflowed)
+        ));
+
+        assert_eq!(receiver.clear(), [0, 1]);
+        assert!(!receiver.is_overflowed());
+        assert!(sender.send(5));
+        assert!(matches!(
+            recv(&mut receiver).await,
+            Some(Delivery::Event(5))
+        ));
+        // the permit left over from the overflow doesn't return anything
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), receiver.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn fanout_overflows_only_slow_subscribers() {
+        let fanout = Fanout::new(2);
+        let mut fast = fanout.subscribe();
+        let mut slow = fanout.subscribe();
+        for event in 0..3 {
+            fanout.send(event);
+            assert!(matches!(recv(&mut fast).await, Some(Delivery::Event(e)) if e == event));
+        }
+        assert!(!fast.is_overflowed());
+        assert!(slow.is_overflowed());
+        assert!(matches!(recv(&mut slow).await, Some(Delivery::Overflowed)));
+        // a resync discards what was queued and continues with the next event
+        slow.clear();
+        fanout.send(3);
+        assert!(matches!(recv(&mut slow).await, Some(Delivery::Event(3))));
+        assert!(matches!(recv(&mut fast).await, Some(Delivery::Event(3))));
+    }
+
+    #[tokio::test]
+    async fn fanout_forgets_dropped_subscribers() {
+        let fanout = Fanout::new(2);
+        let kept = fanout.subscribe();
+        drop(fanout.subscribe());
+        fanout.send(0);
+        assert_eq!(fanout.queues.lock().unwrap().len(), 1);
+        drop(kept);
+        fanout.send(1);
+        assert!(fanout.queues.lock().unwrap().is_empty());
+    }
+}
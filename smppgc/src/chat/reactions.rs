@@ -0,0 +1,61 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::names::UserId;
+
+pub use smppgc_proto::ReactionCount;
+
+///Reactions users can add to messages. Clients refer to them by index.
+pub const REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🔥"];
+
+///Who reacted what on the recent messages of a room. Reactions are only kept in memory.
+#[derive(Default)]
+pub struct Reactions {
+    messages: HashMap<u32, [HashSet<UserId>; REACTIONS.len()]>,
+}
+impl Reactions {
+    ///Returns false when the user already added this reaction
+    pub fn add(&mut self, message_id: u32, reaction: u8, user_id: UserId) -> bool {
+        self.messages.entry(message_id).or_default()[reaction as usize].insert(user_id)
+    }
+
+    ///Returns false when the user didn't add this reaction
+    pub fn remove(&mut self, message_id: u32, reaction: u8, user_id: &UserId) -> bool {
+        let Some(reactions) = self.messages.get_mut(&message_id) else {
+            return false;
+        };
+        let removed = reactions[reaction as usize].remove(user_id);
+        if reactions.iter().all(HashSet::is_empty) {
+            self.messages.remove(&message_id);
+        }
+        removed
+    }
+
+    pub fn remove_message(&mut self, message_id: u32) {
+        self.messages.remove(&message_id);
+    }
+
+    ///Forgets the reactions on messages before `oldest_id`
+    pub fn prune(&mut self, oldest_id: u32) {
+        self.messages
+            .retain(|message_id, _| *message_id >= oldest_id);
+    }
+
+    ///Returns the count of every reaction that was added at least once
+    pub fn counts(&self, user_id: &UserId) -> Vec<ReactionCount> {
+        let mut counts = Vec::new();
+        for (message_id, reactions) in &self.messages {
+            for (reaction, users) in reactions.iter().enumerate() {
+                if users.is_empty() {
+                    continue;
+                }
+                counts.push(ReactionCount {
+                    message_id: *message_id,
+                    reaction: reaction as u8,
+                    count: users.len().min(u16::MAX as usize) as u16,
+                    own: users.contains(user_id),
+                });
+            }
+        }
+        counts
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use lmetrics::metrics;
+use log::*;
+use thiserror::Error;
+
+use super::{
+    history::{FileHistoryStore, HistoryStore},
+    Chat, Kick, Severity, SystemMessage,
+};
+use crate::{moderation::Moderation, ChatConfig, RoomsConfig};
+
+metrics! {
+    pub counter rooms_opened_total("Total opened chat rooms", []);
+    pub counter rooms_closed_total("Total chat rooms closed because they were idle", []);
+}
+
+#[derive(Debug, Error)]
+pub enum RoomError {
+    #[error("Ongeldige chatroom.")]
+    InvalidName,
+    #[error("Te veel chatrooms.")]
+    MaxRoomCount,
+}
+
+struct RoomSlot {
+    chat: Arc<Chat>,
+    idle_since: Option<Instant>,
+}
+
+///Registry of named chat rooms. Rooms are created on first join and closed after being idle for
+///`room_idle_timeout` seconds. The default room is never closed.
+pub struct ChatRooms {
+    rooms: Arc<DashMap<Arc<str>, RoomSlot>>,
+    chat_config: ChatConfig,
+    config: RoomsConfig,
+    moderation: Arc<Moderation>,
+}
+impl ChatRooms {
+    pub fn new(chat_config: ChatConfig, config: RoomsConfig, moderation: Arc<Moderation>) -> Self {
+        let rooms = Arc::new(DashMap::new());
+        Self::spawn_reaper(
+            Arc::downgrade(&rooms),
+            config.default_room.as_str().into(),
+            Duration::from_secs(config.room_idle_timeout),
+        );
+        Self {
+            rooms,
+            chat_config,
+            config,
+            moderation,
+        }
+    }
+
+    fn spawn_reaper(
+        rooms: Weak<DashMap<Arc<str>, RoomSlot>>,
+        default_room: Arc<str>,
+        idle_timeout: Duration,
+    ) {
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(idle_timeout.max(Duration::from_secs(1)) / 2);
+            loop {
+                interval.tick().await;
+                let Some(rooms) = rooms.upgrade() else {
+                    return;
+                };
+                rooms.retain(|name, slot| {
+                    // Every connected socket holds a reference to its room.
+                    if *name == default_room || Arc::strong_count(&slot.chat) > 1 {
+                        slot.idle_since = None;
+                        return true;
+                    }
+                    let idle_since = *slot.idle_since.get_or_insert_with(Instant::now);
+                    if idle_since.elapsed() < idle_timeout {
+                        return true;
+                    }
+                    info!("Closing idle room {}", name);
+                    rooms_closed_total::inc();
+                    false
+                });
+            }
+        });
+    }
+
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= 32
+            && name
+                .chars()
+                .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+    }
+
+    ///Returns the room with the given name (or the default room) and creates it if it doesn't exist
+    pub fn get_or_create(&self, name: Option<&str>) -> Result<Arc<Chat>, RoomError> {
+        let name = name.unwrap_or(&self.config.default_room).to_lowercase();
+        if !Self::is_valid_name(&name) {
+            return Err(RoomError::InvalidName);
+        }
+        if let Some(slot) = self.rooms.get(name.as_str()) {
+            return Ok(slot.chat.clone());
+        }
+        if self.config.max_rooms != 0 && self.rooms.len() >= self.config.max_rooms {
+            return Err(RoomError::MaxRoomCount);
+        }
+
+        let slot = self.rooms.entry(name.as_str().into()).or_insert_with(|| {
+            info!("Opening room {}", name);
+            rooms_opened_total::inc();
+            let config = self.room_config(&name);
+            let store = config.history_dir.as_ref().map(|dir| {
+                FileHistoryStore::new(dir.join(format!("{}.hist", name))) as Arc<dyn HistoryStore>
+            });
+            RoomSlot {
+                chat: Arc::new(Chat::new(config, store, self.moderation.clone())),
+                idle_since: None,
+            }
+        });
+        Ok(slot.chat.clone())
+    }
+
+    ///Sends a system message to every client in every room
+    pub fn broadcast_system(&self, severity: Severity, text: &str) {
+        let text: Arc<str> = text.into();
+        for slot in self.rooms.iter() {
+            slot.chat.send_system(SystemMessage {
+                severity,
+                text: text.clone(),
+            });
+        }
+    }
+
+    ///Kicks the matching clients from every room
+    pub fn kick(&self, kick: Kick) {
+        for slot in self.rooms.iter() {
+            slot.chat.kick(kick.clone());
+        }
+    }
+
+    fn room_config(&self, name: &str) -> ChatConfig {
+        match self.config.rooms.get(name) {
+            Some(config_override) => self.chat_config.with_override(config_override),
+            None => self.chat_config.clone(),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use lmetrics::metrics;
+use log::*;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use super::{client::ClientInfo, presence::PresenceGuard, ChatEvents, PrivateMessage};
+use crate::names::UserId;
+
+metrics! {
+    pub counter detached_sessions_total("Sessions kept after their connection dropped by how they ended", [outcome]);
+}
+
+///What is left of a client after its connection dropped. Keeps the client in the user list and
+///keeps queueing the events of the room until it is resumed or expires.
+pub struct DetachedSession {
+    pub info: ClientInfo,
+    pub user_id: UserId,
+    ///Key of the setup packet
+    pub token: Arc<str>,
+    pub presence: PresenceGuard,
+    pub events: ChatEvents,
+    pub mailbox: mpsc::Receiver<PrivateMessage>,
+    pub last_seen_id: Option<u32>,
+}
+
+///Detached sessions of a room by resume token
+pub struct Sessions {
+    detached: DashMap<Arc<str>, DetachedSession>,
+    grace: Duration,
+}
+impl Sessions {
+    pub fn new(grace: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            detached: DashMap::new(),
+            grace,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.grace.is_zero()
+    }
+
+    ///Token a client passes when reconnecting. Every connection gets a new one, so a token can be
+    ///used only once.
+    pub fn new_token() -> Arc<str> {
+        Uuid::new_v4().simple().to_string().into()
+    }
+
+    ///Keeps the session for the grace period. The client leaves the room when it expires.
+    pub fn detach(self: &Arc<Self>, token: Arc<str>, session: DetachedSession) {
+        debug!("Detached session of client {}", session.info.id());
+        session.presence.set_detached(true);
+        self.detached.insert(token.clone(), session);
+        let sessions: Weak<Self> = Arc::downgrade(self);
+        let grace = self.grace;
+        tokio::task::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let Some(sessions) = sessions.upgrade() else {
+                return;
+            };
+            if let Some((_, session)) = sessions.detached.remove(&token) {
+                debug!("Session of client {} expired", session.info.id());
+                detached_sessions_total::inc("expired");
+            }
+        });
+    }
+
+    ///Removes the session so a new connection can continue it. Only the user that owns the
+    ///session can take it.
+    pub fn take(&self, token: &str, user_id: &UserId) -> Option<DetachedSession> {
+        let (_, session) = self
+            .detached
+            .remove_if(token, |_, session| session.user_id == *user_id)?;
+        // evicted while detached, its leave is already announced
+        if !session.presence.is_registered() {
+            detached_sessions_total::inc("evicted");
+            return None;
+        }
+        detached_sessions_total::inc("resumed");
+        Some(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{
+        presence::Presence,
+        queue::{Fanout, QueueReceiver},
+        ChatEvent,
+    };
+
+    struct Room {
+        presence: Arc<Presence>,
+        events: Arc<Fanout<ChatEvent>>,
+        ///Receives the events of the other clients in the room
+        observer: QueueReceiver<ChatEvent>,
+    }
+    impl Room {
+        fn new() -> Self {
+            let events = Arc::new(Fanout::new(16));
+            Self {
+                presence: Presence::new(events.clone(), Duration::from_secs(60)),
+                observer: events.subscribe(),
+                events,
+            }
+        }
+
+        fn session(&self, id: u16, user_id: &UserId) -> DetachedSession {
+            let (mailbox_sender, mailbox) = mpsc::channel(1);
+            let info = ClientInfo::for_test(id, "alice");
+            let presence = self
+                .presence
+                .register(info.clone(), mailbox_sender)
+                .unwrap();
+            // the sender lives in the presence slot, like it does for a real client
+            DetachedSession {
+                info,
+                user_id: user_id.clone(),
+                token: "sleutel".into(),
+                presence,
+                events: self.events.subscribe(),
+                mailbox,
+                last_seen_id: None,
+            }
+        }
+
+        fn left(&mut self) -> Vec<u16> {
+            self.observer
+                .clear()
+                .into_iter()
+                .filter_map(|event| match event {
+                    ChatEvent::Left(info) => Some(info.id()),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_take_a_session_once() {
+        let mut room = Room::new();
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let owner = UserId::new();
+        let token = Sessions::new_token();
+        sessions.detach(token.clone(), room.session(1, &owner));
+
+        assert!(sessions.take(&token, &UserId::new()).is_none());
+        assert!(sessions.take("onbekend", &owner).is_none());
+        let session = sessions.take(&token, &owner).unwrap();
+        assert_eq!(session.info.id(), 1);
+        assert!(sessions.take(&token, &owner).is_none());
+        // the client never left the room
+        assert!(room.presence.contains(1));
+        assert!(room.left().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_leave_the_room() {
+        let mut room = Room::new();
+        let sessions = Sessions::new(Duration::from_millis(20));
+        let owner = UserId::new();
+        let token = Sessions::new_token();
+        sessions.detach(token.clone(), room.session(1, &owner));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // resuming after the grace period fails
+        assert!(sessions.take(&token, &owner).is_none());
+        assert!(!room.presence.contains(1));
+        assert_eq!(room.left(), [1]);
+    }
+
+    #[tokio::test]
+    async fn evicted_sessions_cant_be_resumed() {
+        let mut room = Room::new();
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let owner = UserId::new();
+        let token = Sessions::new_token();
+        let session = room.session(1, &owner);
+        // without its mailbox the reconciler takes the session for a ghost
+        drop(session.mailbox);
+        let session = DetachedSession {
+            mailbox: mpsc::channel(1).1,
+            ..session
+        };
+        sessions.detach(token.clone(), session);
+        room.presence.reconcile();
+
+        assert!(sessions.take(&token, &owner).is_none());
+        assert_eq!(room.left(), [1]);
+    }
+
+    #[test]
+    fn tokens_are_unique() {
+        assert_ne!(Sessions::new_token(), Sessions::new_token());
+        assert!(!Sessions::new(Duration::ZERO).enabled());
+    }
+}
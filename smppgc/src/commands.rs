@@ -0,0 +1,685 @@
+use std::{sync::Arc, time::Duration};
+
+use rocket::{async_trait, fairing::AdHoc};
+use thiserror::Error;
+
+use crate::{
+    chat::{
+        client::{Client, ClientInfo},
+        rooms::ChatRooms,
+        Chat, Kick, KickTarget, PrivateMessage, Severity,
+    },
+    mesg_filter::Cmd,
+    moderation::{BanTarget, Moderation},
+    names::UsernameManager,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    User,
+    Moderator,
+}
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("Onbekend commando: /{0}. Typ /help voor een lijst van commando's.")]
+    Unknown(String),
+    #[error("Ongeldige argumenten.")]
+    InvalidArgs,
+    #[error("Je hebt geen toestemming voor dit commando.")]
+    PermissionDenied,
+    #[error("{0}")]
+    Failed(String),
+    #[error("Socket error: {0}")]
+    Socket(Box<rocket_ws::result::Error>),
+}
+impl From<rocket_ws::result::Error> for CommandError {
+    fn from(err: rocket_ws::result::Error) -> Self {
+        CommandError::Socket(Box::new(err))
+    }
+}
+
+///Longest mute or ban in minutes (a year), leave out the duration to ban forever
+const MAX_MINUTES: u64 = 60 * 24 * 365;
+
+pub enum CommandOutcome {
+    Continue,
+    Disconnect,
+}
+
+///Whitespace separated command arguments
+pub struct Args<'a>(&'a str);
+impl<'a> Args<'a> {
+    pub fn new(args: &'a str) -> Self {
+        Self(args.trim())
+    }
+    ///Returns the next word
+    pub fn next_word(&mut self) -> Result<&'a str, CommandError> {
+        if self.0.is_empty() {
+            return Err(CommandError::InvalidArgs);
+        }
+        let (word, rest) = self
+            .0
+            .split_once(char::is_whitespace)
+            .unwrap_or((self.0, ""));
+        self.0 = rest.trim_start();
+        Ok(word)
+    }
+    ///Returns everything that is left
+    pub fn rest(self) -> Result<&'a str, CommandError> {
+        if self.0.is_empty() {
+            return Err(CommandError::InvalidArgs);
+        }
+        Ok(self.0)
+    }
+    pub fn end(self) -> Result<(), CommandError> {
+        if !self.0.is_empty() {
+            return Err(CommandError::InvalidArgs);
+        }
+        Ok(())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    ///Returns the next word as a duration in minutes, at most [MAX_MINUTES]
+    pub fn next_minutes(&mut self) -> Result<Duration, CommandError> {
+        let minutes: u64 = self
+            .next_word()?
+            .parse()
+            .map_err(|_| CommandError::InvalidArgs)?;
+        if minutes == 0 || minutes > MAX_MINUTES {
+            return Err(CommandError::InvalidArgs);
+        }
+        Ok(Duration::from_secs(minutes * 60))
+    }
+}
+
+pub struct CommandContext<'a> {
+    pub client: &'a mut Client,
+    pub chat: &'a Chat,
+    pub rooms: &'a ChatRooms,
+    pub usernames: &'a UsernameManager,
+    pub moderation: &'a Moderation,
+    pub registry: &'a CommandRegistry,
+    pub permission: &'a mut Permission,
+    ///Silently drop all messages of this client
+    pub blockme: &'a mut bool,
+}
+impl CommandContext<'_> {
+    ///Sends a system message to the client that issued the command
+    pub async fn reply(&mut self, text: &str) -> Result<(), CommandError> {
+        self.client.send_system(Severity::Info, text).await?;
+        Ok(())
+    }
+
+    ///Finds a client in the room by id or username
+    pub fn find_client(&self, name_or_id: &str) -> Result<ClientInfo, CommandError> {
+        let clients = self.chat.clients();
+        let id = name_or_id.parse::<u16>().ok();
+        clients
+            .into_iter()
+            .find(|client| {
+                Some(client.id()) == id || client.username().eq_ignore_ascii_case(name_or_id)
+            })
+            .ok_or_else(|| CommandError::Failed(format!("{} is niet online.", name_or_id)))
+    }
+
+    ///Fails when the client is muted
+    pub fn check_muted(&self) -> Result<(), CommandError> {
+        match self.moderation.muted_for(self.client.user_id()) {
+            Some(remaining) => Err(CommandError::Failed(muted_text(remaining))),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn muted_text(remaining: Duration) -> String {
+    if remaining == Duration::MAX {
+        return "Je bent gedempt. Je kan geen berichten meer sturen.".to_string();
+    }
+    format!(
+        "Je bent gedempt. Je kan nog {} minuten geen berichten sturen.",
+        remaining.as_secs().div_ceil(60)
+    )
+}
+
+#[async_trait]
+pub trait Command: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn usage(&self) -> &'static str;
+    fn help(&self) -> &'static str;
+    fn permission(&self) -> Permission {
+        Permission::User
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError>;
+}
+
+pub struct CommandRegistry {
+    commands: Vec<Box<dyn Command>>,
+}
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl CommandRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            commands: Vec::new(),
+        };
+        registry.register(Help);
+        registry.register(Me);
+        registry.register(Nick);
+        registry.register(Who);
+        registry.register(Msg);
+        registry.register(KillMe);
+        registry.register(BlockMe);
+        registry.register(Mod);
+        registry.register(KickCmd);
+        registry.register(Mute);
+        registry.register(Unmute);
+        registry.register(Ban);
+        registry.register(BanIp);
+        registry.register(Unban);
+        registry
+    }
+
+    pub fn register(&mut self, command: impl Command + 'static) {
+        self.commands.push(Box::new(command));
+    }
+
+    pub fn find(&self, name: &str) -> Option<&dyn Command> {
+        self.commands
+            .iter()
+            .find(|command| command.name().eq_ignore_ascii_case(name))
+            .map(|command| command.as_ref())
+    }
+
+    pub fn available(&self, permission: Permission) -> impl Iterator<Item = &dyn Command> {
+        self.commands
+            .iter()
+            .filter(move |command| command.permission() <= permission)
+            .map(|command| command.as_ref())
+    }
+
+    ///Runs the command and replies with the error when it fails
+    pub async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        cmd: Cmd,
+    ) -> rocket_ws::result::Result<CommandOutcome> {
+        let result = match self.find(&cmd.name) {
+            None => Err(CommandError::Unknown(cmd.name)),
+            Some(command) if command.permission() > *ctx.permission => {
+                Err(CommandError::PermissionDenied)
+            }
+            Some(command) => match command.run(ctx, Args::new(&cmd.args)).await {
+                Err(CommandError::InvalidArgs) => Err(CommandError::Failed(format!(
+                    "Gebruik: {}",
+                    command.usage()
+                ))),
+                result => result,
+            },
+        };
+        match result {
+            Ok(outcome) => Ok(outcome),
+            Err(CommandError::Socket(err)) => Err(*err),
+            Err(err) => {
+                ctx.client
+                    .send_system(Severity::Error, &err.to_string())
+                    .await?;
+                Ok(CommandOutcome::Continue)
+            }
+        }
+    }
+}
+
+struct Help;
+#[async_trait]
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn usage(&self) -> &'static str {
+        "/help"
+    }
+    fn help(&self) -> &'static str {
+        "Toon deze lijst."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        args.end()?;
+        let mut text = String::from("Commando's:");
+        for command in ctx.registry.available(*ctx.permission) {
+            text.push_str(&format!("\n{} - {}", command.usage(), command.help()));
+        }
+        ctx.reply(&text).await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Me;
+#[async_trait]
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+    fn usage(&self) -> &'static str {
+        "/me <actie>"
+    }
+    fn help(&self) -> &'static str {
+        "Beschrijf wat je doet."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let action = args.rest()?;
+        ctx.check_muted()?;
+        if *ctx.blockme {
+            return Ok(CommandOutcome::Continue);
+        }
+        let mesg = ctx
+            .client
+            .new_message(format!("* {} {}", ctx.client.client_info().username(), action).into());
+        ctx.chat.send_message(mesg, ctx.client.user_id()).await;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Nick;
+#[async_trait]
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+    fn usage(&self) -> &'static str {
+        "/nick <naam>"
+    }
+    fn help(&self) -> &'static str {
+        "Verander je naam."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let name = args.rest()?;
+        let name = ctx
+            .usernames
+            .claim_name(name, ctx.client.user_id().clone())
+            .map_err(|err| CommandError::Failed(err.to_string()))?;
+        ctx.client.set_username(name);
+        let info = ctx.client.client_info();
+        ctx.chat.rename_client(info.clone());
+        ctx.reply(&format!("Je heet nu {}.", info.username()))
+            .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Who;
+#[async_trait]
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+    fn usage(&self) -> &'static str {
+        "/who"
+    }
+    fn help(&self) -> &'static str {
+        "Toon wie er online is."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        args.end()?;
+        let clients = ctx.chat.clients();
+        let names: Vec<&str> = clients.iter().map(|client| client.username()).collect();
+        ctx.reply(&format!("Online ({}): {}", names.len(), names.join(", ")))
+            .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Msg;
+#[async_trait]
+impl Command for Msg {
+    fn name(&self) -> &'static str {
+        "msg"
+    }
+    fn usage(&self) -> &'static str {
+        "/msg <naam> <bericht>"
+    }
+    fn help(&self) -> &'static str {
+        "Stuur een privébericht."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        mut args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let recipient = ctx.find_client(args.next_word()?)?;
+        let content = args.rest()?;
+        ctx.check_muted()?;
+        let mesg = ctx.client.new_message(content.into());
+        if *ctx.blockme {
+            // pretend it was delivered
+            let private = PrivateMessage {
+                mesg,
+                recipient: recipient.id(),
+            };
+            ctx.client.forward_private(&private).await?;
+            return Ok(CommandOutcome::Continue);
+        }
+        let private = ctx
+            .chat
+            .send_private(mesg, recipient.id())
+            .map_err(|err| CommandError::Failed(err.to_string()))?;
+        ctx.client.forward_private(&private).await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct KillMe;
+#[async_trait]
+impl Command for KillMe {
+    fn name(&self) -> &'static str {
+        "killme"
+    }
+    fn usage(&self) -> &'static str {
+        "/killme"
+    }
+    fn help(&self) -> &'static str {
+        "Verbreek de verbinding."
+    }
+    async fn run(
+        &self,
+        _ctx: &mut CommandContext<'_>,
+        _args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        Ok(CommandOutcome::Disconnect)
+    }
+}
+
+struct BlockMe;
+#[async_trait]
+impl Command for BlockMe {
+    fn name(&self) -> &'static str {
+        "blockme"
+    }
+    fn usage(&self) -> &'static str {
+        "/blockme"
+    }
+    fn help(&self) -> &'static str {
+        "Niemand ziet je berichten nog."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        _args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        *ctx.blockme = true;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Mod;
+#[async_trait]
+impl Command for Mod {
+    fn name(&self) -> &'static str {
+        "mod"
+    }
+    fn usage(&self) -> &'static str {
+        "/mod <geheim>"
+    }
+    fn help(&self) -> &'static str {
+        "Word moderator."
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        if !ctx.moderation.check_secret(args.rest()?) {
+            return Err(CommandError::Failed("Ongeldig geheim.".to_string()));
+        }
+        *ctx.permission = Permission::Moderator;
+        ctx.reply("Je bent nu moderator. Typ /help voor de moderator commando's.")
+            .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct KickCmd;
+#[async_trait]
+impl Command for KickCmd {
+    fn name(&self) -> &'static str {
+        "kick"
+    }
+    fn usage(&self) -> &'static str {
+        "/kick <naam>"
+    }
+    fn help(&self) -> &'static str {
+        "Zet iemand uit de chat."
+    }
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        mut args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let target = ctx.find_client(args.next_word()?)?;
+        args.end()?;
+        ctx.chat.kick(Kick {
+            target: KickTarget::Client(target.id()),
+            reason: "Je bent uit de chat gezet door een moderator.",
+        });
+        ctx.reply(&format!("{} is uit de chat gezet.", target.username()))
+            .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Mute;
+#[async_trait]
+impl Command for Mute {
+    fn name(&self) -> &'static str {
+        "mute"
+    }
+    fn usage(&self) -> &'static str {
+        "/mute <naam> <minuten>"
+    }
+    fn help(&self) -> &'static str {
+        "Demp iemand een tijdje."
+    }
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        mut args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let target = ctx.find_client(args.next_word()?)?;
+        let duration = args.next_minutes()?;
+        args.end()?;
+        ctx.moderation.mute(target.user_id().clone(), duration);
+        ctx.reply(&format!(
+            "{} is gedempt voor {} minuten.",
+            target.username(),
+            duration.as_secs() / 60
+        ))
+        .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+struct Unmute;
+#[async_trait]
+impl Command for Unmute {
+    fn name(&self) -> &'static str {
+        "unmute"
+    }
+    fn usage(&self) -> &'static str {
+        "/unmute <naam>"
+    }
+    fn help(&self) -> &'static str {
+        "Hef een demping op."
+    }
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let target = ctx.find_client(args.rest()?)?;
+        if !ctx.moderation.unmute(target.user_id()) {
+            return Err(CommandError::Failed(format!(
+                "{} is niet gedempt.",
+                target.username()
+            )));
+        }
+        ctx.reply(&format!("{} is niet meer gedempt.", target.username()))
+            .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+///Bans the client and kicks it from every room
+async fn ban(
+    ctx: &mut CommandContext<'_>,
+    mut args: Args<'_>,
+    to_target: fn(&ClientInfo) -> Option<BanTarget>,
+) -> Result<CommandOutcome, CommandError> {
+    let client = ctx.find_client(args.next_word()?)?;
+    let duration = if args.is_empty() {
+        None
+    } else {
+        Some(args.next_minutes()?)
+    };
+    args.end()?;
+    let target = to_target(&client).ok_or_else(|| {
+        CommandError::Failed(format!("Het ip van {} is onbekend.", client.username()))
+    })?;
+    ctx.moderation.ban(target.clone(), duration);
+    ctx.rooms.kick(Kick {
+        target: KickTarget::Ban(target.clone()),
+        reason: "Je bent verbannen.",
+    });
+    let text = match duration {
+        Some(duration) => format!(
+            "{} ({}) is verbannen voor {} minuten.",
+            client.username(),
+            target,
+            duration.as_secs() / 60
+        ),
+        None => format!("{} ({}) is verbannen.", client.username(), target),
+    };
+    ctx.reply(&text).await?;
+    Ok(CommandOutcome::Continue)
+}
+
+struct Ban;
+#[async_trait]
+impl Command for Ban {
+    fn name(&self) -> &'static str {
+        "ban"
+    }
+    fn usage(&self) -> &'static str {
+        "/ban <naam> [minuten]"
+    }
+    fn help(&self) -> &'static str {
+        "Verban iemand (voor altijd als er geen tijd gegeven is)."
+    }
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        ban(ctx, args, |client| {
+            Some(BanTarget::User(client.user_id().clone()))
+        })
+        .await
+    }
+}
+
+struct BanIp;
+#[async_trait]
+impl Command for BanIp {
+    fn name(&self) -> &'static str {
+        "banip"
+    }
+    fn usage(&self) -> &'static str {
+        "/banip <naam> [minuten]"
+    }
+    fn help(&self) -> &'static str {
+        "Verban het ip van iemand."
+    }
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        ban(ctx, args, |client| client.ip().map(BanTarget::Ip)).await
+    }
+}
+
+struct Unban;
+#[async_trait]
+impl Command for Unban {
+    fn name(&self) -> &'static str {
+        "unban"
+    }
+    fn usage(&self) -> &'static str {
+        "/unban <sleutel|ip>"
+    }
+    fn help(&self) -> &'static str {
+        "Hef een ban op."
+    }
+    fn permission(&self) -> Permission {
+        Permission::Moderator
+    }
+    async fn run(
+        &self,
+        ctx: &mut CommandContext<'_>,
+        args: Args<'_>,
+    ) -> Result<CommandOutcome, CommandError> {
+        let target = BanTarget::parse_str(args.rest()?).ok_or(CommandError::InvalidArgs)?;
+        if !ctx.moderation.unban(&target) {
+            return Err(CommandError::Failed(format!(
+                "{} is niet verbannen.",
+                target
+            )));
+        }
+        ctx.reply(&format!("{} is niet meer verbannen.", target))
+            .await?;
+        Ok(CommandOutcome::Continue)
+    }
+}
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("commands", |r| async {
+        r.manage(Arc::new(CommandRegistry::new()))
+    })
+}
@@ -3,7 +3,7 @@ use rocket::{fairing::AdHoc, get, response::Redirect, routes};
 #[get("/reload_js")]
 fn reload_js() -> Redirect {
     std::process::Command::new("smppgc/gen_js.sh")
-        .spawn()
+        .status()
         .unwrap();
     Redirect::temporary("/v1")
 }
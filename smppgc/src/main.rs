@@ -1,21 +1,25 @@
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
 
-use chat::Chat;
+use chat::{rooms::ChatRooms, Severity};
 use lmetrics::LMetrics;
+use moderation::Moderation;
 use rocket::get;
 use rocket::response::Redirect;
 use rocket::routes;
 use rocket::serde::Deserialize;
 use rocket::{fairing::AdHoc, launch};
-use tokio::sync::Mutex;
 use utils::static_routing;
 
+mod admin;
 pub mod chat;
+mod commands;
 #[cfg(debug_assertions)]
 mod debug;
 mod mesg_filter;
+mod moderation;
 pub mod names;
 pub mod profanity;
 pub mod socket;
@@ -28,14 +32,110 @@ pub struct RateLimitConfig {
     pub min_message_time_hard: isize,
     pub min_message_time_soft: isize,
     pub kick_burst: isize,
+    ///Burst at which the client is warned that it will be kicked soon
+    pub warn_burst: isize,
+    ///Minimum time (in ms) between two typing signals. Faster signals are dropped.
+    pub min_typing_time: u64,
+    ///Minimum time (in ms) between two history requests. Faster requests get an empty reply.
+    pub min_history_time: u64,
 }
 
-#[derive(Deserialize, Debug)]
+///What happens to a client that can't keep up with the events of its room
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum SlowClientPolicy {
+    ///Close the connection
+    Disconnect,
+    ///Throw away the queued events and send a new setup packet. Clients that can't handle a
+    ///second setup packet are disconnected.
+    Resync,
+}
+
+#[derive(Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct ChatConfig {
     pub max_stored_messages: usize,
     pub max_users: u16,
     pub rate_limit: RateLimitConfig,
+    ///Directory where the history of every room is stored. History is only kept in memory when unset.
+    pub history_dir: Option<PathBuf>,
+    ///How long (in seconds) stored messages are kept. 0 keeps them forever.
+    pub history_retention: u64,
+    ///Message of the day, sent to every client after joining
+    pub motd: Option<String>,
+    ///Allow anonymous users to send and receive private messages
+    pub anon_private_messages: bool,
+    ///How many events can be waiting for a client before `slow_client_policy` applies
+    pub client_queue_size: usize,
+    pub slow_client_policy: SlowClientPolicy,
+    ///Seconds between two pings to a client
+    pub ping_interval: u64,
+    ///Seconds a client has to answer a ping before it is disconnected
+    pub pong_timeout: u64,
+    ///Seconds without packets from a client before it is disconnected. 0 keeps idle clients.
+    pub idle_timeout: u64,
+    ///Seconds the session of a client whose connection dropped is kept so it can resume it. 0
+    ///disables resuming.
+    pub resume_grace: u64,
+}
+impl ChatConfig {
+    pub fn with_override(&self, config_override: &ChatConfigOverride) -> Self {
+        Self {
+            max_stored_messages: config_override
+                .max_stored_messages
+                .unwrap_or(self.max_stored_messages),
+            max_users: config_override.max_users.unwrap_or(self.max_users),
+            rate_limit: config_override
+                .rate_limit
+                .clone()
+                .unwrap_or_else(|| self.rate_limit.clone()),
+            history_dir: self.history_dir.clone(),
+            history_retention: config_override
+                .history_retention
+                .unwrap_or(self.history_retention),
+            motd: config_override.motd.clone().or_else(|| self.motd.clone()),
+            anon_private_messages: config_override
+                .anon_private_messages
+                .unwrap_or(self.anon_private_messages),
+            client_queue_size: config_override
+                .client_queue_size
+                .unwrap_or(self.client_queue_size),
+            slow_client_policy: config_override
+                .slow_client_policy
+                .unwrap_or(self.slow_client_policy),
+            ping_interval: config_override.ping_interval.unwrap_or(self.ping_interval),
+            pong_timeout: config_override.pong_timeout.unwrap_or(self.pong_timeout),
+            idle_timeout: config_override.idle_timeout.unwrap_or(self.idle_timeout),
+            resume_grace: config_override.resume_grace.unwrap_or(self.resume_grace),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ChatConfigOverride {
+    pub max_stored_messages: Option<usize>,
+    pub max_users: Option<u16>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub history_retention: Option<u64>,
+    pub motd: Option<String>,
+    pub anon_private_messages: Option<bool>,
+    pub client_queue_size: Option<usize>,
+    pub slow_client_policy: Option<SlowClientPolicy>,
+    pub ping_interval: Option<u64>,
+    pub pong_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub resume_grace: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RoomsConfig {
+    pub default_room: String,
+    pub max_rooms: usize,
+    pub room_idle_timeout: u64,
+    #[serde(default)]
+    pub rooms: HashMap<String, ChatConfigOverride>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -73,6 +173,15 @@ fn rocket() -> _ {
         &chat::joined_total::METRIC,
         &chat::left_total::METRIC,
         &chat::messages_total::METRIC,
+        &chat::queue::client_queue_depth::METRIC,
+        &chat::queue::client_queue_overflows_total::METRIC,
+        &chat::presence::users_online::METRIC,
+        &chat::presence::presence_evicted_total::METRIC,
+        &chat::session::detached_sessions_total::METRIC,
+        &chat::rooms::rooms_opened_total::METRIC,
+        &chat::rooms::rooms_closed_total::METRIC,
+        &profanity::censored_total::METRIC,
+        &socket::connections_total::METRIC,
     ]);
     metrics.on_before_handle(|| {});
     let r = rocket::build()
@@ -81,15 +190,44 @@ fn rocket() -> _ {
         .attach(static_routing::stage())
         .attach(template::stage())
         .attach(names::stage())
+        .attach(admin::stage())
+        .attach(commands::stage())
+        .attach(moderation::stage())
+        .attach(profanity::stage())
         .attach(AdHoc::config::<OfflineConfig>())
         .attach(AdHoc::on_ignite("chat", |r| async {
-            let config = r
+            let mut config = r
                 .figment()
                 .extract::<ChatConfig>()
                 .expect("No chat config found");
+            if let Some(history_dir) = &mut config.history_dir {
+                if history_dir.is_relative() {
+                    *history_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(&history_dir);
+                }
+                std::fs::create_dir_all(&history_dir).expect("Failed to create history_dir");
+            }
+            let rooms_config = r
+                .figment()
+                .extract::<RoomsConfig>()
+                .expect("No rooms config found");
+
+            let moderation = r
+                .state::<Arc<Moderation>>()
+                .expect("Moderation stage must be attached before the chat")
+                .clone();
 
-            r.mount("/", routes![socket::socket_v1])
-                .manage(Arc::new(Mutex::new(Chat::new(config))))
+            r.mount("/", routes![socket::socket_v1, socket::socket_v2])
+                .manage(Arc::new(ChatRooms::new(config, rooms_config, moderation)))
+        }))
+        .attach(AdHoc::on_shutdown("shutdown notice", |r| {
+            Box::pin(async move {
+                if let Some(rooms) = r.state::<Arc<ChatRooms>>() {
+                    rooms.broadcast_system(
+                        Severity::Warning,
+                        "De server wordt herstart. Je wordt zo dadelijk losgekoppeld.",
+                    );
+                }
+            })
         }));
     #[cfg(debug_assertions)]
     let r = r.attach(debug::stage());
@@ -1,32 +1,57 @@
-use crate::chat::client::Message;
+use crate::{
+    chat::{client::Message, Chat},
+    profanity::{censored_total, ProfFilter},
+};
 
-pub enum Cmd {
-    KillMe,
-    BlockMe,
+///A slash command: `/<name> <args>`
+pub struct Cmd {
+    pub name: String,
+    pub args: String,
 }
 
 pub enum FilterResult {
     Message(Message),
     Cmd(Cmd),
     Invalid,
+    ///The message replies to a message that isn't in the history (anymore)
+    UnknownReply,
 }
 
 fn parse_cmd(str: &str) -> Option<Cmd> {
-    if str == "/killme" {
-        Some(Cmd::KillMe);
-    } else if str == "/blockme" {
-        Some(Cmd::BlockMe);
+    let cmd = str.strip_prefix('/')?;
+    let (name, args) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    if name.is_empty() {
+        return None;
     }
-    None
+    Some(Cmd {
+        name: name.to_string(),
+        args: args.to_string(),
+    })
 }
 
-pub fn filter(mut mesg: Message) -> FilterResult {
-    if !mesg.is_valid() {
+fn is_valid(mesg: &Message) -> bool {
+    mesg.content.len() <= 100 && !mesg.is_empty()
+}
+
+fn is_valid_cmd(cmd: &Cmd) -> bool {
+    !cmd.name.is_empty()
+        && !cmd.name.contains(char::is_whitespace)
+        && cmd.name.len() + cmd.args.len() <= 100
+}
+
+pub async fn filter(mut mesg: Message, prof_filter: &ProfFilter, chat: &Chat) -> FilterResult {
+    if !is_valid(&mesg) {
         return FilterResult::Invalid;
     };
+    if let Some(reply_to) = mesg.reply_to {
+        if !chat.message_exists(reply_to).await {
+            return FilterResult::UnknownReply;
+        }
+    }
     let content = mesg.content.as_ref().trim();
+    // commands are parsed before censoring so their arguments can be censored by filter_cmd
     if let Some(cmd) = parse_cmd(content) {
-        return FilterResult::Cmd(cmd);
+        return filter_cmd(cmd, prof_filter);
     }
 
     let word = ['k', 'y', 's'];
@@ -38,10 +63,32 @@ pub fn filter(mut mesg: Message) -> FilterResult {
 
     if is_kys {
         mesg.content = "Kiss me pwees".into();
+    } else if let Some(censored) = prof_filter.censor(content) {
+        censored_total::inc("message");
+        mesg.content = censored.into();
+        // the replacement can take more bytes than the censored characters
+        if !is_valid(&mesg) {
+            return FilterResult::Invalid;
+        }
     }
-
     FilterResult::Message(mesg)
 }
 
-pub struct MesgFilter {}
-impl MesgFilter {}
+///Checks a command, the arguments are censored like messages
+pub fn filter_cmd(mut cmd: Cmd, prof_filter: &ProfFilter) -> FilterResult {
+    if !is_valid_cmd(&cmd) {
+        return FilterResult::Invalid;
+    }
+    // the moderator secret is never shown and has to match exactly
+    if cmd.name.eq_ignore_ascii_case("mod") {
+        return FilterResult::Cmd(cmd);
+    }
+    if let Some(censored) = prof_filter.censor(cmd.args.trim()) {
+        censored_total::inc("message");
+        cmd.args = censored;
+        if !is_valid_cmd(&cmd) {
+            return FilterResult::Invalid;
+        }
+    }
+    FilterResult::Cmd(cmd)
+}
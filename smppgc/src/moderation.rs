@@ -0,0 +1,345 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use dashmap::DashMap;
+use log::*;
+use rocket::{fairing::AdHoc, serde::Deserialize};
+
+use crate::{admin::constant_time_eq, names::UserId};
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ModerationConfig {
+    ///User ids (keys) that are moderator as soon as they connect
+    #[serde(default)]
+    pub moderators: Vec<String>,
+    ///Secret that makes a client moderator with `/mod <secret>`. Disabled when unset.
+    pub moderator_secret: Option<String>,
+    ///File where the bans are stored. Bans are only kept in memory when unset.
+    pub ban_list: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    User(UserId),
+    Ip(IpAddr),
+}
+impl BanTarget {
+    pub fn parse_str(string: &str) -> Option<Self> {
+        if let Ok(ip) = string.parse() {
+            return Some(Self::Ip(ip));
+        }
+        UserId::parse_str(string).map(Self::User)
+    }
+}
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::User(user_id) => user_id.fmt(f),
+            BanTarget::Ip(ip) => ip.fmt(f),
+        }
+    }
+}
+
+///Unix timestamp (in seconds) until the ban ends. 0 for permanent bans.
+type BanEnd = u64;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+struct BanList {
+    path: Option<PathBuf>,
+    bans: HashMap<BanTarget, BanEnd>,
+}
+impl BanList {
+    fn load(path: Option<PathBuf>) -> Self {
+        let mut bans = HashMap::new();
+        if let Some(path) = &path {
+            match std::fs::read_to_string(path) {
+                Ok(content) => {
+                    //| <target> <end>
+                    for line in content.lines() {
+                        let ban = line.split_once(' ').and_then(|(target, end)| {
+                            Some((BanTarget::parse_str(target)?, end.trim().parse().ok()?))
+                        });
+                        match ban {
+                            Some((target, end)) => {
+                                bans.insert(target, end);
+                            }
+                            None => warn!("Ignoring invalid ban list entry '{}'", line),
+                        }
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => error!("Failed to load ban list '{}'\n{}", path.display(), err),
+            }
+        }
+        let mut ban_list = Self { path, bans };
+        ban_list.prune();
+        ban_list
+    }
+
+    fn prune(&mut self) {
+        let now = unix_now();
+        self.bans.retain(|_, end| *end == 0 || *end > now);
+    }
+
+    fn save(&mut self) {
+        self.prune();
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(err) = Self::write(path, &self.bans) {
+            error!("Failed to save ban list '{}'\n{}", path.display(), err);
+        }
+    }
+
+    fn write(path: &Path, bans: &HashMap<BanTarget, BanEnd>) -> std::io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        for (target, end) in bans {
+            writeln!(file, "{} {}", target, end)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)
+    }
+}
+
+///Moderators, bans and mutes
+pub struct Moderation {
+    moderators: HashSet<UserId>,
+    moderator_secret: Option<String>,
+    bans: Mutex<BanList>,
+    ///When the mute ends, None for mutes that end too far in the future to represent
+    mutes: DashMap<UserId, Option<Instant>>,
+}
+impl Moderation {
+    pub fn new(config: ModerationConfig) -> Self {
+        let moderators = config
+            .moderators
+            .iter()
+            .filter_map(|user_id| {
+                let parsed = UserId::parse_str(user_id);
+                if parsed.is_none() {
+                    error!("Invalid moderator user id '{}'", user_id);
+                }
+                parsed
+            })
+            .collect();
+        Self {
+            moderators,
+            moderator_secret: config.moderator_secret.filter(|secret| !secret.is_empty()),
+            bans: Mutex::new(BanList::load(config.ban_list)),
+            mutes: DashMap::new(),
+        }
+    }
+
+    pub fn is_moderator(&self, user_id: &UserId) -> bool {
+        self.moderators.contains(user_id)
+    }
+
+    pub fn check_secret(&self, secret: &str) -> bool {
+        self.moderator_secret
+            .as_ref()
+            .is_some_and(|moderator_secret| {
+                constant_time_eq(secret.as_bytes(), moderator_secret.as_bytes())
+            })
+    }
+
+    ///Bans the target. A `duration` of None or one that ends too far in the future bans forever.
+    pub fn ban(&self, target: BanTarget, duration: Option<Duration>) {
+        let end = duration
+            .and_then(|duration| unix_now().checked_add(duration.as_secs().max(1)))
+            .unwrap_or(0);
+        info!("Banned {} until {}", target, end);
+        let mut bans = self.bans.lock().unwrap();
+        bans.bans.insert(target, end);
+        bans.save();
+    }
+
+    ///Returns false when the target wasn't banned
+    pub fn unban(&self, target: &BanTarget) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let removed = bans.bans.remove(target).is_some();
+        if removed {
+            info!("Unbanned {}", target);
+            bans.save();
+        }
+        removed
+    }
+
+    ///Returns how long the user or ip is still banned. Permanent bans return `Duration::MAX`.
+    pub fn banned_for(&self, user_id: &UserId, ip: Option<IpAddr>) -> Option<Duration> {
+        let bans = self.bans.lock().unwrap();
+        let now = unix_now();
+        [
+            Some(BanTarget::User(user_id.clone())),
+            ip.map(BanTarget::Ip),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|target| bans.bans.get(&target).copied())
+        .filter(|end| *end == 0 || *end > now)
+        .map(|end| match end {
+            0 => Duration::MAX,
+            end => Duration::from_secs(end - now),
+        })
+        .max()
+    }
+
+    pub fn mute(&self, user_id: UserId, duration: Duration) {
+        self.mutes
+            .insert(user_id, Instant::now().checked_add(duration));
+    }
+
+    ///Returns false when the user wasn't muted
+    pub fn unmute(&self, user_id: &UserId) -> bool {
+        self.mutes.remove(user_id).is_some()
+    }
+
+    ///Returns how long the user is still muted. Permanent mutes return `Duration::MAX`.
+    pub fn muted_for(&self, user_id: &UserId) -> Option<Duration> {
+        let Some(end) = *self.mutes.get(user_id)? else {
+            return Some(Duration::MAX);
+        };
+        let remaining = end.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            self.mutes.remove(user_id);
+        }
+        remaining
+    }
+}
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("moderation", |r| async {
+        let mut config = r
+            .figment()
+            .extract::<ModerationConfig>()
+            .expect("No moderation config");
+        if let Some(ban_list) = &mut config.ban_list {
+            if ban_list.is_relative() {
+                *ban_list = Path::new(env!("CARGO_MANIFEST_DIR")).join(&ban_list);
+            }
+        }
+        r.manage(Arc::new(Moderation::new(config)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Ban list in the temp dir that is removed when the test is done
+    struct TempPath(PathBuf);
+    impl TempPath {
+        fn new() -> Self {
+            let name = format!("smppgc-bans-{}.txt", uuid::Uuid::new_v4().simple());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn new_moderation(ban_list: Option<&Path>) -> Moderation {
+        Moderation::new(ModerationConfig {
+            moderators: Vec::new(),
+            moderator_secret: Some("geheim".to_string()),
+            ban_list: ban_list.map(Path::to_path_buf),
+        })
+    }
+
+    #[test]
+    fn bans_are_persisted() {
+        let path = TempPath::new();
+        let user = UserId::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let moderation = new_moderation(Some(&path.0));
+        moderation.ban(BanTarget::User(user.clone()), None);
+        moderation.ban(BanTarget::Ip(ip), Some(Duration::from_secs(600)));
+
+        let loaded = new_moderation(Some(&path.0));
+        assert_eq!(loaded.banned_for(&user, None), Some(Duration::MAX));
+        let remaining = loaded.banned_for(&UserId::new(), Some(ip)).unwrap();
+        assert!(remaining > Duration::from_secs(590) && remaining <= Duration::from_secs(600));
+        assert_eq!(loaded.banned_for(&UserId::new(), None), None);
+
+        assert!(loaded.unban(&BanTarget::User(user.clone())));
+        assert!(!loaded.unban(&BanTarget::User(user.clone())));
+        assert_eq!(new_moderation(Some(&path.0)).banned_for(&user, None), None);
+    }
+
+    #[test]
+    fn expired_bans_are_dropped() {
+        let path = TempPath::new();
+        let expired = UserId::new();
+        let active = UserId::new();
+        let now = unix_now();
+        std::fs::write(
+            &path.0,
+            format!(
+                "{} {}\n{} {}\nniet een ban\n",
+                expired,
+                now - 60,
+                active,
+                now + 600
+            ),
+        )
+        .unwrap();
+
+        let moderation = new_moderation(Some(&path.0));
+        assert_eq!(moderation.banned_for(&expired, None), None);
+        assert!(moderation.banned_for(&active, None).is_some());
+        // saving drops the expired ban and the invalid line from the file
+        moderation.ban(BanTarget::User(UserId::new()), None);
+        let content = std::fs::read_to_string(&path.0).unwrap();
+        assert!(!content.contains(&expired.to_string()));
+        assert!(content.contains(&active.to_string()));
+        assert_eq!(content.lines().count(), 2);
+    }
+
+    #[test]
+    fn long_bans_and_mutes_are_permanent() {
+        let moderation = new_moderation(None);
+        let user = UserId::new();
+        moderation.ban(BanTarget::User(user.clone()), Some(Duration::MAX));
+        assert_eq!(moderation.banned_for(&user, None), Some(Duration::MAX));
+
+        moderation.mute(user.clone(), Duration::MAX);
+        assert_eq!(moderation.muted_for(&user), Some(Duration::MAX));
+        assert!(moderation.unmute(&user));
+        assert_eq!(moderation.muted_for(&user), None);
+    }
+
+    #[test]
+    fn mutes_expire() {
+        let moderation = new_moderation(None);
+        let user = UserId::new();
+        moderation.mute(user.clone(), Duration::ZERO);
+        assert_eq!(moderation.muted_for(&user), None);
+        assert!(!moderation.unmute(&user));
+
+        moderation.mute(user.clone(), Duration::from_secs(60));
+        assert!(moderation.muted_for(&user).unwrap() <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn secret() {
+        assert!(new_moderation(None).check_secret("geheim"));
+        assert!(!new_moderation(None).check_secret("geheim!"));
+        assert!(!new_moderation(None).check_secret(""));
+    }
+}
@@ -1,10 +1,11 @@
 use dashmap::DashMap;
-use log::*;
-use rocket::{fairing::AdHoc, serde::Deserialize};
+use rocket::{fairing::AdHoc, routes, serde::Deserialize};
 use std::{collections::VecDeque, ops::Deref, sync::Arc};
 use thiserror::Error;
 
+mod token;
 mod userid;
+pub use token::*;
 pub use userid::*;
 
 #[derive(Error, Debug)]
@@ -74,14 +75,10 @@ impl UsernameManager {
     }
 
     fn is_valid_name_char(char: char) -> bool {
-        if char.is_ascii() && !char.is_control() && char != '@' {
-            true
-        } else {
-            false
-        }
+        char.is_ascii() && !char.is_control() && char != '@'
     }
 
-    fn normalize_name<'a>(name: &'a str) -> Option<NormName> {
+    fn normalize_name(name: &str) -> Option<NormName> {
         let name: &str = name.trim();
         if name.len() > 20 || name.len() < 2 {
             return None;
@@ -111,9 +108,9 @@ impl Deref for ClaimedName {
         &self.0
     }
 }
-impl Into<Arc<str>> for ClaimedName {
-    fn into(self) -> Arc<str> {
-        self.0.into()
+impl From<ClaimedName> for Arc<str> {
+    fn from(name: ClaimedName) -> Self {
+        name.0
     }
 }
 
@@ -129,6 +126,12 @@ pub fn stage() -> AdHoc {
             .figment()
             .extract::<NameConfig>()
             .expect("No username config");
-        r.manage(UsernameManager::new(config.max_reserved_names))
+        let token_config = r
+            .figment()
+            .extract::<TokenConfig>()
+            .expect("No token config");
+        r.manage(Arc::new(UsernameManager::new(config.max_reserved_names)))
+            .manage(Arc::new(TokenSigner::new(&token_config)))
+            .mount("/admin", routes![mint_token])
     })
 }
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rocket::{http::Status, post, serde::Deserialize, State};
+use sha2::Sha256;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::UserId;
+use crate::admin::Admin;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenConfig {
+    ///Secrets used to verify logged in keys, by key id. Logged in keys are rejected when empty.
+    #[serde(default)]
+    pub token_secrets: HashMap<String, String>,
+    ///Id of the secret that signs new keys. Keep the old secrets around until their keys expire when rotating.
+    pub token_signing_key: Option<String>,
+    ///How long (in seconds) a minted key stays valid
+    pub token_ttl: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("Ongeldige sleutel.")]
+    Invalid,
+    #[error("Je sleutel is verlopen. Log opnieuw in.")]
+    Expired,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+///Mints and verifies the keys of logged in users.
+///
+///A logged in key looks like `l<uuid>.<expiry>.<key id>.<signature>`, the signature is a HMAC-SHA256
+///over the user id and the expiry (unix timestamp in seconds). Anonymous keys (`a<uuid>`) are not signed.
+pub struct TokenSigner {
+    secrets: HashMap<String, Vec<u8>>,
+    signing_key: Option<String>,
+    ttl: Duration,
+}
+impl TokenSigner {
+    pub fn new(config: &TokenConfig) -> Self {
+        for key_id in config.token_secrets.keys() {
+            if key_id.is_empty() || key_id.len() > 32 || key_id.contains('.') {
+                panic!("Invalid token key id '{}'", key_id);
+            }
+        }
+        if let Some(signing_key) = &config.token_signing_key {
+            if !config.token_secrets.contains_key(signing_key) {
+                panic!(
+                    "token_signing_key '{}' is not in token_secrets",
+                    signing_key
+                );
+            }
+        }
+        Self {
+            secrets: config
+                .token_secrets
+                .iter()
+                .map(|(key_id, secret)| (key_id.clone(), secret.as_bytes().to_vec()))
+                .collect(),
+            signing_key: config.token_signing_key.clone(),
+            ttl: Duration::from_secs(config.token_ttl),
+        }
+    }
+
+    fn mac(secret: &[u8], user_id: &UserId, expiry: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+        mac.update(&user_id.to_bytes_le());
+        mac.update(&expiry.to_be_bytes());
+        mac
+    }
+
+    ///Mints a logged in key for the user. Returns None when no signing key is configured.
+    pub fn mint(&self, uuid: Uuid) -> Option<String> {
+        let key_id = self.signing_key.as_ref()?;
+        let user_id = UserId::logged_in(uuid);
+        let expiry = unix_now() + self.ttl.as_secs();
+        let signature = Self::mac(&self.secrets[key_id], &user_id, expiry)
+            .finalize()
+            .into_bytes();
+        Some(format!(
+            "{}.{}.{}.{}",
+            user_id,
+            expiry,
+            key_id,
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    ///Parses an anonymous key or verifies a logged in key
+    pub fn verify(&self, key: &str) -> Result<UserId, TokenError> {
+        if key.starts_with('a') {
+            return UserId::parse_str(key).ok_or(TokenError::Invalid);
+        }
+        let mut parts = key.split('.');
+        let (Some(user_id), Some(expiry), Some(key_id), Some(signature), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err(TokenError::Invalid);
+        };
+        let user_id = UserId::parse_str(user_id)
+            .filter(|user_id| !user_id.is_anon())
+            .ok_or(TokenError::Invalid)?;
+        let expiry: u64 = expiry.parse().map_err(|_| TokenError::Invalid)?;
+        let secret = self.secrets.get(key_id).ok_or(TokenError::Invalid)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Invalid)?;
+        Self::mac(secret, &user_id, expiry)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::Invalid)?;
+        if expiry <= unix_now() {
+            return Err(TokenError::Expired);
+        }
+        Ok(user_id)
+    }
+}
+
+///Mints a logged in key for `user`. Called by the login flow after it verified the user.
+#[post("/token?<user>")]
+pub fn mint_token(_admin: Admin, user: &str, signer: &State<Arc<TokenSigner>>) -> (Status, String) {
+    let Ok(uuid) = Uuid::parse_str(user) else {
+        return (Status::BadRequest, "Invalid user uuid".to_string());
+    };
+    match signer.mint(uuid) {
+        Some(token) => (Status::Ok, token),
+        None => (
+            Status::ServiceUnavailable,
+            "No token_signing_key configured".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(secrets: &[(&str, &str)], signing_key: Option<&str>, ttl: u64) -> TokenSigner {
+        TokenSigner::new(&TokenConfig {
+            token_secrets: secrets
+                .iter()
+                .map(|(key_id, secret)| (key_id.to_string(), secret.to_string()))
+                .collect(),
+            token_signing_key: signing_key.map(str::to_string),
+            token_ttl: ttl,
+        })
+    }
+
+    #[test]
+    fn minted_key_verifies() {
+        let signer = signer(&[("2024", "geheim")], Some("2024"), 600);
+        let uuid = Uuid::new_v4();
+        let key = signer.mint(uuid).unwrap();
+        assert_eq!(signer.verify(&key).unwrap(), UserId::logged_in(uuid));
+    }
+
+    #[test]
+    fn anonymous_keys_are_not_signed() {
+        let signer = signer(&[], None, 600);
+        let user_id = UserId::new();
+        assert_eq!(signer.verify(&user_id.to_string()).unwrap(), user_id);
+        assert!(signer.mint(Uuid::new_v4()).is_none());
+        // a logged in user id without a signature is not accepted
+        let forged = UserId::logged_in(Uuid::new_v4()).to_string();
+        assert!(matches!(signer.verify(&forged), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn tampered_keys_are_rejected() {
+        let signer = signer(&[("2024", "geheim")], Some("2024"), 600);
+        let key = signer.mint(Uuid::new_v4()).unwrap();
+        let parts: Vec<&str> = key.split('.').collect();
+        let other_user = UserId::logged_in(Uuid::new_v4()).to_string();
+        let later = (unix_now() + 100_000).to_string();
+        let forged = [
+            [other_user.as_str(), parts[1], parts[2], parts[3]].join("."),
+            [parts[0], later.as_str(), parts[2], parts[3]].join("."),
+            [parts[0], parts[1], "2025", parts[3]].join("."),
+            [parts[0], parts[1], parts[2], &parts[3][1..]].join("."),
+            [parts[0], parts[1], parts[2], "AAAA"].join("."),
+            [parts[0], parts[1], parts[2]].join("."),
+            format!("{}.extra", key),
+            key[..key.len() - 1].to_string(),
+            parts[0].to_string(),
+        ];
+        for key in forged {
+            assert!(
+                matches!(signer.verify(&key), Err(TokenError::Invalid)),
+                "{} was accepted",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn keys_of_other_secrets_are_rejected() {
+        let key = signer(&[("2024", "geheim")], Some("2024"), 600)
+            .mint(Uuid::new_v4())
+            .unwrap();
+        let other = signer(&[("2024", "anders")], Some("2024"), 600);
+        assert!(matches!(other.verify(&key), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn old_secrets_verify_after_rotation() {
+        let uuid = Uuid::new_v4();
+        let key = signer(&[("2024", "oud")], Some("2024"), 600)
+            .mint(uuid)
+            .unwrap();
+        let rotated = signer(&[("2024", "oud"), ("2025", "nieuw")], Some("2025"), 600);
+        assert_eq!(rotated.verify(&key).unwrap(), UserId::logged_in(uuid));
+        assert!(rotated.mint(uuid).unwrap().contains(".2025."));
+    }
+
+    #[test]
+    fn expired_keys_are_rejected() {
+        let signer = signer(&[("2024", "geheim")], Some("2024"), 0);
+        let key = signer.mint(Uuid::new_v4()).unwrap();
+        assert!(matches!(signer.verify(&key), Err(TokenError::Expired)));
+    }
+}
@@ -2,11 +2,16 @@ use std::fmt::Display;
 
 use uuid::Uuid;
 
-#[derive(Eq, PartialEq, Clone, Hash)]
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
 pub struct UserId {
     uuid: Uuid,
     anon: bool,
 }
+impl Default for UserId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl UserId {
     pub fn new() -> UserId {
         Self {
@@ -14,6 +19,9 @@ impl UserId {
             anon: true,
         }
     }
+    pub fn logged_in(uuid: Uuid) -> UserId {
+        Self { uuid, anon: false }
+    }
     pub fn parse_str(string: &str) -> Option<Self> {
         if string.len() != 33 {
             return None;
@@ -30,7 +38,7 @@ impl UserId {
     }
     pub fn to_bytes_le(&self) -> [u8; 17] {
         let mut out = [0; 17];
-        out.clone_from_slice(&self.uuid.to_bytes_le());
+        out[..16].copy_from_slice(&self.uuid.to_bytes_le());
         if self.anon {
             out[16] = 0x61; //a
         } else {
@@ -38,9 +46,21 @@ impl UserId {
         }
         out
     }
+    pub fn from_bytes_le(bytes: [u8; 17]) -> Option<Self> {
+        let anon = match bytes[16] {
+            0x61 => true,
+            0x6C => false,
+            _ => return None,
+        };
+        let uuid = Uuid::from_bytes_le(bytes[..16].try_into().unwrap());
+        Some(Self { uuid, anon })
+    }
     pub fn uuid(&self) -> Uuid {
         self.uuid
     }
+    pub fn is_anon(&self) -> bool {
+        self.anon
+    }
 }
 impl Display for UserId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
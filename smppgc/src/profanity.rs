@@ -1,31 +1,171 @@
 use std::{
     ops::AddAssign,
+    path::PathBuf,
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 
+use lmetrics::metrics;
+use log::*;
+use rocket::{fairing::AdHoc, http::Status, post, routes, serde::Deserialize, State};
+
+use crate::admin::Admin;
+
+metrics! {
+    pub counter censored_total("Total count of censored messages and usernames", [kind]);
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum CensorSet {
+    Standard,
+    Sex,
+    Zealous,
+}
+impl From<CensorSet> for censor::Censor {
+    fn from(set: CensorSet) -> Self {
+        match set {
+            CensorSet::Standard => censor::Standard,
+            CensorSet::Sex => censor::Sex,
+            CensorSet::Zealous => censor::Zealous,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ProfanityConfig {
+    ///File with extra words to censor (one per line, # for comments)
+    pub profanity_wordlist: Option<PathBuf>,
+    pub profanity_sets: Vec<CensorSet>,
+    pub profanity_replacement: String,
+    ///How often (in seconds) the wordlist is checked for changes. 0 disables watching.
+    pub profanity_reload_interval: u64,
+}
+
 #[derive(Clone)]
 pub struct ProfFilter {
     censor: Arc<RwLock<censor::Censor>>,
+    replacement: Arc<str>,
+    sets: Arc<[CensorSet]>,
+    wordlist: Option<Arc<PathBuf>>,
 }
 impl ProfFilter {
-    pub fn new() {
+    pub fn new(config: &ProfanityConfig) -> Self {
+        let sets: Arc<[CensorSet]> = config.profanity_sets.as_slice().into();
         Self {
-            censor: RwLock::new(censor::Sex + censor::Zealous + censor::Standard).into(),
-        };
+            censor: RwLock::new(Self::base_censor(&sets)).into(),
+            replacement: config.profanity_replacement.as_str().into(),
+            sets,
+            wordlist: config.profanity_wordlist.clone().map(Arc::new),
+        }
+    }
+
+    fn base_censor(sets: &[CensorSet]) -> censor::Censor {
+        let mut censor = censor::Censor::empty();
+        for set in sets {
+            censor += censor::Censor::from(*set);
+        }
+        censor
     }
 
-    pub fn load_wordlist(&self, path: &str) -> std::io::Result<()> {
-        let mut lock = self.censor.write().unwrap();
-        for line in std::fs::read_to_string(path)?.split('\n') {
-            let line = line.trim();
-            if line.len() == 0 || line.starts_with("#") {
-                continue;
+    ///Rebuilds the censor from the configured sets and the wordlist and swaps it in.
+    ///Returns the number of loaded terms. The old censor stays active when the wordlist can't be read.
+    pub fn reload(&self) -> std::io::Result<usize> {
+        let mut censor = Self::base_censor(&self.sets);
+        if let Some(path) = &self.wordlist {
+            for line in std::fs::read_to_string(path.as_path())?.split('\n') {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with("#") {
+                    continue;
+                }
+                censor.add_assign(line);
             }
-            lock.add_assign(line);
         }
-        Ok(())
+        let count = censor.set().len();
+        *self.censor.write().unwrap() = censor;
+        Ok(count)
+    }
+
+    ///Reloads the wordlist every time its modification time changes
+    pub fn spawn_watcher(&self, interval: Duration) {
+        let Some(path) = self.wordlist.clone() else {
+            return;
+        };
+        let filter = self.clone();
+        tokio::task::spawn(async move {
+            let modified = |path: &PathBuf| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            };
+            let mut last_modified: Option<SystemTime> = modified(&path);
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let new_modified = modified(&path);
+                if new_modified == last_modified {
+                    continue;
+                }
+                last_modified = new_modified;
+                match filter.reload() {
+                    Ok(count) => info!("Reloaded profanity wordlist ({} terms)", count),
+                    Err(err) => error!(
+                        "Failed to reload profanity wordlist '{}'\n{}",
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        });
     }
     pub fn filter(&self, string: &str) -> String {
-        self.censor.read().unwrap().replace(string, "#")
+        self.censor
+            .read()
+            .unwrap()
+            .replace(string, &self.replacement)
+    }
+
+    ///Returns the censored string or None if nothing had to be censored
+    pub fn censor(&self, string: &str) -> Option<String> {
+        let censor = self.censor.read().unwrap();
+        if !censor.check(string) {
+            return None;
+        }
+        Some(censor.replace(string, &self.replacement))
+    }
+}
+
+#[post("/profanity/reload")]
+fn reload(_admin: Admin, filter: &State<ProfFilter>) -> (Status, String) {
+    match filter.reload() {
+        Ok(count) => {
+            info!("Reloaded profanity wordlist ({} terms)", count);
+            (Status::Ok, format!("Loaded {} terms", count))
+        }
+        Err(err) => (
+            Status::InternalServerError,
+            format!("Failed to reload wordlist: {}", err),
+        ),
     }
 }
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("profanity filter", |r| async {
+        let config = r
+            .figment()
+            .extract::<ProfanityConfig>()
+            .expect("No profanity config");
+        if config.profanity_replacement.is_empty() {
+            panic!("profanity_replacement can't be empty");
+        }
+        let filter = ProfFilter::new(&config);
+        if let Err(err) = filter.reload() {
+            error!("Failed to load profanity wordlist\n{}", err);
+        }
+        if config.profanity_reload_interval != 0 {
+            filter.spawn_watcher(Duration::from_secs(config.profanity_reload_interval));
+        }
+        r.manage(filter).mount("/admin", routes![reload])
+    })
+}
@@ -1,22 +1,40 @@
 use rocket::{get, Responder, State};
-use std::{borrow::Cow, sync::Arc, time::Instant};
+use std::{
+    borrow::Cow,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use lmetrics::metrics;
 use log::*;
 use rocket_ws::{
     frame::{CloseCode, CloseFrame},
     Channel, WebSocket,
 };
-use tokio::sync::{broadcast::error::RecvError, Mutex};
 
 use crate::{
-    chat::Chat,
+    chat::{
+        client::{handshake, Client},
+        packet::{Capabilities, ClientPacket, Protocol, Version},
+        queue::{client_queue_overflows_total, Delivery},
+        rooms::ChatRooms,
+        ChatEvent, PrivateMessage, Severity, Typing,
+    },
+    commands::{muted_text, CommandContext, CommandOutcome, CommandRegistry, Permission},
     mesg_filter::{self, Cmd, FilterResult},
-    names::{NameClaimError, UserId, UsernameManager},
-    OfflineConfig,
+    moderation::Moderation,
+    names::{NameClaimError, TokenSigner, UserId, UsernameManager},
+    profanity::{censored_total, ProfFilter},
+    OfflineConfig, SlowClientPolicy,
 };
 
+metrics! {
+    pub counter connections_total("Total websocket connections by protocol version", [version]);
+}
+
 #[derive(Responder)]
-pub enum SocketV1Responder {
+pub enum SocketResponder {
     #[response(status = 503)]
     Offline(&'static str),
     #[response(status = 500)]
@@ -25,138 +43,505 @@ pub enum SocketV1Responder {
     Channel(Channel<'static>),
 }
 
-#[get("/socket/v1?<username>&<key>")]
+///Old clients without a handshake. Served from the same chat through the v1 packet adapter.
+#[get("/socket/v1?<username>&<key>&<room>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn socket_v1(
     username: &str,
     key: Option<&str>,
+    room: Option<&str>,
+    ws: WebSocket,
+    ip: Option<IpAddr>,
+    offline_config: &State<OfflineConfig>,
+    rooms: &State<Arc<ChatRooms>>,
+    usrnamemgr: &State<Arc<UsernameManager>>,
+    prof_filter: &State<ProfFilter>,
+    commands: &State<Arc<CommandRegistry>>,
+    moderation: &State<Arc<Moderation>>,
+    token_signer: &State<Arc<TokenSigner>>,
+) -> SocketResponder {
+    socket(
+        Version::V1,
+        username,
+        key,
+        room,
+        None,
+        ws,
+        ip,
+        offline_config,
+        rooms,
+        usrnamemgr,
+        prof_filter,
+        commands,
+        moderation,
+        token_signer,
+    )
+}
+
+///The client sends a hello with its version and capabilities before the setup packet. A client
+///that lost its connection passes its resume token and the id of the last message it received to
+///continue its session.
+#[get("/socket/v2?<username>&<key>&<room>&<resume>&<last_seen>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn socket_v2(
+    username: &str,
+    key: Option<&str>,
+    room: Option<&str>,
+    resume: Option<&str>,
+    last_seen: Option<u32>,
+    ws: WebSocket,
+    ip: Option<IpAddr>,
+    offline_config: &State<OfflineConfig>,
+    rooms: &State<Arc<ChatRooms>>,
+    usrnamemgr: &State<Arc<UsernameManager>>,
+    prof_filter: &State<ProfFilter>,
+    commands: &State<Arc<CommandRegistry>>,
+    moderation: &State<Arc<Moderation>>,
+    token_signer: &State<Arc<TokenSigner>>,
+) -> SocketResponder {
+    socket(
+        Version::V2,
+        username,
+        key,
+        room,
+        resume.map(|token| (token, last_seen)),
+        ws,
+        ip,
+        offline_config,
+        rooms,
+        usrnamemgr,
+        prof_filter,
+        commands,
+        moderation,
+        token_signer,
+    )
+}
+
+///Forwards an event of the room to the client. Returns false when the client got kicked.
+async fn forward_event(client: &mut Client, event: ChatEvent) -> rocket_ws::result::Result<bool> {
+    match event {
+        ChatEvent::Message(mesg) => client.forward(&mesg).await?,
+        ChatEvent::Edit(edit) => client.forward_edit(&edit).await?,
+        ChatEvent::Reaction(reaction) => client.forward_reaction(reaction).await?,
+        ChatEvent::Joined(joined_client) => {
+            info!("user join {}", joined_client.id());
+            client.forward_client(&joined_client).await?;
+        }
+        ChatEvent::Left(left_client) => client.forward_client_left(&left_client).await?,
+        ChatEvent::System(system_mesg) => {
+            client
+                .send_system(system_mesg.severity, &system_mesg.text)
+                .await?
+        }
+        ChatEvent::Kick(kick) => {
+            if kick.matches(&client.client_info()) {
+                client.kick(kick.reason).await?;
+                return Ok(false);
+            }
+        }
+        ChatEvent::Typing(typing_event) => {
+            if typing_event.id != client.client_info().id() {
+                client.forward_typing(typing_event).await?;
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn socket(
+    version: Version,
+    username: &str,
+    key: Option<&str>,
+    room: Option<&str>,
+    resume: Option<(&str, Option<u32>)>,
     ws: WebSocket,
+    ip: Option<IpAddr>,
     offline_config: &State<OfflineConfig>,
-    chat: &State<Arc<Mutex<Chat>>>,
-    usrnamemgr: &State<UsernameManager>,
-) -> SocketV1Responder {
+    rooms: &State<Arc<ChatRooms>>,
+    usrnamemgr: &State<Arc<UsernameManager>>,
+    prof_filter: &State<ProfFilter>,
+    commands: &State<Arc<CommandRegistry>>,
+    moderation: &State<Arc<Moderation>>,
+    token_signer: &State<Arc<TokenSigner>>,
+) -> SocketResponder {
     if offline_config.offline {
-        return SocketV1Responder::Offline("smppgc offline");
+        return SocketResponder::Offline("smppgc offline");
     }
-    let key = if let Some(user_id) = key {
-        UserId::parse_str(user_id)
-    } else {
-        Some(UserId::new())
+    let key = match key {
+        Some(key) => token_signer
+            .verify(key)
+            .map(|user_id| (user_id, key.to_string())),
+        None => {
+            let user_id = UserId::new();
+            let key = user_id.to_string();
+            Ok((user_id, key))
+        }
     };
 
-    let chat: Arc<Mutex<Chat>> = chat.inner().clone();
-    let name_lease = match key.clone() {
-        Some(key) => usrnamemgr.claim_name(username, key),
-        None => Err(NameClaimError::Invalid),
+    let chat = rooms.get_or_create(room);
+    let username = match prof_filter.censor(username) {
+        Some(censored) => {
+            censored_total::inc("username");
+            Cow::Owned(censored)
+        }
+        None => Cow::Borrowed(username),
     };
+    let name_lease = match &key {
+        Ok((user_id, _)) => usrnamemgr.claim_name(&username, user_id.clone()),
+        Err(_) => Err(NameClaimError::Invalid),
+    };
+
+    let resume = resume.map(|(token, last_seen)| (token.to_string(), last_seen));
 
-    SocketV1Responder::Channel(ws.channel(move |mut stream| {
+    let prof_filter = prof_filter.inner().clone();
+    let usrnamemgr = usrnamemgr.inner().clone();
+    let commands = commands.inner().clone();
+    let moderation = moderation.inner().clone();
+    let rooms = rooms.inner().clone();
+    connections_total::inc(version.label());
+    SocketResponder::Channel(ws.channel(move |mut stream| {
         Box::pin(async move {
-            let Some(key) = key else {
-                stream
-                    .close(Some(CloseFrame {
-                        code: CloseCode::Error,
-                        reason: Cow::Borrowed("INT: Ongeldige sleutel."),
-                    }))
-                    .await?;
-                return Ok(());
-            };
-            let name_lease = match name_lease {
-                Ok(name_lease) => name_lease,
+            let (key, token) = match key {
+                Ok(key) => key,
                 Err(e) => {
                     stream
                         .close(Some(CloseFrame {
                             code: CloseCode::Error,
-                            reason: Cow::Owned(e.to_string()),
+                            reason: Cow::Owned(format!("INT: {}", e)),
                         }))
                         .await?;
                     return Ok(());
                 }
             };
-
-            let mut chat = chat.lock().await;
-            let mut client = match chat.new_client(stream, key, name_lease).await {
-                Ok(c) => c,
+            let chat = match chat {
+                Ok(chat) => chat,
                 Err(e) => {
-                    info!("Closing connection: {:?}", e);
+                    stream
+                        .close(Some(CloseFrame {
+                            code: CloseCode::Error,
+                            reason: Cow::Owned(e.to_string()),
+                        }))
+                        .await?;
                     return Ok(());
                 }
             };
-            let (mut messages_receiver, messages_sender, mut join_reciever) =
-                chat.subscribe_events();
-            let rate_limit = chat.config().rate_limit.clone();
-            drop(chat);
 
-            let mut blockme = false;
-            let mut burst = 0;
-            let mut last_message_instant = Instant::now();
-            loop {
-                tokio::select! {
-                    mesg = client.try_recv() => {
-                        let Some(mesg) = mesg? else { continue; };
-                        let last_mesg_sec : isize = last_message_instant.elapsed().as_millis().try_into().unwrap_or(isize::MAX);
-                        last_message_instant = Instant::now();
-
-                        if last_mesg_sec < rate_limit.min_message_time_hard{
-                            client.ratelimit_kick().await?;
+            let protocol = match version {
+                Version::V1 => Protocol::V1,
+                Version::V2 => match handshake(&mut stream).await? {
+                    Some(protocol) => protocol,
+                    None => return Ok(()),
+                },
+            };
+
+            let mut permission = if moderation.is_moderator(&key) {
+                Permission::Moderator
+            } else {
+                Permission::User
+            };
+            let session = resume.and_then(|(resume, last_seen)| Some((chat.take_session(&resume, &key)?, last_seen)));
+            let resumed = session.is_some();
+            let (mut client, mut mailbox, mut events) = match session {
+                Some((session, last_seen)) => {
+                    // A ban issued while the client was detached still has to keep it out.
+                    if chat.reject_banned(&mut stream, &key, ip).await? {
+                        info!("Dropped session of banned client {}", session.info.id());
+                        return Ok(());
+                    }
+                    let (mut client, mailbox, events, missed) = chat.resume(stream, protocol, session, last_seen).await?;
+                    info!("Resumed session of client {}", client.client_info().id());
+                    for event in missed {
+                        if !forward_event(&mut client, event).await? {
                             return Ok(());
                         }
-                        burst+=rate_limit.min_message_time_hard.saturating_sub(last_mesg_sec);
-                        if burst < 0{
-                            burst=0;
-                        }
-                        if burst > rate_limit.kick_burst{
-                            client.ratelimit_kick().await?;
+                    }
+                    (client, mailbox, events)
+                }
+                None => {
+                    let name_lease = match name_lease {
+                        Ok(name_lease) => name_lease,
+                        Err(e) => {
+                            stream
+                                .close(Some(CloseFrame {
+                                    code: CloseCode::Error,
+                                    reason: Cow::Owned(e.to_string()),
+                                }))
+                                .await?;
                             return Ok(());
                         }
-                        if last_mesg_sec < rate_limit.min_message_time_soft{
-                            burst+=rate_limit.min_message_time_soft.saturating_sub(last_mesg_sec)*2.clamp(0, isize::MAX);
+                    };
+                    match chat.new_client(stream, protocol, key, &token, ip, name_lease).await {
+                        Ok(joined) => joined,
+                        Err(e) => {
+                            info!("Closing connection: {:?}", e);
+                            return Ok(());
                         }
-                        match mesg_filter::filter(mesg){
-                            FilterResult::Cmd(Cmd::BlockMe) => {
-                                blockme=true;
-                            },
-                            FilterResult::Cmd(Cmd::KillMe) => {
+                    }
+                }
+            };
+            let rate_limit = chat.config().rate_limit.clone();
+            let motd = chat.config().motd.clone().filter(|_| !resumed);
+            if let Some(motd) = motd {
+                client.send_system(Severity::Info, &motd).await?;
+            }
+
+            let mut blockme = false;
+            let mut burst = 0;
+            let mut warned = false;
+            let mut last_message_instant = Instant::now();
+            let min_typing_time = Duration::from_millis(rate_limit.min_typing_time);
+            let mut typing = false;
+            let mut last_typing_instant: Option<Instant> = None;
+            let min_history_time = Duration::from_millis(rate_limit.min_history_time);
+            let mut last_history_instant: Option<Instant> = None;
+            let eviction = client.eviction();
+            let ping_interval = Duration::from_secs(chat.config().ping_interval.max(1));
+            let pong_timeout = Duration::from_secs(chat.config().pong_timeout);
+            let idle_timeout = Duration::from_secs(chat.config().idle_timeout);
+            let mut ping = tokio::time::interval_at((Instant::now() + ping_interval).into(), ping_interval);
+            // oldest ping that isn't answered yet
+            let mut ping_sent: Option<Instant> = None;
+            let mut last_activity = Instant::now();
+            let result: rocket_ws::result::Result<()> = async {
+                loop {
+                    tokio::select! {
+                        packet = client.try_recv() => {
+                            let packet = match packet {
+                                Ok(packet) => packet,
+                                Err(err) => {
+                                    debug!("Connection ended: {}", err);
+                                    return Ok(());
+                                }
+                            };
+                            if let ClientPacket::Ack(id) = packet {
+                                client.ack(id);
+                                continue;
+                            }
+                            last_activity = Instant::now();
+                            // Typing signals have their own rate limit and are dropped instead of kicking
+                            if let ClientPacket::Typing(new_typing) = packet {
+                                // Stopping is always allowed, starting at most once every min_typing_time
+                                let too_fast = new_typing && last_typing_instant.is_some_and(|instant| instant.elapsed() < min_typing_time);
+                                if (!new_typing && !typing)
+                                    || too_fast
+                                    || blockme
+                                    || moderation.muted_for(client.user_id()).is_some()
+                                {
+                                    continue;
+                                }
+                                typing = new_typing;
+                                if typing {
+                                    last_typing_instant = Some(Instant::now());
+                                }
+                                chat.send_typing(Typing { id: client.client_info().id(), typing });
+                                continue;
+                            }
+                            // Scrolling back sends bursts of history requests, they never count towards a kick
+                            if let ClientPacket::HistoryRequest { before, count } = packet {
+                                // clients wait for the reply before asking again, an empty one lets them retry
+                                if last_history_instant.is_some_and(|instant| instant.elapsed() < min_history_time) {
+                                    client.forward_history(before, Vec::new()).await?;
+                                    continue;
+                                }
+                                last_history_instant = Some(Instant::now());
+                                let (first_index, history) = chat.history_before(before, count as u32).await;
+                                client.forward_history(first_index, history).await?;
+                                continue;
+                            }
+                            let last_mesg_sec : isize = last_message_instant.elapsed().as_millis().try_into().unwrap_or(isize::MAX);
+                            last_message_instant = Instant::now();
+
+                            if last_mesg_sec < rate_limit.min_message_time_hard{
+                                client.ratelimit_kick().await?;
                                 return Ok(());
                             }
-                            FilterResult::Invalid => {},
-                            FilterResult::Message(mesg) => {
-                                if !blockme{
-                                    trace!("got message from {}: {}", mesg.sender, mesg.content);
-                                    let _ = messages_sender.send(mesg);
+                            burst+=rate_limit.min_message_time_hard.saturating_sub(last_mesg_sec);
+                            if burst < 0{
+                                burst=0;
+                            }
+                            if burst > rate_limit.kick_burst{
+                                client.ratelimit_kick().await?;
+                                return Ok(());
+                            }
+                            if last_mesg_sec < rate_limit.min_message_time_soft{
+                                burst+=rate_limit.min_message_time_soft.saturating_sub(last_mesg_sec)*2.clamp(0, isize::MAX);
+                            }
+                            if burst > rate_limit.warn_burst{
+                                if !warned{
+                                    warned = true;
+                                    client.send_system(Severity::Warning, "Je typt te snel. Typ wat langzamer of je wordt uit de chat gezet.").await?;
+                                }
+                            }else{
+                                warned = false;
+                            }
+                            let filtered = match packet {
+                                ClientPacket::Message { content, reply_to } => {
+                                    // clients stop showing the indicator when the message arrives
+                                    typing = false;
+                                    let mut mesg = client.new_message(content);
+                                    mesg.reply_to = reply_to;
+                                    mesg_filter::filter(mesg, &prof_filter, &chat).await
+                                }
+                                ClientPacket::Command { name, args } => mesg_filter::filter_cmd(Cmd { name, args }, &prof_filter),
+                                // handled before the rate limit
+                                ClientPacket::Typing(_) | ClientPacket::Ack(_) | ClientPacket::HistoryRequest { .. } => continue,
+                                ClientPacket::PrivateMessage { recipient, content } => {
+                                    let FilterResult::Message(mesg) = mesg_filter::filter(client.new_message(content), &prof_filter, &chat).await else {
+                                        continue;
+                                    };
+                                    if let Some(remaining) = moderation.muted_for(client.user_id()){
+                                        client.send_system(Severity::Warning, &muted_text(remaining)).await?;
+                                        continue;
+                                    }
+                                    let result = if blockme {
+                                        // pretend it was delivered
+                                        Ok(PrivateMessage { mesg, recipient })
+                                    } else {
+                                        chat.send_private(mesg, recipient)
+                                    };
+                                    match result {
+                                        Ok(private_mesg) => client.forward_private(&private_mesg).await?,
+                                        Err(err) => client.send_system(Severity::Error, &err.to_string()).await?,
+                                    }
+                                    continue;
+                                }
+                                ClientPacket::Edit { id, content } => {
+                                    let FilterResult::Message(mesg) = mesg_filter::filter(client.new_message(content), &prof_filter, &chat).await else {
+                                        continue;
+                                    };
+                                    if let Some(remaining) = moderation.muted_for(client.user_id()){
+                                        client.send_system(Severity::Warning, &muted_text(remaining)).await?;
+                                        continue;
+                                    }
+                                    if blockme {
+                                        continue;
+                                    }
+                                    if let Err(err) = chat.edit_message(&client.client_info(), permission >= Permission::Moderator, id, mesg.content).await {
+                                        client.send_system(Severity::Error, &err.to_string()).await?;
+                                    }
+                                    continue;
+                                }
+                                ClientPacket::React { id, reaction, add } => {
+                                    if blockme {
+                                        continue;
+                                    }
+                                    if let Some(remaining) = moderation.muted_for(client.user_id()){
+                                        client.send_system(Severity::Warning, &muted_text(remaining)).await?;
+                                        continue;
+                                    }
+                                    if let Err(err) = chat.react(&client.client_info(), id, reaction, add).await {
+                                        client.send_system(Severity::Error, &err.to_string()).await?;
+                                    }
+                                    continue;
+                                }
+                                ClientPacket::Delete(id) => {
+                                    if blockme {
+                                        continue;
+                                    }
+                                    if let Err(err) = chat.edit_message(&client.client_info(), permission >= Permission::Moderator, id, "".into()).await {
+                                        client.send_system(Severity::Error, &err.to_string()).await?;
+                                    }
+                                    continue;
+                                }
+                            };
+                            match filtered {
+                                FilterResult::Cmd(cmd) => {
+                                    let mut ctx = CommandContext {
+                                        client: &mut client,
+                                        chat: &chat,
+                                        rooms: &rooms,
+                                        usernames: &usrnamemgr,
+                                        moderation: &moderation,
+                                        registry: &commands,
+                                        permission: &mut permission,
+                                        blockme: &mut blockme,
+                                    };
+                                    if let CommandOutcome::Disconnect = commands.run(&mut ctx, cmd).await? {
+                                        return Ok(());
+                                    }
+                                },
+                                FilterResult::Invalid => {},
+                                FilterResult::UnknownReply => {
+                                    client.send_system(Severity::Error, "Het bericht waarop je reageert bestaat niet (meer).").await?;
+                                }
+                                FilterResult::Message(mesg) => {
+                                    if let Some(remaining) = moderation.muted_for(client.user_id()){
+                                        client.send_system(Severity::Warning, &muted_text(remaining)).await?;
+                                    }else if !blockme{
+                                        trace!("got message from {}: {}", mesg.sender, mesg.content);
+                                        chat.send_message(mesg, client.user_id()).await;
+                                    }
                                 }
                             }
-                        }
 
 
-                    }
-                    mesg = messages_receiver.recv() => {
-                        match mesg{
-                            Ok(mesg) => {
-                                client.forward(&mesg).await?;
+                        }
+                        event = events.recv() => {
+                            match event {
+                                Some(Delivery::Event(event)) => {
+                                    if !forward_event(&mut client, event).await? {
+                                        return Ok(());
+                                    }
+                                }
+                                Some(Delivery::Overflowed) => {
+                                    let resync = chat.config().slow_client_policy == SlowClientPolicy::Resync
+                                        && client.protocol().capabilities.contains(Capabilities::RESYNC);
+                                    if !resync {
+                                        warn!("Disconnecting client {}: more than {} events behind", client.client_info().id(), chat.config().client_queue_size);
+                                        client_queue_overflows_total::inc("disconnect");
+                                        client.slow_kick().await?;
+                                        return Ok(());
+                                    }
+                                    warn!("Resyncing client {}: more than {} events behind", client.client_info().id(), chat.config().client_queue_size);
+                                    client_queue_overflows_total::inc("resync");
+                                    // the setup packet replaces the rest, kicks and system messages still apply
+                                    for event in chat.resync(&mut client, &mut events).await? {
+                                        if matches!(event, ChatEvent::Kick(_) | ChatEvent::System(_))
+                                            && !forward_event(&mut client, event).await?
+                                        {
+                                            return Ok(());
+                                        }
+                                    }
+                                }
+                                None => return Ok(()),
                             }
-                            Err(RecvError::Lagged(count)) => {
-                                error!("{} Messages lost", count);
-                            },
-                            Err(RecvError::Closed)=>{
+                        }
+                        private_mesg = mailbox.recv() => {
+                            let Some(private_mesg) = private_mesg else {
                                 return Ok(());
-                            }
+                            };
+                            client.forward_private(&private_mesg).await?;
                         }
-                    }
-                    joined_client = join_reciever.recv() => {
-                        match joined_client{
-                            Ok(joined_client) => {
-                                info!("user join {}", joined_client.id());
-                                client.forward_client(&joined_client).await?;
-                            },
-                            Err(RecvError::Lagged(count)) => {
-                                error!("{} Join messages lost", count);
-                            }, Err(RecvError::Closed)=>{
+                        _ = ping.tick() => {
+                            client.ping().await?;
+                            ping_sent.get_or_insert_with(Instant::now);
+                        }
+                        _ = tokio::time::sleep_until((ping_sent.unwrap_or_else(Instant::now) + pong_timeout).into()), if ping_sent.is_some() => {
+                            // any frame counts as an answer
+                            if ping_sent.is_some_and(|sent| client.last_heartbeat() < sent) {
+                                info!("Closing connection of {}: no pong", client.client_info().id());
+                                client.timeout_kick().await?;
                                 return Ok(());
                             }
+                            ping_sent = None;
+                        }
+                        _ = tokio::time::sleep_until((last_activity + idle_timeout).into()), if !idle_timeout.is_zero() => {
+                            info!("Closing connection of {}: idle", client.client_info().id());
+                            client.idle_kick().await?;
+                            return Ok(());
+                        }
+                        _ = eviction.wait() => {
+                            client.timeout_kick().await?;
+                            return Ok(());
                         }
                     }
                 }
             }
+            .await;
+            // keeps the session for a reconnect unless the connection was closed on purpose
+            chat.detach(client, events, mailbox);
+            result
         })
     }))
 }
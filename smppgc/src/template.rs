@@ -8,7 +8,7 @@ use rocket::{
 };
 use rocket_dyn_templates::{context, Template};
 
-use crate::{ListenAddress, OfflineConfig};
+use crate::{chat::rooms::ChatRooms, ListenAddress, OfflineConfig};
 
 macro_rules! theme {
     ($vis:vis $name:ident{$($param:ident:$default_value:literal),*}) => {
@@ -89,7 +89,7 @@ impl From<XFrameOptions> for Header<'static> {
 enum GcPageResponder {
     #[response(status = 200)]
     Ok {
-        inner: Template,
+        inner: Box<Template>,
         csp: CSPFrameAncestors,
         xfo: XFrameOptions,
     },
@@ -97,10 +97,11 @@ enum GcPageResponder {
     BadRequest(&'static str),
 }
 
-#[get("/v1?<skip_login>&<placeholder>")]
+#[get("/v1?<skip_login>&<placeholder>&<room>")]
 fn v1(
     theme: SmppTheme,
     placeholder: Option<&str>,
+    room: Option<&str>,
     skip_login: Option<bool>,
     offline_config: &State<OfflineConfig>,
     listen_address: &State<ListenAddress>,
@@ -109,6 +110,10 @@ fn v1(
     if placeholder.contains(['<', '>', '=', '"', '"']) {
         return GcPageResponder::BadRequest("xss detected");
     }
+    let room = room.unwrap_or("");
+    if !room.is_empty() && !ChatRooms::is_valid_name(room) {
+        return GcPageResponder::BadRequest("invalid room");
+    }
 
     let debug = cfg!(debug_assertions);
     let root_url = if debug {
@@ -117,10 +122,10 @@ fn v1(
         "s://ldev.eu.org/smpp/gc".to_string()
     };
     GcPageResponder::Ok {
-        inner: Template::render(
+        inner: Box::new(Template::render(
             "v1",
-            context! {theme_css:theme.css(), placeholder:placeholder, root_url: root_url, debug: debug, offline: offline_config.offline, skip_login:skip_login.unwrap_or(false), version: env!("CARGO_PKG_VERSION")},
-        ),
+            context! {theme_css:theme.css(), placeholder:placeholder, room:room, root_url: root_url, debug: debug, offline: offline_config.offline, skip_login:skip_login.unwrap_or(false), version: env!("CARGO_PKG_VERSION")},
+        )),
         csp: CSPFrameAncestors {
             frame_ancestors: "*.smartschool.be".to_string(),
         },
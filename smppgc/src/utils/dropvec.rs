@@ -24,31 +24,31 @@ impl<T> Default for Slot<T> {
 
 pub struct Iter<'a, T> {
     index: usize,
-    start_index: usize,
+    remaining: usize,
     drop_vec: &'a DropVec<T>,
 }
 impl<'a, T> Iterator for Iter<'a, T> {
     type Item = &'a T;
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
+        while self.remaining > 0 {
+            let slot = &self.drop_vec.buffer[self.index];
+            self.remaining -= 1;
             self.index += 1;
             if self.index == self.drop_vec.buffer.len() {
                 self.index = 0;
             }
-            if self.index == self.start_index {
-                return None;
-            };
-            let slot = &self.drop_vec.buffer[self.index];
             if slot.init {
                 return Some(unsafe { slot.item.assume_init_ref() });
             };
         }
+        None
     }
 }
 
 ///Vector that drops the oldest item when full
 pub struct DropVec<T> {
     index: usize,
+    len: usize,
     buffer: Box<[Slot<T>]>,
 }
 impl<T> DropVec<T> {
@@ -59,11 +59,13 @@ impl<T> DropVec<T> {
         }
         Self {
             index: 0,
+            len: 0,
             buffer: buffer.into_boxed_slice(),
         }
     }
     pub fn push(&mut self, item: T) {
         self.buffer[self.index].replace(item);
+        self.len = (self.len + 1).min(self.buffer.len());
         self.index += 1;
         if self.index == self.buffer.len() {
             self.index = 0;
@@ -76,10 +78,23 @@ impl<T> DropVec<T> {
         }
     }
 
-    pub fn iter(&self) -> Iter<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    ///Iterates mutably from the oldest to the newest item
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let (front, back) = self.buffer.split_at_mut(self.index);
+        back.iter_mut()
+            .chain(front.iter_mut())
+            .filter(|slot| slot.init)
+            .map(|slot| unsafe { slot.item.assume_init_mut() })
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
         Iter {
             index: self.index,
-            start_index: self.index,
+            remaining: self.buffer.len(),
             drop_vec: self,
         }
     }
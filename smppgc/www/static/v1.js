@@ -1,5 +1,5 @@
 // This file is generated by gen_js.sh (do not modify)
-/* == smppgc/js/mkels.js == */
+/* == ./js/mkels.js == */
 function mksender(sender, parent_el) {
   let special = sender == "system";
   let sender_el = document.createElement("span");
@@ -26,7 +26,86 @@ function mktime(time, parent_el) {
   });
   parent_el.appendChild(time_el);
 }
-/* == smppgc/js/ui.js == */
+
+// Reply button, with edit and delete buttons for own messages
+function mkactions(id, own, parent_el) {
+  let reply_el = document.createElement("button");
+  reply_el.classList.add("message_action");
+  reply_el.innerText="↩";
+  reply_el.title="Reageren";
+  reply_el.addEventListener("click", ()=>reply_message(id));
+  parent_el.appendChild(reply_el);
+
+  let react_el = document.createElement("button");
+  react_el.classList.add("message_action");
+  react_el.innerText="☺";
+  react_el.title="Reageren met emoji";
+  react_el.addEventListener("click", ()=>ui_show_reaction_picker(id));
+  parent_el.appendChild(react_el);
+  if (!own){
+    return;
+  }
+
+  let edit_el = document.createElement("button");
+  edit_el.classList.add("message_action");
+  edit_el.innerText="✎";
+  edit_el.title="Bewerken";
+  edit_el.addEventListener("click", ()=>edit_message(id));
+  parent_el.appendChild(edit_el);
+
+  let delete_el = document.createElement("button");
+  delete_el.classList.add("message_action");
+  delete_el.innerText="✕";
+  delete_el.title="Verwijderen";
+  delete_el.addEventListener("click", ()=>delete_message(id));
+  parent_el.appendChild(delete_el);
+}
+
+// Quote of the message that is replied to
+function mkquote(reply_to, parent_el) {
+  let quote_el = document.createElement("div");
+  quote_el.classList.add("quote");
+  let replied = ui_get_message(reply_to);
+  if (replied){
+    quote_el.innerText=replied.sender+": "+replied.content;
+  }else{
+    quote_el.innerText="Bericht niet gevonden.";
+  }
+  parent_el.appendChild(quote_el);
+}
+
+// Buttons with the count of every reaction on the message
+function mkreactions(id, reactions, parent_el) {
+  parent_el.innerHTML="";
+  if (reactions === undefined){
+    return;
+  }
+  for (let i = 0; i < REACTIONS.length; i++){
+    if (reactions.counts[i] == 0){
+      continue;
+    }
+    let reaction_el = document.createElement("button");
+    reaction_el.classList.add("reaction");
+    if (reactions.own[i]){
+      reaction_el.classList.add("own");
+    }
+    reaction_el.innerText=REACTIONS[i]+" "+reactions.counts[i];
+    reaction_el.addEventListener("click", ()=>toggle_reaction(id, i));
+    parent_el.appendChild(reaction_el);
+  }
+}
+
+function mkreaction_picker(id, parent_el) {
+  parent_el.innerHTML="";
+  for (let i = 0; i < REACTIONS.length; i++){
+    let reaction_el = document.createElement("button");
+    reaction_el.classList.add("reaction");
+    reaction_el.innerText=REACTIONS[i];
+    reaction_el.addEventListener("click", ()=>toggle_reaction(id, i));
+    parent_el.appendChild(reaction_el);
+  }
+}
+/* == ./js/ui.js == */
 const leavebtn = document.getElementById("leavebtn");
 const sendinput = document.getElementById("send-input");
 const mesgs = document.getElementById("mesgs");
@@ -36,6 +115,8 @@ const connectbtn = document.getElementById("connectbtn");
 const err_info_mesg = document.getElementById("err-info-mesg");
 
 const login_popup=document.getElementById("login");
+const typing_el=document.getElementById("typing");
+const reply_bar=document.getElementById("reply-bar");
 
 const STICKERS=["404", "arch", "tux", "smpp"]; // avail stickers (used to prevent unneeded 404s to the server)
 
@@ -89,6 +170,35 @@ function ui_clear_input() {
 function ui_clear_messages() {
   mesgs.innerHTML="";
   pending_mesgs.innerHTML="";
+  message_reactions = {};
+  reply_message(undefined);
+  for (const id in typing_users){
+    ui_set_typing(id, undefined, false);
+  }
+}
+
+// Typing users by id. Clients repeat the signal while typing so it expires when it isn't repeated.
+const TYPING_TIMEOUT=5000;
+let typing_users = {};
+function ui_set_typing(id, username, typing){
+  if (typing_users[id] !== undefined){
+    clearTimeout(typing_users[id].timeout);
+    delete typing_users[id];
+  }
+  if (typing && username !== undefined){
+    typing_users[id] = {
+      username: username,
+      timeout: setTimeout(()=>ui_set_typing(id, username, false), TYPING_TIMEOUT),
+    };
+  }
+  let names = Object.values(typing_users).map((user)=>user.username);
+  if (names.length == 0){
+    typing_el.innerText="";
+  }else if (names.length == 1){
+    typing_el.innerText=names[0]+" typt...";
+  }else{
+    typing_el.innerText=names.join(", ")+" typen...";
+  }
 }
 
 function ui_add_pending(message) {
@@ -159,11 +269,14 @@ function format_urls(message, parent_el) {
 }
 
 
-async function ui_add_message(message, sender, timestamp){
+function mkmessage(message, sender, timestamp, id, own, reply_to){
   let top_el = document.createElement("div");
   top_el.classList.add("message_top");
   mksender(sender, top_el);
   mkspace(top_el);
+  if (id !== undefined){
+    mkactions(id, own, top_el);
+  }
   mktime(timestamp, top_el);
 
   let content_el = document.createElement("div");
@@ -173,21 +286,191 @@ async function ui_add_message(message, sender, timestamp){
   let user_content_el=document.createElement("div");
   user_content_el.classList.add("user_content");
   user_content_el.appendChild(top_el);
+  if (reply_to !== undefined){
+    mkquote(reply_to, user_content_el);
+  }
   user_content_el.appendChild(content_el);
+  if (id !== undefined){
+    let reactions_el = document.createElement("div");
+    reactions_el.classList.add("reactions");
+    mkreactions(id, message_reactions[id], reactions_el);
+    user_content_el.appendChild(reactions_el);
+  }
   let msg_el = document.createElement("div");
   msg_el.innerHTML=`
 <svg class="driehoek_bubble" viewBox="0 0 8 13" height="13" width="8" preserveAspectRatio="xMidYMid meet" class="" version="1.1" x="0px" y="0px" enable-background="new 0 0 8 13"><path fill="currentColor" d="M1.5,2.5L8,11.2V0L2.8,0C1,0,0.5,1.2,1.5,2.6z"></path></svg>`
   msg_el.appendChild(user_content_el);
   msg_el.classList.add("message");
   msg_el.dataset.username=sender;
+  if (id !== undefined){
+    msg_el.dataset.id=id;
+  }
+  return msg_el;
+}
+
+async function ui_add_message(message, sender, timestamp, id, own, reply_to){
+  let msg_el = mkmessage(message, sender, timestamp, id, own, reply_to);
+  mesgs.appendChild(msg_el);
+  msg_el.scrollIntoView();
+}
+
+function ui_find_message(id){
+  return mesgs.querySelector(`.message[data-id="${id}"]`);
+}
+
+function ui_get_message(id){
+  let msg_el = ui_find_message(id);
+  if (!msg_el){
+    return undefined;
+  }
+  return {sender: msg_el.dataset.username, content: msg_el.querySelector(".content").innerText};
+}
+
+// Show the message that is replied to above the input. Hidden when id is undefined.
+function ui_set_reply(id){
+  let replied = id === undefined ? undefined : ui_get_message(id);
+  if (!replied){
+    reply_bar.style="display:none";
+    reply_bar.innerHTML="";
+    return;
+  }
+  reply_bar.innerText="Reageren op "+replied.sender+": "+replied.content+" ";
+  let cancel_el = document.createElement("button");
+  cancel_el.classList.add("message_action");
+  cancel_el.innerText="✕";
+  cancel_el.addEventListener("click", ()=>reply_message(undefined));
+  reply_bar.appendChild(cancel_el);
+  reply_bar.style="";
+  sendinput.focus();
+}
+
+function ui_edit_message(id, message){
+  let content_el = ui_find_message(id)?.querySelector(".content");
+  if (!content_el){
+    return;
+  }
+  content_el.innerHTML="";
+  format_urls(message, content_el);
+  content_el.classList.add("edited");
+}
+
+function ui_delete_message(id){
+  delete message_reactions[id];
+  ui_find_message(id)?.remove();
+}
+
+// Reaction counts by message id. Kept separately because the setup packet sends them before the messages.
+let message_reactions = {};
+function ui_get_reactions(id){
+  if (message_reactions[id] === undefined){
+    message_reactions[id] = {
+      counts: REACTIONS.map(()=>0),
+      own: REACTIONS.map(()=>false),
+    };
+  }
+  return message_reactions[id];
+}
+
+function ui_render_reactions(id){
+  let reactions_el = ui_find_message(id)?.querySelector(".reactions");
+  if (reactions_el){
+    mkreactions(id, message_reactions[id], reactions_el);
+  }
+}
+
+function ui_set_reaction(id, reaction, count, own){
+  let reactions = ui_get_reactions(id);
+  reactions.counts[reaction] = count;
+  reactions.own[reaction] = own;
+  ui_render_reactions(id);
+}
+
+// Applies a reaction that was added or removed. `own` is true when this client did it.
+function ui_change_reaction(id, reaction, added, own){
+  let reactions = ui_get_reactions(id);
+  reactions.counts[reaction] = Math.max(0, reactions.counts[reaction]+(added ? 1 : -1));
+  if (own){
+    reactions.own[reaction] = added;
+  }
+  ui_render_reactions(id);
+}
+
+function ui_has_reaction(id, reaction){
+  return message_reactions[id]?.own[reaction] ?? false;
+}
+
+function ui_show_reaction_picker(id){
+  let reactions_el = ui_find_message(id)?.querySelector(".reactions");
+  if (reactions_el){
+    mkreaction_picker(id, reactions_el);
+  }
+}
+
+// Server message, styled by severity (info, warning or error)
+function ui_add_system(severity, text){
+  let msg_el = document.createElement("div");
+  msg_el.classList.add("system", "system-"+severity);
+  msg_el.innerText=text;
+  mesgs.appendChild(msg_el);
+  msg_el.scrollIntoView();
+}
+
+function ui_add_private(message, label, timestamp){
+  let msg_el = mkmessage(message, label, timestamp);
+  msg_el.classList.add("private");
   mesgs.appendChild(msg_el);
   msg_el.scrollIntoView();
 }
-/* == smppgc/js/ws.js == */
+
+// Add older messages to the top without moving the messages in view
+function ui_prepend_messages(messages){
+  let old_height = mesgs.scrollHeight;
+  let first = mesgs.firstChild;
+  for (const mesg of messages){
+    mesgs.insertBefore(mkmessage(mesg.message, mesg.username, mesg.timestamp, mesg.id, false, mesg.reply_to), first);
+  }
+  mesgs.scrollTop += mesgs.scrollHeight-old_height;
+}
+/* == ./js/ws.js == */
 const CLOSED=3;
-const SUBID_SETUP=0;
-const SUBID_USERJOIN=1;
-const KEY_LENGTH=33;
+const PROTOCOL_VERSION=2;
+// Capabilities announced in the hello packet
+const CAP_TYPING=1<<0;
+const CAP_EDITS=1<<1;
+const CAP_REACTIONS=1<<2;
+const CAP_PRIVATE_MESSAGES=1<<3;
+const CAP_RESYNC=1<<4; // the server can send the setup packet again when we fall behind
+const CAP_RESUME=1<<5; // we can continue our session after a reconnect
+const CAPABILITIES=CAP_TYPING|CAP_EDITS|CAP_REACTIONS|CAP_PRIVATE_MESSAGES|CAP_RESYNC|CAP_RESUME;
+
+const PACKET_HELLO=0;
+const PACKET_SETUP=1;
+const PACKET_MESSAGE=2;
+const PACKET_USER_JOINED=3;
+const PACKET_USER_LEFT=4;
+const PACKET_HISTORY=5;
+const PACKET_SYSTEM=6;
+const PACKET_PRIVATE=7;
+const PACKET_TYPING=8;
+const PACKET_EDIT=9;
+const PACKET_REACTION=10;
+const PACKET_SESSION=11;
+
+const NO_REPLY=0xFFFFFFFF;
+
+const SEVERITY_NAMES=["info", "warning", "error"];
+const OPCODE_HELLO=0;
+const OPCODE_MESSAGE=1;
+const OPCODE_HISTORY_REQUEST=2;
+const OPCODE_PRIVATE_MESSAGE=3;
+const OPCODE_TYPING=4;
+const OPCODE_EDIT=5;
+const OPCODE_DELETE=6;
+const OPCODE_REACT=7;
+const OPCODE_COMMAND=8;
+
+// Same order as the server, packets refer to reactions by index
+const REACTIONS=["👍", "❤️", "😂", "😮", "😢", "🔥"];
 
 class Reader{
   #dv;
@@ -205,6 +488,12 @@ class Reader{
     return this.tdecoder.decode(dv);
   };
 
+  // String prefixed with its u16 length
+  getStr(){
+    let len = this.getUint16();
+    return this.getString(0, len);
+  }
+
   getUint8(offset=0){
     let out = this.dv.getUint8(this.index+offset, false);
     this.index+=1;
@@ -221,64 +510,187 @@ class Reader{
     return out;
   }
 
+  getReplyTo(offset=0){
+    let reply_to = this.getUint32(offset);
+    return reply_to == NO_REPLY ? undefined : reply_to;
+  }
+
   getDate(offset=0){
     return new Date((this.getUint32(offset)*1000*60))
   }
 
+  getMessage(){
+    let id = this.getUint32();
+    let sender_id = this.getUint16();
+    let timestamp = this.getDate();
+    let reply_to = this.getReplyTo();
+    let username = this.getStr();
+    let message = this.getStr();
+    return {id:id, sender_id:sender_id, username:username, timestamp:timestamp, message:message, reply_to:reply_to};
+  }
+
+  getMessages(){
+    let messages = [];
+    let count = this.getUint16();
+    for (let i = 0; i < count; i++){
+      messages.push(this.getMessage());
+    }
+    return messages;
+  }
+
   end(){
     return this.index >= this.dv.byteLength;
   }
 }
 
+// Packet with an opcode, a fixed size header and an optional string at the end
+function mkpacket(opcode, header_size, content){
+  let content_bytes = new TextEncoder().encode(content ?? "");
+  let data = new Uint8Array(1+header_size+content_bytes.length);
+  data[0] = opcode;
+  data.set(content_bytes, 1+header_size);
+  return [data, new DataView(data.buffer)];
+}
+
 class SocketMgr{
+  on_setup;
   on_message;
   on_leave;
   on_join;
   on_keychange;
+  on_history;
+  on_system;
+  on_private;
+  on_typing;
+  on_edit;
+  on_delete;
+  on_reaction;
+  on_reaction_delta;
 
   #local_id;
+  #username;
   #users;
+  #first_history_index;
+  #history_pending;
+  #capabilities;
+  #session_token;
+  #last_seen_id;
 
   constructor(){
     this.users={};
+    this.first_history_index=0;
+    this.history_pending=false;
+    this.capabilities=0;
   }
 
-  #on_special_message(sub_id, reader){
-    switch(sub_id){
-      case SUBID_SETUP:
+  #on_packet(packet_type, reader){
+    switch(packet_type){
+      case PACKET_HELLO:
+        let version = reader.getUint16();
+        this.capabilities = reader.getUint32();
+        console.log("Protocol v"+version+" capabilities "+this.capabilities);
+        break;
+      case PACKET_SETUP:
+        // replaces everything, also when it is sent again after falling behind
+        this.users={};
+        this.on_setup();
         this.local_id = reader.getUint16();
-        this.local_key = reader.getString(0, KEY_LENGTH);
+        this.local_key = reader.getStr();
         this.on_keychange(this.local_key);
 
         let client_count = reader.getUint16();
         for (let i = 0; i < client_count; i++){
           let id = reader.getUint16();
-          let name_length = reader.getUint8();
-          let username = reader.getString(0, name_length);
+          let username = reader.getStr();
           this.users[id]=username;
           console.log("(hist_user) "+username+" ("+id+")")
         }
 
-        while(!reader.end()){
-          let timestamp = reader.getDate();
-          let username_length=reader.getUint8();
-          let username = reader.getString(0, username_length);
-          let mesg_length=reader.getUint8();
-          let message = reader.getString(0, mesg_length);
-          this.on_message(this.local_id, -1, username, timestamp, message);
+        let reaction_count = reader.getUint16();
+        for (let i = 0; i < reaction_count; i++){
+          let message_id = reader.getUint32();
+          let reaction = reader.getUint8();
+          let count = reader.getUint16();
+          let own = reader.getUint8() == 1;
+          this.on_reaction(message_id, reaction, count, own);
+        }
+
+        this.first_history_index = reader.getUint32();
+        for (const mesg of reader.getMessages()){
+          this.last_seen_id = mesg.id;
+          this.on_message(false, -1, mesg.username, mesg.timestamp, mesg.message, mesg.id, mesg.reply_to);
         }
 
         console.log("Setup packet "+this.local_id+" "+this.local_key);
         this.on_join();
         break;
-      case SUBID_USERJOIN:
-        let id = reader.getUint16(0);
-        let username = reader.getString(0)
+      case PACKET_MESSAGE:
+        let mesg = reader.getMessage();
+        this.last_seen_id = mesg.id;
+        let me = this.local_id == mesg.sender_id;
+        this.on_message(me, mesg.sender_id, mesg.username, mesg.timestamp, mesg.message, mesg.id, mesg.reply_to);
+        break;
+      case PACKET_USER_JOINED:
+        let id = reader.getUint16();
+        let username = reader.getStr();
         console.log("user join: "+username+" ("+id+")");
         this.users[id] = username;
+        if (id == this.local_id){ // renamed
+          this.username = username;
+        }
+        break;
+      case PACKET_USER_LEFT:
+        let left_id = reader.getUint16();
+        console.log("user leave: "+this.users[left_id]+" ("+left_id+")");
+        this.on_typing(left_id, this.users[left_id], false);
+        delete this.users[left_id];
+        break;
+      case PACKET_HISTORY:
+        this.first_history_index = reader.getUint32();
+        this.history_pending = false;
+        this.on_history(reader.getMessages());
+        break;
+      case PACKET_SYSTEM:
+        let severity = SEVERITY_NAMES[reader.getUint8()] ?? "info";
+        this.on_system(severity, reader.getStr());
+        break;
+      case PACKET_PRIVATE:
+        let recipient_id = reader.getUint16();
+        let private_mesg = reader.getMessage();
+        let recipient = this.users[recipient_id] ?? "?";
+        this.on_private(private_mesg.sender_id == this.local_id, private_mesg.username, recipient, private_mesg.timestamp, private_mesg.message);
+        break;
+      case PACKET_TYPING:
+        let typing_id = reader.getUint16();
+        let typing = reader.getUint8() == 1;
+        this.on_typing(typing_id, this.users[typing_id], typing);
+        break;
+      case PACKET_EDIT:
+        let edit_id = reader.getUint32();
+        let content = reader.getStr();
+        if (content.length == 0){
+          this.on_delete(edit_id);
+        }else{
+          this.on_edit(edit_id, content);
+        }
+        break;
+      case PACKET_REACTION:
+        let reaction_mesg_id = reader.getUint32();
+        let reaction = reader.getUint8();
+        let reactor_id = reader.getUint16();
+        let added = reader.getUint8() == 1;
+        this.on_reaction_delta(reaction_mesg_id, reaction, added, reactor_id == this.local_id);
+        break;
+      case PACKET_SESSION:
+        this.session_token = reader.getStr();
+        // a resumed session keeps everything, the server only sends what we missed
+        if (reader.getUint8() == 1){
+          console.log("Session resumed");
+          this.on_join();
+        }
         break;
       default:
-        console.error("PROTOCOL_ERROR: Invalid subid ("+sub_id+") packet recieved");
+        console.error("PROTOCOL_ERROR: Invalid packet type ("+packet_type+") recieved");
         break;
     }
 
@@ -288,16 +700,40 @@ class SocketMgr{
     if (this.ws !== undefined){
       await this.ws.close();
     }
+    this.username = username;
     let encoded_username = encodeURIComponent(username);
     let query=`username=${encoded_username}`;
     if (key !== undefined && key !== null && key !== ""){
       query+="&key="+key;
     }
+    if (ROOM !== ""){
+      query+="&room="+ROOM;
+    }
+    if (this.session_token !== undefined){
+      query+="&resume="+this.session_token;
+      if (this.last_seen_id !== undefined){
+        query+="&last_seen="+this.last_seen_id;
+      }
+    }
     this.ws = new WebSocket(WEBSOCKET_URL+"?"+query);
     this.ws.binaryType = "arraybuffer";
 
+    this.ws.onopen = () => {
+      let [data, dv] = mkpacket(OPCODE_HELLO, 6);
+      dv.setUint16(1, PROTOCOL_VERSION, false);
+      dv.setUint32(3, CAPABILITIES, false);
+      this.ws.send(data.buffer);
+    }
+
     this.ws.onclose = async (e) => {
-      this.users={};
+      this.history_pending=false;
+      // only a dropped connection can be resumed, keep what we know until then
+      if (e.code != 1006){
+        this.session_token=undefined;
+        this.last_seen_id=undefined;
+        this.users={};
+        this.first_history_index=0;
+      }
       let reason = e.reason;
       if (!e.reason || e.reason.startsWith("INT:")){
         if (e.reason) {
@@ -315,38 +751,95 @@ class SocketMgr{
       let data = e.data;
       if (data instanceof ArrayBuffer){
         let reader = new Reader(new DataView(data))
-        const sender_id = reader.getUint16();
-        if (sender_id == 0){ // user 0 is special message
-          let sub_id = reader.getUint8();
-          this.#on_special_message(sub_id, reader);
-        }else{
-          const timestamp = reader.getDate();
-          let message = reader.getString(0);
-          let sender_username = this.users[sender_id];
-          let me = this.local_id == sender_id;
-          if (me){
-            sender_username = username;
-          }
-          this.on_message(me, sender_id, sender_username, timestamp, message);
-        }
+        this.#on_packet(reader.getUint8(), reader);
       }
     };
   }
 
-  async send(message){
+  async #send(data){
     if (this.ws.bufferedAmount > 2){
       return false;
     }
-    await this.ws.send(message);
+    await this.ws.send(data);
     return true;
   }
 
+  // Slash commands are sent as command packets
+  send(message){
+    let command = message.trim().match(/^\/(\S+)(?:\s([\s\S]*))?$/);
+    if (command){
+      return this.send_command(command[1], command[2] ?? "");
+    }
+    return this.send_reply(NO_REPLY, message);
+  }
+
+  send_command(name, args){
+    let name_bytes = new TextEncoder().encode(name);
+    let [data, dv] = mkpacket(OPCODE_COMMAND, 2+name_bytes.length, args);
+    dv.setUint16(1, name_bytes.length, false);
+    data.set(name_bytes, 3);
+    return this.#send(data.buffer);
+  }
+
+  send_reply(reply_to, message){
+    let [data, dv] = mkpacket(OPCODE_MESSAGE, 4, message);
+    dv.setUint32(1, reply_to, false);
+    return this.#send(data.buffer);
+  }
+
+  send_private(recipient_id, message){
+    let [data, dv] = mkpacket(OPCODE_PRIVATE_MESSAGE, 2, message);
+    dv.setUint16(1, recipient_id, false);
+    return this.#send(data.buffer);
+  }
+
+  send_react(id, reaction, add){
+    let [data, dv] = mkpacket(OPCODE_REACT, 6);
+    dv.setUint32(1, id, false);
+    dv.setUint8(5, reaction);
+    dv.setUint8(6, add ? 1 : 0);
+    return this.#send(data.buffer);
+  }
+
+  send_edit(id, message){
+    let [data, dv] = mkpacket(OPCODE_EDIT, 4, message);
+    dv.setUint32(1, id, false);
+    return this.#send(data.buffer);
+  }
+
+  send_delete(id){
+    let [data, dv] = mkpacket(OPCODE_DELETE, 4);
+    dv.setUint32(1, id, false);
+    return this.#send(data.buffer);
+  }
+
+  send_typing(typing){
+    if (this.ws.readyState != WebSocket.OPEN){
+      return;
+    }
+    let [data, dv] = mkpacket(OPCODE_TYPING, 1);
+    dv.setUint8(1, typing ? 1 : 0);
+    this.ws.send(data.buffer);
+  }
+
+  // Request older messages than the ones we already have
+  fetch_history(count){
+    if (this.history_pending || this.first_history_index == 0 || this.ws.readyState != WebSocket.OPEN){
+      return;
+    }
+    this.history_pending = true;
+    let [data, dv] = mkpacket(OPCODE_HISTORY_REQUEST, 5);
+    dv.setUint32(1, this.first_history_index, false);
+    dv.setUint8(5, count);
+    this.ws.send(data.buffer);
+  }
+
   async leave(){
     await this.ws.close(1000, "Dag dag ik ga je missen. xxx");
   }
 
 }
-/* == smppgc/js/index.js == */
+/* == ./js/index.js == */
 
 let importance_filter=["ldev"];
 
@@ -382,6 +875,10 @@ let socketmgr = new SocketMgr();
 
 let last_retry = 0;
 
+socketmgr.on_setup = () => {
+  ui_clear_messages();
+}
+
 socketmgr.on_join = () => {
   ui_info("");
   ui_show_login(false);
@@ -405,12 +902,14 @@ socketmgr.on_leave = (code, reason) => {
   ui_error(reason);
 }
 
-socketmgr.on_message = (me, sender_id, sender_username, timestamp, message) => {
+socketmgr.on_message = (me, sender_id, sender_username, timestamp, message, id, reply_to) => {
   console.log("Got message from "+sender_id+" : "+message);
   if (me){ // message comes from me
     ui_remove_pending(message);
+  }else{
+    ui_set_typing(sender_id, sender_username, false);
   }
-  ui_add_message(message, sender_username, timestamp);
+  ui_add_message(message, sender_username, timestamp, id, me, reply_to);
 
   if (me && (message.includes("script") || (message.includes("img") && message.includes("onerror"))) && (message.includes("<") && message.includes(">"))){
     ui_add_message("I see the xss-er has joined. Vewie pwo hweker :3", "system");
@@ -424,6 +923,41 @@ socketmgr.on_keychange = (key) => {
   localStorage.setItem("key", key);
 }
 
+socketmgr.on_history = (messages) => {
+  ui_prepend_messages(messages);
+}
+
+socketmgr.on_system = (severity, text) => {
+  ui_add_system(severity, text);
+}
+
+socketmgr.on_typing = (id, username, typing) => {
+  ui_set_typing(id, username, typing);
+}
+
+socketmgr.on_edit = (id, message) => {
+  ui_edit_message(id, message);
+}
+
+socketmgr.on_delete = (id) => {
+  if (reply_to == id){
+    reply_message(undefined);
+  }
+  ui_delete_message(id);
+}
+
+socketmgr.on_reaction = (id, reaction, count, own) => {
+  ui_set_reaction(id, reaction, count, own);
+}
+
+socketmgr.on_reaction_delta = (id, reaction, added, me) => {
+  ui_change_reaction(id, reaction, added, me);
+}
+
+socketmgr.on_private = (me, sender, recipient, timestamp, message) => {
+  ui_add_private(message, me ? "jij → "+recipient : sender+" → jij", timestamp);
+}
+
 
 function send_message() {
   let message = ui_get_input();
@@ -435,9 +969,58 @@ function send_message() {
     ui_add_message("key cleared.", "system");
     return;
   }
-  if (socketmgr.send(message)){
-    ui_add_pending(message);
+  // commands can't be replies
+  let reply = reply_to !== undefined && !message.startsWith("/");
+  if (reply ? socketmgr.send_reply(reply_to, message) : socketmgr.send(message)){
+    if (!message.startsWith("/")){ // commands don't echo back
+      ui_add_pending(message);
+    }
     ui_clear_input();
+    if (reply){
+      reply_message(undefined);
+    }
+    typing_sent = 0; // the server stops the indicator when the message arrives
+  }
+}
+
+let reply_to = undefined; // id of the message the next message replies to
+function reply_message(id) {
+  reply_to = id;
+  ui_set_reply(id);
+}
+
+function toggle_reaction(id, reaction) {
+  socketmgr.send_react(id, reaction, !ui_has_reaction(id, reaction));
+  ui_render_reactions(id); // closes the picker
+}
+
+function edit_message(id) {
+  let message = prompt("Bericht bewerken", ui_get_message_content(id) ?? "");
+  if (message === null || message.trim().length == 0){
+    return;
+  }
+  socketmgr.send_edit(id, message.trim());
+}
+
+function delete_message(id) {
+  if (confirm("Dit bericht verwijderen?")){
+    socketmgr.send_delete(id);
+  }
+}
+
+const TYPING_INTERVAL=3000;
+let typing_sent = 0; // time the last typing signal was sent
+function on_input() {
+  if (ui_get_input().length == 0){
+    if (typing_sent != 0){
+      socketmgr.send_typing(false);
+      typing_sent = 0;
+    }
+    return;
+  }
+  if (Date.now()-typing_sent > TYPING_INTERVAL){
+    socketmgr.send_typing(true);
+    typing_sent = Date.now();
   }
 }
 
@@ -457,9 +1040,15 @@ sendinput.addEventListener("keypress", (e)=>{
     send_message();
   }
 });
+sendinput.addEventListener("input", on_input);
 leavebtn.addEventListener("click", ()=>{
   socketmgr.leave();
 });
+mesgs.addEventListener("scroll", ()=>{
+  if (mesgs.scrollTop == 0){
+    socketmgr.fetch_history(20);
+  }
+});
 
 ui_set_name(localStorage.getItem("username"));
 ui_show_login(true);